@@ -1,4 +1,5 @@
 use crate::node::{NodeType, Texture};
+use crate::primitives;
 
 #[derive(Clone,Debug)]
 pub struct MeshCollection {
@@ -30,11 +31,28 @@ impl MeshCollection {
             vertices: drawable_mesh,
             indices: indices,
             draw_with_indices: true,
+            normals: None,
             vertices_filling: vertices_filling,
             texture_data: texture_data,
         });
     }
 
+    /* Same as add_drawable_mesh with a normal per vertex, for shapes whose 
+    normals are known rather than estimated from the triangles */
+    pub fn add_drawable_mesh_with_normals(&mut self,
+        drawable_mesh: Vec<f32>,
+        indices: Vec<u32>,
+        normals: Vec<f32>,
+        texture_data: Option<Texture>,
+        vertices_filling: Option<Vec<VertexFilling>>) {
+        assert!(normals.len() == drawable_mesh.len(),
+            "Expected a normal for each of the vertices provided.");
+        self.add_drawable_mesh(drawable_mesh, indices, texture_data, vertices_filling);
+        if let Some(mesh) = self.drawable_meshes.last_mut() {
+            mesh.normals = Some(normals);
+        }
+    }
+
     pub fn get_full_interleaved_vertices(&self) -> Vec<f32> {
         self.drawable_meshes.iter().flat_map(|mesh| {
             mesh.get_full_interleaved_vertices()
@@ -85,6 +103,8 @@ pub struct DrawableMesh {
     pub vertices: Vec<f32>,
     pub indices: Vec<u32>,
     pub draw_with_indices: bool,
+    //Computed from the triangles when not given
+    pub normals: Option<Vec<f32>>,
     pub vertices_filling: Option<Vec<VertexFilling>>,
     //Assumes only a single texture for a given mesh
     pub texture_data: Option<Texture> 
@@ -96,6 +116,7 @@ impl DrawableMesh {
             vertices: Vec::new(),
             indices: Vec::new(),
             draw_with_indices: true,  
+            normals: None,
             vertices_filling: None,
             texture_data: None,
         }
@@ -109,8 +130,17 @@ impl DrawableMesh {
         (self.vertices.len() / 3) as u32
     }
 
+    pub fn get_normals(&self) -> Vec<f32> {
+        match &self.normals {
+            Some(normals) => normals.clone(),
+            None => primitives::vertex_normals(&self.vertices, &self.indices)
+        }
+    }
+
+    /* Per vertex: position, color, texture coordinates & normal, 3 floats each */
     pub fn get_full_interleaved_vertices(&self) -> Vec<f32> {
         let num_vertices = self.vertices.len() / 3;
+        let normals = self.get_normals();
         let mut interleaved_vertices = Vec::new();
         if let Some(vertices_filling) = &self.vertices_filling {
             // println!("Getting interleaved vertices with texture filling");
@@ -145,6 +175,7 @@ impl DrawableMesh {
                         interleaved_vertices.push(0.0);
                     }
                 }
+                interleaved_vertices.extend_from_slice(&normals[i*3..i*3+3]);
             }
         } else {
             // println!("Getting interleaved vertices without texture filling");
@@ -158,6 +189,7 @@ impl DrawableMesh {
                 interleaved_vertices.push(0.0);
                 interleaved_vertices.push(0.0);
                 interleaved_vertices.push(0.0);
+                interleaved_vertices.extend_from_slice(&normals[i*3..i*3+3]);
             }
         }
         interleaved_vertices
@@ -0,0 +1,250 @@
+use nalgebra::{DMatrix, DVector, Isometry3, Point3, Vector3};
+use crate::kinematics::{forward_kinematics, jacobian, integrate, carrying_joints, Poses};
+use crate::model::Model;
+use crate::node::{Node, JointType};
+use crate::scene::NodeId;
+
+/* Inverse kinematics by damped least squares. The joints moved are those 
+between the end effector & the world; other joints keep their position */
+
+#[derive(Debug, Clone)]
+pub struct IkOptions {
+    pub max_iterations: usize,
+    //Distance from the target position that counts as reached
+    pub position_tolerance: f32,
+    //Angle in radians from the target orientation that counts as reached
+    pub rotation_tolerance: f32,
+    //Damping of the least squares step, larger values are steadier near singularities but slower
+    pub damping: f32,
+    //When false only the target position is sought & its rotation is ignored
+    pub match_orientation: bool
+}
+
+impl IkOptions {
+    pub fn default() -> Self {
+        IkOptions {
+            max_iterations: 200,
+            position_tolerance: 1e-4,
+            rotation_tolerance: 1e-3,
+            damping: 0.05,
+            match_orientation: true
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IkSolution {
+    //Full joint positions, laid out as Model::get_qpos
+    pub qpos: Vec<f32>,
+    pub converged: bool,
+    pub iterations: usize,
+    pub position_error: f32,
+    pub rotation_error: f32
+}
+
+/* A joint on the chain, where its values sit in qpos & its degrees of 
+freedom in the joint velocities */
+struct ChainJoint {
+    qpos_index: usize,
+    dof_index: usize,
+    nv: usize,
+    //Range of a limited hinge or slide
+    range: Option<(f32,f32)>
+}
+
+/* Joints on the path from end_effector (a body, geom or site) up to the world */
+fn chain_joints(model: &Model, end_effector: NodeId) -> Vec<ChainJoint> {
+    let carrying = carrying_joints(model, end_effector);
+    let mut chain = Vec::new();
+    let (mut offset, mut dof) = (0, 0);
+    for id in model.joints() {
+        let Node::Joint(j) = model.graph.get(id) else {
+            continue;
+        };
+        if carrying.contains(&id) {
+            let single = j.get_joint_type() == JointType::Hinge || j.get_joint_type() == JointType::Slide;
+            chain.push(ChainJoint {
+                qpos_index: offset,
+                dof_index: dof,
+                nv: j.nv(),
+                range: if single && j.is_limited() { j.get_range() } else { None }
+            });
+        }
+        offset += j.nq();
+        dof += j.nv();
+    }
+    chain
+}
+
+/* Jacobian of the end effector's position (rows 0-2) & rotation (rows 3-5) 
+with respect to each degree of freedom of the chain joints */
+fn chain_jacobian(model: &Model, poses: &Poses, chain: &[ChainJoint], end_effector: NodeId, 
+        point: &Point3<f32>) -> DMatrix<f32> {
+    let (jp, jr) = jacobian(model, poses, end_effector, point);
+    let mut chain_jacobian = DMatrix::zeros(6, chain.iter().map(|joint| joint.nv).sum());
+    let mut column = 0;
+    for joint in chain {
+        for k in 0..joint.nv {
+            chain_jacobian.fixed_view_mut::<3, 1>(0, column).copy_from(&jp.column(joint.dof_index + k));
+            chain_jacobian.fixed_view_mut::<3, 1>(3, column).copy_from(&jr.column(joint.dof_index + k));
+            column += 1;
+        }
+    }
+    chain_jacobian
+}
+
+/* Position & rotation error of the end effector, as world frame vectors */
+fn pose_error(current: &Isometry3<f32>, target: &Isometry3<f32>) -> (Vector3<f32>,Vector3<f32>) {
+    let position = target.translation.vector - current.translation.vector;
+    let rotation = (target.rotation * current.rotation.inverse()).scaled_axis();
+    (position, rotation)
+}
+
+/* Solves for joint positions placing end_effector (a body, geom or site) at 
+target, starting from the model's current joint positions. The model isn't 
+changed; see solve_and_apply */
+pub fn solve(model: &Model, end_effector: NodeId, target: &Isometry3<f32>, options: &IkOptions) -> IkSolution {
+    let chain = chain_joints(model, end_effector);
+    let mut qpos = model.get_qpos();
+    let mut solution = IkSolution {
+        qpos: qpos.clone(),
+        converged: false,
+        iterations: 0,
+        position_error: f32::INFINITY,
+        rotation_error: f32::INFINITY
+    };
+    for iteration in 0..=options.max_iterations {
+        let poses = forward_kinematics(model, &qpos);
+        let Some(current) = poses.get(end_effector) else {
+            break;
+        };
+        let (position_error, rotation_error) = pose_error(&current.isometry, target);
+        solution.qpos = qpos.clone();
+        solution.iterations = iteration;
+        solution.position_error = position_error.norm();
+        solution.rotation_error = if options.match_orientation { rotation_error.norm() } else { 0.0 };
+        if solution.position_error <= options.position_tolerance && 
+                solution.rotation_error <= options.rotation_tolerance {
+            solution.converged = true;
+            break;
+        }
+        if iteration == options.max_iterations || chain.is_empty() {
+            break;
+        }
+        let mut jacobian = chain_jacobian(model, &poses, &chain, end_effector, 
+            &Point3::from(current.isometry.translation.vector));
+        let mut error = DVector::from_iterator(6, position_error.iter().chain(rotation_error.iter()).copied());
+        if !options.match_orientation {
+            jacobian = jacobian.rows(0, 3).into_owned();
+            error = error.rows(0, 3).into_owned();
+        }
+        //dq = J^T (J J^T + damping^2 I)^-1 e
+        let rows = jacobian.nrows();
+        let jjt = &jacobian * jacobian.transpose() + DMatrix::identity(rows, rows) * options.damping.powi(2);
+        let Some(step) = jjt.lu().solve(&error) else {
+            break;
+        };
+        let step = jacobian.transpose() * step;
+        //Joints off the chain don't move
+        let mut dq = vec![0.0; model.nv()];
+        let mut column = 0;
+        for joint in &chain {
+            for k in 0..joint.nv {
+                dq[joint.dof_index + k] = step[column];
+                column += 1;
+            }
+        }
+        qpos = integrate(model, &qpos, &dq);
+        for joint in &chain {
+            if let Some((low, high)) = joint.range {
+                qpos[joint.qpos_index] = qpos[joint.qpos_index].clamp(low, high);
+            }
+        }
+    }
+    solution
+}
+
+/* Same as solve, setting the model's joints to the result so it's drawn in the solved pose */
+pub fn solve_and_apply(model: &mut Model, end_effector: NodeId, target: &Isometry3<f32>, 
+        options: &IkOptions) -> IkSolution {
+    let solution = solve(model, end_effector, target, options);
+    model.set_qpos(&solution.qpos);
+    solution
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::tests::{load_str, ur5e_path};
+
+    #[test]
+    fn ur5e_reaches_a_pose_it_was_placed_in() {
+        let mut model = Model::load_model(ur5e_path()).expect("Expected ur5e.xml to load");
+        let site = model.find_by_name("site", "attachment_site").expect("Expected the attachment site");
+        let goal = [0.4, -1.1, 1.3, -1.6, -1.4, 0.6];
+        let target = forward_kinematics(&model, &goal).get(site).expect("Expected the site to be posed").isometry;
+        let options = IkOptions::default();
+        let solution = solve(&model, site, &target, &options);
+        assert!(solution.converged, "{:?}", solution);
+        assert!(solution.position_error <= options.position_tolerance, "{:?}", solution);
+        assert!(solution.rotation_error <= options.rotation_tolerance, "{:?}", solution);
+        //The solution may differ from goal, but must place the site at the target
+        let reached = forward_kinematics(&model, &solution.qpos).get(site).expect("Expected the site to be posed").isometry;
+        let (position, rotation) = pose_error(&reached, &target);
+        assert!(position.norm() <= options.position_tolerance && rotation.norm() <= options.rotation_tolerance);
+
+        let applied = solve_and_apply(&mut model, site, &target, &options);
+        assert_eq!(model.get_qpos(), applied.qpos);
+    }
+
+    #[test]
+    fn limited_joints_stay_in_range_and_joints_off_the_chain_stay_put() {
+        let mut model = load_str("ik-limits", r#"<mujoco>
+            <compiler angle="radian"/>
+            <worldbody>
+                <body name="arm">
+                    <joint name="hinge" axis="0 0 1" range="-0.5 0.5"/>
+                    <geom type="capsule" fromto="0 0 0 1 0 0" size="0.05"/>
+                    <site name="tip" pos="1 0 0"/>
+                </body>
+                <body name="other" pos="0 2 0">
+                    <joint name="spin" axis="0 0 1"/>
+                </body>
+            </worldbody>
+        </mujoco>"#).expect("Expected the test model to load");
+        let spin = model.find_by_name("joint", "spin").expect("Expected the spin joint");
+        model.set_joint_qpos(spin, &[0.3]);
+        let tip = model.find_by_name("site", "tip").expect("Expected the tip site");
+        //Behind the hinge at about -2.2 rad, far outside what its range allows
+        let target = Isometry3::translation(-0.6, -0.8, 0.0);
+        let options = IkOptions { match_orientation: false, ..IkOptions::default() };
+        let solution = solve(&model, tip, &target, &options);
+        assert!(!solution.converged);
+        assert!(solution.qpos[0] >= -0.5 && solution.qpos[0] <= 0.5, "{:?}", solution.qpos);
+        assert!((solution.qpos[0] + 0.5).abs() < 1e-3, "The hinge should end up at its lower limit: {:?}", solution.qpos);
+        assert_eq!(solution.qpos[1], 0.3);
+    }
+
+    #[test]
+    fn rotation_is_ignored_unless_matched() {
+        let model = load_str("ik-orientation", r#"<mujoco>
+            <compiler angle="radian"/>
+            <worldbody>
+                <body name="arm">
+                    <joint name="hinge" axis="0 0 1"/>
+                    <site name="tip" pos="1 0 0"/>
+                </body>
+            </worldbody>
+        </mujoco>"#).expect("Expected the test model to load");
+        let tip = model.find_by_name("site", "tip").expect("Expected the tip site");
+        //Reachable position, but with a rotation about x the hinge can't produce
+        let target = Isometry3::new(Vector3::new(0.0, 1.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        let position_only = solve(&model, tip, &target, &IkOptions { match_orientation: false, ..IkOptions::default() });
+        assert!(position_only.converged, "{:?}", position_only);
+        assert_eq!(position_only.rotation_error, 0.0);
+        assert!((position_only.qpos[0] - std::f32::consts::FRAC_PI_2).abs() < 1e-3, "{:?}", position_only.qpos);
+        let full = solve(&model, tip, &target, &IkOptions::default());
+        assert!(!full.converged);
+        assert!(full.rotation_error > 0.5, "{:?}", full);
+    }
+}
@@ -0,0 +1,332 @@
+use nalgebra::{Isometry3, Matrix3, Point3, Vector3};
+use std::f32::consts::PI;
+use crate::kinematics::Poses;
+use crate::model::Model;
+use crate::node::{Node, Geom, GeomType, Compiler};
+use crate::scene::{NodeId, SceneGraph};
+use crate::visitor::depth_first;
+
+/* Mass, center of mass & inertia tensor about the center of mass.
+Body properties are given in the body's own frame */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MassProperties {
+    pub mass: f32,
+    pub com: Point3<f32>,
+    pub inertia: Matrix3<f32>
+}
+
+impl MassProperties {
+    pub fn zero() -> Self {
+        MassProperties {
+            mass: 0.0,
+            com: Point3::origin(),
+            inertia: Matrix3::zeros()
+        }
+    }
+
+    /* The same properties seen from a frame in which this one sits at pose */
+    pub fn transformed(&self, pose: &Isometry3<f32>) -> Self {
+        let r = pose.rotation.to_rotation_matrix();
+        MassProperties {
+            mass: self.mass,
+            com: pose * self.com,
+            inertia: r.matrix() * self.inertia * r.matrix().transpose()
+        }
+    }
+
+    /* Properties of both parts taken as one rigid body, both in the same frame */
+    pub fn combine(&self, other: &MassProperties) -> Self {
+        let mass = self.mass + other.mass;
+        if mass <= 0.0 {
+            return *self;
+        }
+        let com = Point3::from((self.com.coords * self.mass + other.com.coords * other.mass) / mass);
+        //Parallel axis theorem, moving both inertias to the common center of mass
+        let shifted = |part: &MassProperties| {
+            let d = part.com - com;
+            part.inertia + (Matrix3::identity() * d.norm_squared() - d * d.transpose()) * part.mass
+        };
+        MassProperties {
+            mass: mass,
+            com: com,
+            inertia: shifted(self) + shifted(other)
+        }
+    }
+}
+
+/* Mass properties of a single geom in its body's frame, following MuJoCo's
+compiler: the geom's mass when set, otherwise its density times its volume.
+Planes, height fields & meshes without volume have none */
+pub fn geom_mass_properties(graph: &SceneGraph, geom: &Geom) -> Option<MassProperties> {
+    let (volume, com, inertia) = unit_density_properties(graph, geom)?;
+    if volume <= 0.0 {
+        return None;
+    }
+    let density = geom.get_mass().map_or(geom.get_density(), |mass| mass / volume);
+    let properties = MassProperties {
+        mass: density * volume,
+        com: com,
+        inertia: inertia * density
+    };
+    Some(properties.transformed(&geom.get_local_pose().isometry))
+}
+
+/* Volume, center of mass & inertia for a density of 1, in the geom's frame */
+fn unit_density_properties(graph: &SceneGraph, geom: &Geom) -> Option<(f32,Point3<f32>,Matrix3<f32>)> {
+    let size = geom.get_size();
+    let diagonal = |volume: f32, x: f32, y: f32, z: f32| {
+        Some((volume, Point3::origin(), Matrix3::from_diagonal(&Vector3::new(x, y, z))))
+    };
+    match geom.get_geom_type() {
+        GeomType::Sphere => {
+            let r = size[0];
+            let volume = 4.0 / 3.0 * PI * r.powi(3);
+            let i = 0.4 * volume * r * r;
+            diagonal(volume, i, i, i)
+        },
+        GeomType::Box => {
+            let (a, b, c) = (size[0], size[1], size[2]);
+            let volume = 8.0 * a * b * c;
+            diagonal(volume, volume * (b * b + c * c) / 3.0, volume * (a * a + c * c) / 3.0,
+                volume * (a * a + b * b) / 3.0)
+        },
+        GeomType::Ellipsoid => {
+            let (a, b, c) = (size[0], size[1], size[2]);
+            let volume = 4.0 / 3.0 * PI * a * b * c;
+            diagonal(volume, volume * (b * b + c * c) / 5.0, volume * (a * a + c * c) / 5.0,
+                volume * (a * a + b * b) / 5.0)
+        },
+        GeomType::Cylinder => {
+            //size holds the radius & half the height
+            let (r, h) = (size[0], 2.0 * size[1]);
+            let volume = PI * r * r * h;
+            let i = volume * (3.0 * r * r + h * h) / 12.0;
+            diagonal(volume, i, i, volume * r * r / 2.0)
+        },
+        GeomType::Capsule => {
+            //A cylinder with a hemisphere at each end, as MuJoCo computes it
+            let (r, h) = (size[0], 2.0 * size[1]);
+            let cylinder = PI * r * r * h;
+            let sphere = 4.0 / 3.0 * PI * r.powi(3);
+            let i = cylinder * (3.0 * r * r + h * h) / 12.0 +
+                sphere * (0.4 * r * r + h * h / 4.0 + 3.0 * h * r / 8.0);
+            diagonal(cylinder + sphere, i, i, cylinder * r * r / 2.0 + sphere * 0.4 * r * r)
+        },
+        GeomType::Mesh => match graph.get(geom.get_mesh()?) {
+            Node::Mesh(mesh) => {
+                let (vertices, indices) = mesh.getAllGeometries();
+                mesh_properties(&vertices, &indices)
+            },
+            _ => None
+        },
+        GeomType::Plane | GeomType::Hfield | GeomType::Sdf => None
+    }
+}
+
+/* Integrates over the tetrahedra joining the origin to each triangle. The 
+mesh is expected to be closed & consistently wound, as convex hulls are; when 
+it's wound inwards the result is flipped */
+fn mesh_properties(vertices: &[f32], indices: &[u32]) -> Option<(f32,Point3<f32>,Matrix3<f32>)> {
+    let vertex_count = vertices.len() / 3;
+    if vertex_count == 0 {
+        return None;
+    }
+    let vertex = |i: usize| Vector3::new(vertices[3 * i] as f64, vertices[3 * i + 1] as f64, vertices[3 * i + 2] as f64);
+    //Second moments of the tetrahedron (0, e1, e2, e3)
+    let canonical = Matrix3::new(
+        2.0, 1.0, 1.0,
+        1.0, 2.0, 1.0,
+        1.0, 1.0, 2.0) / 120.0;
+    let mut volume = 0.0;
+    let mut first = Vector3::zeros();
+    let mut second = Matrix3::<f64>::zeros();
+    for triangle in indices.chunks_exact(3) {
+        if triangle.iter().any(|i| *i as usize >= vertex_count) {
+            continue;
+        }
+        let edges = Matrix3::from_columns(&[
+            vertex(triangle[0] as usize),
+            vertex(triangle[1] as usize),
+            vertex(triangle[2] as usize)]);
+        let det = edges.determinant();
+        volume += det / 6.0;
+        first += edges.column_sum() * det / 24.0;
+        second += edges * canonical * edges.transpose() * det;
+    }
+    if volume < 0.0 {
+        volume = -volume;
+        first = -first;
+        second = -second;
+    }
+    if volume <= f64::EPSILON {
+        return None;
+    }
+    let com = first / volume;
+    let covariance = second - com * com.transpose() * volume;
+    let inertia = Matrix3::identity() * covariance.trace() - covariance;
+    Some((volume as f32, Point3::from(com.cast::<f32>()), inertia.cast::<f32>()))
+}
+
+/* Mass properties of a body in its own frame. With the compiler's default
+inertiafromgeom="auto" the body's <inertial> is used when it has one,
+otherwise its geoms are summed up. The worldbody has no mass */
+pub fn body_mass_properties(model: &Model, body: NodeId) -> MassProperties {
+    if !matches!(model.graph.get(body), Node::Body(_)) {
+        return MassProperties::zero();
+    }
+    let from_geom = match model.compiler.map(|c| model.graph.get(c)) {
+        Some(Node::Compiler(c)) => c.get_inertia_from_geom(),
+        _ => Compiler::new().get_inertia_from_geom()
+    };
+    let children = model.graph.children(body);
+    let inertial = children.iter().find_map(|child| match model.graph.get(*child) {
+        Node::Inertial(i) => Some(i),
+        _ => None
+    });
+    match (from_geom, inertial) {
+        (Some(true), _) | (None, None) => children.iter().fold(MassProperties::zero(), |total, child| {
+            match model.graph.get(*child) {
+                Node::Geom(g) => geom_mass_properties(&model.graph, g).map_or(total, |p| total.combine(&p)),
+                _ => total
+            }
+        }),
+        (_, Some(i)) => MassProperties {
+            mass: i.get_mass(),
+            com: Point3::from(i.get_pos()),
+            inertia: i.get_inertia()
+        },
+        (Some(false), None) => MassProperties::zero()
+    }
+}
+
+/* Total mass of a body & all the bodies below it */
+pub fn subtree_mass(model: &Model, body: NodeId) -> f32 {
+    depth_first(&model.graph, body).map(|id| body_mass_properties(model, id).mass).sum()
+}
+
+/* World position of the center of mass of a body & all the bodies below it,
+placed at poses. None when the subtree has no mass */
+pub fn subtree_com(model: &Model, poses: &Poses, body: NodeId) -> Option<Point3<f32>> {
+    let mut mass = 0.0;
+    let mut moment = Vector3::zeros();
+    for id in depth_first(&model.graph, body) {
+        if let Some(pose) = poses.get(id) {
+            let properties = body_mass_properties(model, id);
+            mass += properties.mass;
+            moment += (pose.isometry * properties.com).coords * properties.mass;
+        }
+    }
+    if mass > 0.0 {
+        Some(Point3::from(moment / mass))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::tests::load_str;
+
+    fn geom_properties(model: &Model, name: &str) -> MassProperties {
+        match model.graph.get(model.find_by_name("geom", name).expect("Expected the geom")) {
+            Node::Geom(g) => geom_mass_properties(&model.graph, g).expect("Expected the geom to have mass"),
+            _ => panic!("Expected {} to be a geom", name)
+        }
+    }
+
+    fn assert_close(actual: Matrix3<f32>, expected: Matrix3<f32>) {
+        assert!((actual - expected).norm() <= 1e-3 * expected.norm().max(1.0), "{} vs {}", actual, expected);
+    }
+
+    #[test]
+    fn hull_meshes_are_wound_outwards() {
+        let model = load_str("hull-winding", r#"<mujoco>
+            <asset>
+                <mesh name="slab" vertex="1 0 0  3 0 0  1 1 0  3 1 0  1 0 1  3 0 1  1 1 1  3 1 1  2 0.5 0.5"/>
+            </asset>
+            <worldbody><geom name="slab" type="mesh" mesh="slab"/></worldbody>
+        </mujoco>"#).expect("Expected the model to load");
+        let mesh = model.find_by_name("geom", "slab").and_then(|id| match model.graph.get(id) {
+            Node::Geom(g) => g.get_mesh(),
+            _ => None
+        }).expect("Expected the geom's mesh");
+        let Node::Mesh(mesh) = model.graph.get(mesh) else {
+            panic!("Expected a mesh");
+        };
+        let (vertices, indices) = mesh.getAllGeometries();
+        let vertex = |i: u32| Vector3::new(vertices[3 * i as usize], vertices[3 * i as usize + 1], vertices[3 * i as usize + 2]);
+        let center = Vector3::new(2.0, 0.5, 0.5);
+        for triangle in indices.chunks_exact(3) {
+            let [a, b, c] = [vertex(triangle[0]), vertex(triangle[1]), vertex(triangle[2])];
+            assert!((b - a).cross(&(c - a)).dot(&(a - center)) > 0.0, "{:?} faces inwards", triangle);
+        }
+        //A 2 x 1 x 1 box at the default density
+        let properties = geom_properties(&model, "slab");
+        assert!((properties.mass - 2000.0).abs() < 0.1, "{}", properties.mass);
+        assert!((properties.com.coords - center).norm() < 1e-4, "{}", properties.com);
+        assert_close(properties.inertia, Matrix3::from_diagonal(&Vector3::new(2.0, 5.0, 5.0)) * 2000.0 / 12.0);
+    }
+
+    #[test]
+    fn primitive_geoms_match_closed_forms() {
+        let model = load_str("closed-forms", r#"<mujoco>
+            <worldbody>
+                <geom name="box" type="box" size="0.1 0.2 0.3" mass="2" pos="1 0 0"/>
+                <geom name="cylinder" type="cylinder" size="0.1 0.25"/>
+                <geom name="capsule" type="capsule" size="0.1 0.2" density="500"/>
+                <geom name="lying" type="cylinder" fromto="0 0 0 0.5 0 0" size="0.1"/>
+            </worldbody>
+        </mujoco>"#).expect("Expected the model to load");
+        let diagonal = |x: f32, y: f32, z: f32| Matrix3::from_diagonal(&Vector3::new(x, y, z));
+
+        //Solid cuboid with edges 0.2, 0.4 & 0.6
+        let cuboid = geom_properties(&model, "box");
+        assert_eq!(cuboid.mass, 2.0);
+        assert!((cuboid.com.coords - Vector3::new(1.0, 0.0, 0.0)).norm() < 1e-6);
+        assert_close(cuboid.inertia, diagonal(0.16 + 0.36, 0.04 + 0.36, 0.04 + 0.16) * 2.0 / 12.0);
+
+        //Solid cylinder of radius 0.1 & height 0.5
+        let (r, h) = (0.1_f32, 0.5_f32);
+        let mass = 1000.0 * PI * r * r * h;
+        let cylinder = geom_properties(&model, "cylinder");
+        assert!((cylinder.mass - mass).abs() < 1e-3);
+        let across = mass * (3.0 * r * r + h * h) / 12.0;
+        assert_close(cylinder.inertia, diagonal(across, across, mass * r * r / 2.0));
+        //The same cylinder laid along x by fromto
+        let lying = geom_properties(&model, "lying");
+        assert!((lying.com.coords - Vector3::new(0.25, 0.0, 0.0)).norm() < 1e-6);
+        assert_close(lying.inertia, diagonal(mass * r * r / 2.0, across, across));
+
+        //Cylinder of radius 0.1 & height 0.4 with two hemispherical caps, each 
+        //hemisphere's inertia shifted from its own center of mass (3r/8 from the flat face)
+        let (r, h) = (0.1_f32, 0.4_f32);
+        let cylinder_mass = 500.0 * PI * r * r * h;
+        let hemisphere_mass = 500.0 * 2.0 / 3.0 * PI * r.powi(3);
+        let cap_offset = h / 2.0 + 3.0 * r / 8.0;
+        let cap_own = hemisphere_mass * (2.0 / 5.0 - 9.0 / 64.0) * r * r;
+        let across = cylinder_mass * (3.0 * r * r + h * h) / 12.0 + 
+            2.0 * (cap_own + hemisphere_mass * cap_offset * cap_offset);
+        let along = cylinder_mass * r * r / 2.0 + 2.0 * hemisphere_mass * 2.0 / 5.0 * r * r;
+        let capsule = geom_properties(&model, "capsule");
+        assert!((capsule.mass - cylinder_mass - 2.0 * hemisphere_mass).abs() < 1e-3);
+        assert_close(capsule.inertia, diagonal(across, across, along));
+    }
+
+    #[test]
+    fn inertials_need_a_mass_and_no_orientation_with_fullinertia() {
+        let with = |inertial: &str| load_str("inertial-checks", &format!(r#"<mujoco>
+            <worldbody><body name="link">{}<geom type="sphere" size="0.1"/></body></worldbody>
+        </mujoco>"#, inertial));
+        let bad_key = |result: Result<Model,crate::model::ModelLoadError>| match result {
+            Err(crate::model::ModelLoadError::BadAttribute { error, .. }) => error.key,
+            other => panic!("Expected a bad attribute, got {:?}", other.err())
+        };
+        assert_eq!(bad_key(with(r#"<inertial pos="0 0 0" diaginertia="1 1 1"/>"#)), "mass");
+        assert_eq!(bad_key(with(r#"<inertial mass="1" pos="0 0 0" fullinertia="1 1 1 0 0 0" euler="0 0 90"/>"#)), "fullinertia");
+        let model = with(r#"<inertial mass="1" pos="0 0 0" diaginertia="1 2 3" euler="0 0 90"/>"#).
+            expect("Expected an oriented diaginertia to load");
+        let link = model.find_by_name("body", "link").expect("Expected the body");
+        assert_close(body_mass_properties(&model, link).inertia, Matrix3::from_diagonal(&Vector3::new(2.0, 1.0, 3.0)));
+    }
+}
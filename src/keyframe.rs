@@ -0,0 +1,35 @@
+use crate::node::{RawElement, NodeType, AttributeError, parse_attr_values};
+
+/* A saved state from the <keyframe> section. Values that weren't given in 
+the key are left empty, applying the keyframe then uses the model's defaults */
+#[derive(Debug, Clone)]
+pub struct Keyframe {
+    pub name: String,
+    pub time: f32,
+    pub qpos: Vec<f32>,
+    pub ctrl: Vec<f32>
+}
+
+impl Keyframe {
+    /* Reads a <key> element, checking qpos & ctrl against the model's 
+    number of joint positions (nq) & actuators (nu) */
+    pub fn from_key(key: &RawElement, nq: usize, nu: usize) -> Result<Self,AttributeError> {
+        let attrs = key.get_set_attributes_list();
+        let values = |name: &str, count: usize| -> Result<Vec<f32>,AttributeError> {
+            match attrs.get(name) {
+                Some(value) => parse_attr_values::<f32>(name, value, count..=count),
+                None => Ok(Vec::new())
+            }
+        };
+        let time = match attrs.get("time") {
+            Some(value) => parse_attr_values::<f32>("time", value, 1..=1)?[0],
+            None => 0.0
+        };
+        Ok(Keyframe {
+            name: key.get_name(),
+            time: time,
+            qpos: values("qpos", nq)?,
+            ctrl: values("ctrl", nu)?
+        })
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::{HashMap, HashSet};
+use nalgebra::{DMatrix, Isometry3, Vector3, Point3, Unit, UnitQuaternion, Quaternion};
+use crate::drawable::MeshCollection;
+use crate::model::Model;
+use crate::node::{Node, NodeType, Compiler, JointType, Orientation, RawElement, ORIENTATION_ATTRS, parse_attr_values};
+use crate::scene::NodeId;
+use crate::transform::Transform;
+use crate::visitor::depth_first;
+
+/* World poses of a model's bodies, geoms & sites for one set of joint positions */
+#[derive(Debug, Clone)]
+pub struct Poses {
+    poses: HashMap<NodeId,Transform>,
+    //World anchor & axis of each joint
+    joints: HashMap<NodeId,(Point3<f32>,Unit<Vector3<f32>>)>,
+    //World frame of each joint's body, displaced up to & including that joint
+    joint_frames: HashMap<NodeId,Isometry3<f32>>
+}
+
+impl Poses {
+    /* World pose of a body, geom or site. The worldbody is at the origin */
+    pub fn get(&self, id: NodeId) -> Option<&Transform> {
+        self.poses.get(&id)
+    }
+
+    /* World position of a joint's anchor & direction of its axis. Each joint is 
+    taken in its body's frame as displaced by the body's earlier joints, as MuJoCo does */
+    pub fn joint_axis(&self, joint: NodeId) -> Option<(Point3<f32>,Unit<Vector3<f32>>)> {
+        self.joints.get(&joint).copied()
+    }
+
+    /* World frame of a joint's body as displaced by its earlier joints & the joint 
+    itself, but not the ones after it. Ball & free joints rotate about its axes */
+    pub fn joint_frame(&self, joint: NodeId) -> Option<&Isometry3<f32>> {
+        self.joint_frames.get(&joint)
+    }
+}
+
+/* Computes every pose in a single pass down the tree, parents before children.
+qpos is laid out as Model::get_qpos; joints past the end of a short qpos are
+taken at their current position. Runtime rotations (Body::apply_added_rotations)
+are included, as they are when drawing */
+pub fn forward_kinematics(model: &Model, qpos: &[f32]) -> Poses {
+    let mut poses = HashMap::new();
+    let mut joints = HashMap::new();
+    let mut joint_frames = HashMap::new();
+    let Some(world_body) = model.world_body else {
+        return Poses { poses: poses, joints: joints, joint_frames: joint_frames };
+    };
+    let compiler = match model.compiler.map(|c| model.graph.get(c)) {
+        Some(Node::Compiler(c)) => c.clone(),
+        _ => Compiler::new()
+    };
+    let graph = &model.graph;
+    let origin = Transform::identity();
+    let mut offset = 0;
+    //Pre-order, so a body's parent is always placed before it
+    for id in depth_first(graph, world_body) {
+        let parent_pose = match graph.parent(id) {
+            Some(parent) => match poses.get(&parent) {
+                Some(pose) => pose,
+                None => continue
+            },
+            None => &origin
+        };
+        let pose = match graph.get(id) {
+            Node::WorldBody(_) => Transform::identity(),
+            Node::Body(b) => {
+                let body_pose = b.get_model_pose().isometry;
+                let mut motion = Isometry3::identity();
+                //Joints take their qpos values in the same order as Model::joints
+                for child in graph.children(id) {
+                    if let Node::Joint(j) = graph.get(*child) {
+                        let frame = parent_pose.compose(&b.local_pose_with(&motion));
+                        joints.insert(*child, (frame.transform_point(&Point3::from(j.get_pos())),
+                            Unit::new_normalize(frame.rotation() * j.get_axis().into_inner())));
+                        if offset + j.nq() <= qpos.len() {
+                            motion = motion * j.motion_at(&qpos[offset..], &body_pose);
+                        } else {
+                            motion = motion * j.motion(&body_pose);
+                        }
+                        offset += j.nq();
+                        joint_frames.insert(*child, parent_pose.compose(&b.local_pose_with(&motion)).isometry);
+                    }
+                }
+                parent_pose.compose(&b.local_pose_with(&motion))
+            },
+            Node::Geom(g) => parent_pose.compose(&g.get_local_pose()),
+            Node::Raw(r) if r.get_node_name() == "site" => parent_pose.compose(&site_local_pose(r, &compiler)),
+            _ => continue
+        };
+        poses.insert(id, pose);
+    }
+    Poses { poses: poses, joints: joints, joint_frames: joint_frames }
+}
+
+/* Sites are kept as raw elements, their placement is read from the attributes.
+Values that don't parse are left at their defaults */
+fn site_local_pose(site: &RawElement, compiler: &Compiler) -> Transform {
+    let attrs = site.get_set_attributes_list();
+    let mut pose = Transform::identity();
+    if let Some(Ok(pos)) = attrs.get("pos").map(|value| parse_attr_values::<f32>("pos", value, 3..=3)) {
+        pose.isometry.translation.vector = Vector3::new(pos[0], pos[1], pos[2]);
+    }
+    for key in ORIENTATION_ATTRS {
+        if let Some(Ok(orientation)) = attrs.get(key).map(|value| Orientation::from_attr(key, value)) {
+            pose.isometry.rotation = orientation.to_quaternion(compiler);
+            break;
+        }
+    }
+    pose
+}
+
+/* Geometry of every geom placed at its pose in poses, ready for the painter */
+pub fn geometries(model: &Model, poses: &Poses) -> MeshCollection {
+    let mut meshes = MeshCollection::new();
+    let Some(world_body) = model.world_body else {
+        return meshes;
+    };
+    for id in depth_first(&model.graph, world_body) {
+        if let (Node::Geom(g), Some(parent)) = (model.graph.get(id), model.graph.parent(id)) {
+            if let Some(parent_pose) = poses.get(parent) {
+                meshes = meshes.merge(g.get_geometries_in(&model.graph, parent_pose));
+            }
+        }
+    }
+    meshes
+}
+
+/* Joints that carry node (a body, geom or site), i.e. those of its body & 
+of every body above it */
+pub(crate) fn carrying_joints(model: &Model, node: NodeId) -> HashSet<NodeId> {
+    let mut ancestors = Vec::new();
+    let mut current = Some(node);
+    while let Some(id) = current {
+        ancestors.push(id);
+        current = model.graph.parent(id);
+    }
+    model.joints().into_iter().filter(|id| {
+        model.graph.parent(*id).map_or(false, |body| ancestors.contains(&body))
+    }).collect()
+}
+
+/* Jacobian of a point moving with node (a body, geom or site) over all degrees 
+of freedom: Jp maps joint velocities to the point's linear velocity & Jr to the 
+node's angular velocity, both in the world frame & 3 x Model::nv. Columns follow 
+MuJoCo's layout: a free joint's translation is along the world axes, its rotation 
+& a ball joint's are about the body's own axes as the joint leaves them. Joints that don't carry node 
+have zero columns */
+pub fn jacobian(model: &Model, poses: &Poses, node: NodeId, point: &Point3<f32>) -> (DMatrix<f32>,DMatrix<f32>) {
+    let nv = model.nv();
+    let mut jp = DMatrix::zeros(3, nv);
+    let mut jr = DMatrix::zeros(3, nv);
+    let carrying = carrying_joints(model, node);
+    let mut column = 0;
+    for id in model.joints() {
+        let Node::Joint(j) = model.graph.get(id) else {
+            continue;
+        };
+        let carried = carrying.contains(&id);
+        if let (true, Some((anchor, axis)), Some(frame)) = (carried, poses.joint_axis(id), 
+                poses.joint_frame(id)) {
+            let offset = point - anchor;
+            let body_axes = frame.rotation.to_rotation_matrix();
+            let rotational = |jp: &mut DMatrix<f32>, jr: &mut DMatrix<f32>, column: usize, axis: Vector3<f32>| {
+                jp.fixed_view_mut::<3, 1>(0, column).copy_from(&axis.cross(&offset));
+                jr.fixed_view_mut::<3, 1>(0, column).copy_from(&axis);
+            };
+            match j.get_joint_type() {
+                JointType::Hinge => rotational(&mut jp, &mut jr, column, axis.into_inner()),
+                JointType::Slide => jp.fixed_view_mut::<3, 1>(0, column).copy_from(&axis.into_inner()),
+                JointType::Ball => {
+                    for k in 0..3 {
+                        rotational(&mut jp, &mut jr, column + k, body_axes.matrix().column(k).into_owned());
+                    }
+                },
+                JointType::Free => {
+                    //The rotation is about the body's origin, wherever the translation has moved it
+                    let offset = point - Point3::from(frame.translation.vector);
+                    for k in 0..3 {
+                        let axis = body_axes.matrix().column(k).into_owned();
+                        jp[(k, column + k)] = 1.0;
+                        jp.fixed_view_mut::<3, 1>(0, column + 3 + k).copy_from(&axis.cross(&offset));
+                        jr.fixed_view_mut::<3, 1>(0, column + 3 + k).copy_from(&axis);
+                    }
+                }
+            }
+        }
+        column += j.nv();
+    }
+    (jp, jr)
+}
+
+/* Moves qpos by the joint velocities dq (Model::nv values) applied for unit 
+time. Rotations of ball & free joints are composed onto their quaternions, 
+so they stay normalised */
+pub fn integrate(model: &Model, qpos: &[f32], dq: &[f32]) -> Vec<f32> {
+    let mut result = qpos.to_vec();
+    let (mut q, mut v) = (0, 0);
+    for id in model.joints() {
+        let Node::Joint(j) = model.graph.get(id) else {
+            continue;
+        };
+        let rotate = |result: &mut Vec<f32>, at: usize, omega: Vector3<f32>| {
+            let current = UnitQuaternion::from_quaternion(
+                Quaternion::new(result[at], result[at + 1], result[at + 2], result[at + 3]));
+            let rotated = current * UnitQuaternion::from_scaled_axis(omega);
+            result[at..at + 4].copy_from_slice(&[rotated.w, rotated.i, rotated.j, rotated.k]);
+        };
+        match j.get_joint_type() {
+            JointType::Hinge | JointType::Slide => result[q] += dq[v],
+            JointType::Ball => rotate(&mut result, q, Vector3::new(dq[v], dq[v + 1], dq[v + 2])),
+            JointType::Free => {
+                for k in 0..3 {
+                    result[q + k] += dq[v + k];
+                }
+                rotate(&mut result, q + 3, Vector3::new(dq[v + 3], dq[v + 4], dq[v + 5]));
+            }
+        }
+        q += j.nq();
+        v += j.nv();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::tests::load_str;
+    use nalgebra::Matrix3;
+
+    /* Compares each Jacobian column with the motion of node when the matching 
+    degree of freedom is nudged through integrate */
+    fn check_against_finite_differences(model: &Model, qpos: &[f32], node: NodeId) {
+        let h = 1e-3;
+        let poses = forward_kinematics(model, qpos);
+        let pose = poses.get(node).expect("Expected the node to be posed").isometry;
+        let (jp, jr) = jacobian(model, &poses, node, &Point3::from(pose.translation.vector));
+        assert_eq!(jp.ncols(), model.nv());
+        for i in 0..model.nv() {
+            let mut dq = vec![0.0; model.nv()];
+            dq[i] = h;
+            let moved = forward_kinematics(model, &integrate(model, qpos, &dq)).get(node).
+                expect("Expected the node to be posed").isometry;
+            let linear = (moved.translation.vector - pose.translation.vector) / h;
+            let angular = (moved.rotation * pose.rotation.inverse()).scaled_axis() / h;
+            for k in 0..3 {
+                assert!((jp[(k, i)] - linear[k]).abs() < 5e-3,
+                    "linear column {} row {}: {} vs {}", i, k, jp[(k, i)], linear[k]);
+                assert!((jr[(k, i)] - angular[k]).abs() < 5e-3,
+                    "angular column {} row {}: {} vs {}", i, k, jr[(k, i)], angular[k]);
+            }
+        }
+    }
+
+    #[test]
+    fn hinge_and_slide_columns_match_finite_differences() {
+        let model = load_str("hinge-slide", r#"<mujoco>
+            <worldbody>
+                <body name="upper" pos="0 0 0.5" euler="10 0 20">
+                    <joint name="shoulder" type="hinge" axis="0 1 0.5" pos="0 0.1 0"/>
+                    <geom type="sphere" size="0.05"/>
+                    <body name="lower" pos="0.4 0 0">
+                        <joint name="extend" type="slide" axis="1 0.2 0"/>
+                        <joint name="elbow" type="hinge" axis="0 0 1" pos="0.1 0 0"/>
+                        <site name="tip" pos="0.3 0.1 -0.05"/>
+                    </body>
+                </body>
+            </worldbody>
+        </mujoco>"#).expect("Expected the test model to load");
+        let tip = model.find_by_name("site", "tip").expect("Expected the tip site");
+        check_against_finite_differences(&model, &[0.3, 0.05, -0.7], tip);
+    }
+
+    #[test]
+    fn ball_and_free_columns_match_finite_differences() {
+        let model = load_str("ball-free", r#"<mujoco>
+            <worldbody>
+                <body name="root" pos="0 0 1">
+                    <joint name="float" type="free"/>
+                    <geom type="box" size="0.1 0.1 0.1"/>
+                    <body name="arm" pos="0.3 0 0" euler="0 30 0">
+                        <joint name="socket" type="ball" pos="0.05 0 0"/>
+                        <joint name="hinge" type="hinge" axis="0 1 0" pos="0 0.1 0"/>
+                        <site name="tip" pos="0.4 0 0.1"/>
+                    </body>
+                </body>
+            </worldbody>
+        </mujoco>"#).expect("Expected the test model to load");
+        let tip = model.find_by_name("site", "tip").expect("Expected the tip site");
+        let ball = UnitQuaternion::from_euler_angles(0.3, -0.2, 0.5);
+        let free = UnitQuaternion::from_euler_angles(-0.4, 0.1, 0.2);
+        let qpos = [0.2, -0.1, 1.1, free.w, free.i, free.j, free.k,
+            ball.w, ball.i, ball.j, ball.k, 0.6];
+        check_against_finite_differences(&model, &qpos, tip);
+        //The body itself, which only the free joint carries
+        let root = model.find_by_name("body", "root").expect("Expected the root body");
+        check_against_finite_differences(&model, &qpos, root);
+    }
+
+    /* Link positions worked out by hand from the offsets & rotations in ur5e.xml */
+    #[test]
+    fn ur5e_links_are_placed_as_in_the_model() {
+        let model = Model::load_model(crate::model::tests::ur5e_path()).expect("Expected ur5e.xml to load");
+        let position = |poses: &Poses, kind: &str, name: &str| {
+            let id = model.find_by_name(kind, name).expect("Expected the node");
+            poses.get(id).expect("Expected the node to be posed").isometry.translation.vector
+        };
+        let close = |actual: Vector3<f32>, expected: [f32; 3]| {
+            assert!((actual - Vector3::from(expected)).norm() < 1e-4, "{:?} vs {:?}", actual, expected);
+        };
+        let zero = forward_kinematics(&model, &[0.0; 6]);
+        close(position(&zero, "body", "shoulder_link"), [0.0, 0.0, 0.163]);
+        close(position(&zero, "body", "upper_arm_link"), [0.0, -0.138, 0.163]);
+        close(position(&zero, "body", "forearm_link"), [-0.425, -0.007, 0.163]);
+        close(position(&zero, "body", "wrist_1_link"), [-0.817, -0.007, 0.163]);
+        close(position(&zero, "body", "wrist_2_link"), [-0.817, -0.134, 0.163]);
+        close(position(&zero, "body", "wrist_3_link"), [-0.817, -0.134, 0.063]);
+        close(position(&zero, "site", "attachment_site"), [-0.817, -0.234, 0.063]);
+        //Half a turn about z from the base, then half a turn about y twice over
+        let wrist = model.find_by_name("body", "wrist_3_link").expect("Expected the wrist");
+        let rotation = zero.get(wrist).expect("Expected the wrist to be posed").isometry.rotation.to_rotation_matrix();
+        assert!((rotation.matrix() - Matrix3::from_diagonal(&Vector3::new(1.0, -1.0, -1.0))).norm() < 1e-4);
+
+        //The pan joint turns the whole arm a quarter turn about the world z axis
+        let pan = std::f32::consts::FRAC_PI_2;
+        let panned = forward_kinematics(&model, &[pan, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        close(position(&panned, "body", "forearm_link"), [0.007, -0.425, 0.163]);
+        close(position(&panned, "site", "attachment_site"), [0.234, -0.817, 0.063]);
+
+        //The elbow turns the forearm a quarter turn about -y, through the forearm's origin
+        let bent = forward_kinematics(&model, &[0.0, 0.0, pan, 0.0, 0.0, 0.0]);
+        close(position(&bent, "body", "forearm_link"), [-0.425, -0.007, 0.163]);
+        close(position(&bent, "body", "wrist_1_link"), [-0.425, -0.007, -0.229]);
+        close(position(&bent, "site", "attachment_site"), [-0.325, -0.234, -0.229]);
+    }
+}
@@ -0,0 +1,94 @@
+use std::path::{Path,PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use crate::mesh_ops::MeshProcessing;
+use crate::model::{Model, ModelLoadError, LoadProgress};
+use crate::drawable::MeshCollection;
+use crate::kinematics;
+
+/* A loaded model with its drawables at the initial pose, which are also 
+extracted on the worker as that's costly for large meshes */
+pub struct LoadedModel {
+    pub model: Model,
+    pub geometries: MeshCollection
+}
+
+pub enum LoadEvent {
+    Progress(LoadProgress),
+    Finished(Result<LoadedModel,ModelLoadError>),
+    //The worker went away without a result, e.g. after a panic
+    Aborted
+}
+
+/* Loads a model on a worker thread so the UI stays responsive. The UI polls
+for progress & the finished model; notify is called from the worker whenever
+there's something new to poll (e.g. to request a repaint) */
+pub struct ModelLoader {
+    file: PathBuf,
+    receiver: Receiver<LoadEvent>,
+    done: bool
+}
+
+impl ModelLoader {
+    pub fn start<F: Fn() + Send + 'static>(file: PathBuf, notify: F) -> Self {
+        Self::start_with_options(file, MeshProcessing::default(), notify)
+    }
+
+    /* Same as start, processing the model's meshes as they're loaded */
+    pub fn start_with_options<F: Fn() + Send + 'static>(file: PathBuf, 
+            mesh_processing: MeshProcessing, notify: F) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let worker_file = file.clone();
+        thread::spawn(move || {
+            let result = Model::load_model_with_options(worker_file, &mesh_processing, |progress| {
+                //The receiving side may have been dropped, the load just runs to completion then
+                let _ = sender.send(LoadEvent::Progress(progress));
+                notify();
+            }).map(|model| {
+                let poses = kinematics::forward_kinematics(&model, &model.get_qpos());
+                let geometries = kinematics::geometries(&model, &poses);
+                LoadedModel {
+                    model: model,
+                    geometries: geometries
+                }
+            });
+            let _ = sender.send(LoadEvent::Finished(result));
+            notify();
+        });
+        ModelLoader {
+            file: file,
+            receiver: receiver,
+            done: false
+        }
+    }
+
+    pub fn get_file(&self) -> &Path {
+        &self.file
+    }
+
+    /* True once the Finished or Aborted event has been polled */
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /* Returns the events received since the last poll without blocking */
+    pub fn poll(&mut self) -> Vec<LoadEvent> {
+        let mut events = Vec::new();
+        while !self.done {
+            match self.receiver.try_recv() {
+                Ok(event) => {
+                    if let LoadEvent::Finished(_) = event {
+                        self.done = true;
+                    }
+                    events.push(event);
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.done = true;
+                    events.push(LoadEvent::Aborted);
+                }
+            }
+        }
+        events
+    }
+}
@@ -4,8 +4,12 @@ use eframe::glow::{self};
 use std::sync::{Arc,Mutex};
 use rfd::FileDialog;
 use chrono::{DateTime, Utc};
-use crate::node::Node;
+use crate::node::{Node, NodeType};
 use crate::model::Model;
+use crate::scene::NodeId;
+use crate::kinematics::Poses;
+use crate::loader::{ModelLoader, LoadEvent};
+use crate::mesh_ops::{MeshProcessing, NormalMode};
 use crate::painter::PaintsMan;
 use crate::drawable::{MeshCollection};
 use crate::plugin::rubiks::RubiksCubeModelInterface;
@@ -16,11 +20,24 @@ use std::cell::RefCell;
 use std::time::{SystemTime};
 
 pub mod drawable;
+pub mod ik;
+pub mod inertia;
+pub mod keyframe;
+pub mod kinematics;
 pub mod node;
 pub mod model;
+pub mod loader;
+pub mod mesh_io;
+pub mod mesh_ops;
 pub mod painter;
 pub mod physics_mj;
 pub mod plugin;
+pub mod primitives;
+pub mod query;
+pub mod scene;
+pub mod transform;
+pub mod visitor;
+pub mod writer;
 
 const TOOLBAR_POINTER: &str = "file:///Users/avi/Documents/manual/assembly-theory/assets/blender_icon_restrict_select_on.svg";
 const COLLAPSE: &str = "file:///Users/avi/Documents/manual/assembly-theory/assets/blender_icon_area_join_down.svg";
@@ -29,6 +46,10 @@ const EXPAND: &str = "file:///Users/avi/Documents/manual/assembly-theory/assets/
 const LEFT_COLLAPSE: &str = "file:///Users/avi/Documents/manual/assembly-theory/assets/blender_icon_area_join_left.svg";
 const EXPAND_RIGHT: &str = "file:///Users/avi/Documents/manual/assembly-theory/assets/blender_icon_area_join.svg";
 const CUBE_FILE: &str = "/Users/avi/Documents/manual/assembly-theory/assets/model-2.xml";
+//Starting values of the mesh processing options when they're switched on
+const WELD_TOLERANCE: f32 = 1e-6;
+const DECIMATION_TARGET: usize = 10000;
+const CREASE_ANGLE: f32 = std::f32::consts::PI / 6.0;
 
 fn main() -> eframe::Result {
     env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`).
@@ -91,6 +112,14 @@ struct AssemblyTheory {
     file_assets: HashMap<String,(u32,u32,Vec<u8>)>,
     current_tool: Tool,
     plugins_manager: PluginManager,
+    //Model being loaded in the background, if any
+    loader: Option<ModelLoader>,
+    //Index of the keyframe last picked in the hierarchy panel
+    selected_keyframe: Option<usize>,
+    //Drawables at the model's current pose, cleared whenever the model changes
+    geometries: Option<MeshCollection>,
+    //Processing applied to the meshes of the models loaded from then on
+    mesh_processing: MeshProcessing,
     stamp: SystemTime
 }
 
@@ -113,30 +142,23 @@ impl AssemblyTheory {
             file_assets: HashMap::new(),
             current_tool: Tool::None,
             plugins_manager: PluginManager::new(),
+            loader: None,
+            selected_keyframe: None,
+            geometries: None,
+            mesh_processing: MeshProcessing::default(),
             stamp: SystemTime::now()
         };
         Self::initialize_gl_context(&cc);
         // inst.logger.add_log("No model loaded.");
 
-        inst.model = Some(Model::load_model(std::path::PathBuf::from(CUBE_FILE)));
-        if let Some(m) = &mut inst.model {
-            if let Some(wb) = &m.world_body {
-                inst.plugins_manager.register_plugin(
-                    Rc::new(RefCell::new(RubiksCubeModelInterface::new())),
-                    PluginCapabilities {
-                        process_model_load: true,
-                        process_sim_loop: true,
-                        load_model: false
-                    });
-                inst.plugins_manager.process_model_load(wb.clone());
-            }
-        }
-        inst.view_prop.lock().expect("Expected view prop lock to be available").
-            model_updated = true;
-        inst.view_prop.lock().expect("Expected view prop lock to be available").
-            model_loaded = true;
-        inst.view_prop.lock().expect("Expected view prop lock to be available").
-            texture_loaded = true;
+        inst.plugins_manager.register_plugin(
+            Rc::new(RefCell::new(RubiksCubeModelInterface::new())),
+            PluginCapabilities {
+                process_model_load: true,
+                process_sim_loop: true,
+                load_model: false
+            });
+        inst.start_loading(std::path::PathBuf::from(CUBE_FILE), &cc.egui_ctx);
         inst
     }
 
@@ -149,25 +171,123 @@ impl AssemblyTheory {
 }
 
 impl AssemblyTheory {
-    //Replace angle with UI state to generate the geometries
+    /* Loads the model file on a worker thread, replacing any load in progress */
+    fn start_loading(&mut self, file: std::path::PathBuf, ctx: &egui::Context) {
+        self.logger.add_log(&format!("Loading {}", file.display()));
+        let ctx = ctx.clone();
+        self.loader = Some(ModelLoader::start_with_options(file, self.mesh_processing.clone(), 
+            move || ctx.request_repaint()));
+    }
+
+    /* Logs the background load's progress & takes over the model once it's done */
+    fn poll_loader(&mut self) {
+        let (events, file) = match &mut self.loader {
+            Some(loader) => (loader.poll(), loader.get_file().to_path_buf()),
+            None => return
+        };
+        for event in events {
+            match event {
+                LoadEvent::Progress(progress) => {
+                    self.logger.add_log(&progress.to_string());
+                },
+                LoadEvent::Finished(Ok(loaded)) => {
+                    let mut model = loaded.model;
+                    //Plugins may move things around, the worker's geometries are stale then
+                    let processed = self.plugins_manager.process_model_load(&mut model);
+                    self.geometries = if processed { None } else { Some(loaded.geometries) };
+                    self.model = Some(model);
+                    self.selected_keyframe = None;
+                    self.logger.add_log(&format!("Loaded {}", file.display()));
+                    let mut view_prop = self.view_prop.lock().
+                        expect("Expected view prop lock to be available");
+                    view_prop.model_updated = true;
+                    view_prop.model_loaded = true;
+                    view_prop.texture_loaded = true;
+                },
+                LoadEvent::Finished(Err(e)) => {
+                    self.logger.add_log(&format!("Failed to load {}: {}", file.display(), e));
+                },
+                LoadEvent::Aborted => {
+                    self.logger.add_log(&format!("Loading {} was aborted", file.display()));
+                }
+            }
+        }
+        if self.loader.as_ref().map_or(false, |loader| loader.is_done()) {
+            self.loader = None;
+        }
+    }
+
+    /* Options for processing the meshes of the next model imported */
+    fn show_mesh_processing(ui: &mut egui::Ui, processing: &mut MeshProcessing) {
+        let mut weld = processing.weld_tolerance.is_some();
+        if ui.checkbox(&mut weld, "Weld vertices").changed() {
+            processing.weld_tolerance = weld.then_some(WELD_TOLERANCE);
+        }
+        ui.checkbox(&mut processing.remove_degenerate, "Remove degenerate triangles");
+        let mut decimate = processing.target_triangles.is_some();
+        if ui.checkbox(&mut decimate, "Decimate").changed() {
+            processing.target_triangles = decimate.then_some(DECIMATION_TARGET);
+        }
+        if let Some(target) = processing.target_triangles.as_mut() {
+            ui.add(egui::DragValue::new(target).range(1..=usize::MAX).suffix(" triangles"));
+        }
+        let mut normals = processing.normals.is_some();
+        if ui.checkbox(&mut normals, "Compute normals").changed() {
+            processing.normals = normals.then_some(NormalMode::Crease(CREASE_ANGLE));
+        }
+        if let Some(NormalMode::Crease(angle)) = processing.normals.as_mut() {
+            ui.horizontal(|ui| {
+                ui.label("Crease angle");
+                ui.drag_angle(angle);
+            });
+        }
+    }
+
+    /* Adds node & its descendants to the hierarchy panel. The poses are only
+       computed on the first hover of the frame & shared by the other rows */
+    fn show_hierarchy(ui: &mut egui::Ui, model: &Model, poses: &mut Option<Poses>, id: NodeId) {
+        let graph = &model.graph;
+        let node = graph.get(id);
+        let label = if node.get_name() == "" {
+            node.get_node_name()
+        } else {
+            format!("{} ({})", node.get_name(), node.get_node_name())
+        };
+        let children = graph.children(id);
+        let response = if children.is_empty() {
+            ui.label(label)
+        } else {
+            egui::CollapsingHeader::new(label).id_salt(id).show(ui, |ui| {
+                for child in children {
+                    Self::show_hierarchy(ui, model, poses, *child);
+                }
+            }).header_response
+        };
+        //Mass of the body & everything it carries, at the current pose
+        if let Node::Body(_) | Node::WorldBody(_) = node {
+            response.on_hover_ui(|ui| {
+                let poses = poses.get_or_insert_with(||
+                    kinematics::forward_kinematics(model, &model.get_qpos()));
+                let mass = inertia::body_mass_properties(model, id).mass;
+                ui.label(format!("Mass: {:.4}", mass));
+                ui.label(format!("Subtree mass: {:.4}", inertia::subtree_mass(model, id)));
+                if let Some(com) = inertia::subtree_com(model, poses, id) {
+                    ui.label(format!("Subtree COM: {:.4} {:.4} {:.4}", com.x, com.y, com.z));
+                }
+            });
+        }
+    }
+
+    /* The drawables at the model's current pose, only extracted again after the model changed */
     fn extract_geometries(&mut self) -> MeshCollection {
-        match &self.model {
+        let model = &self.model;
+        self.geometries.get_or_insert_with(|| match model {
             None => MeshCollection::new(),
             Some(model) => {
-                let node = &model.world_body;
-                if let Some(node) = node {
-                    match *node.borrow_mut() {
-                        Node::WorldBody(ref node) => {
-                            let geometries = node.getAllGeometries();
-                            geometries
-                        },
-                        _ => MeshCollection::new()
-                    }
-                } else {
-                    MeshCollection::new()
-                }
+                let poses = kinematics::forward_kinematics(model, &model.get_qpos());
+                kinematics::geometries(model, &poses)
             }
-        }
+        }).clone()
     }
 
     fn custom_painting(&mut self, ui: &mut egui::Ui, zoom_scale: f32) {
@@ -196,12 +316,12 @@ impl AssemblyTheory {
 
         //Synchronously let all the plugins process the sim loop
         //TODO: This might need to be more closely associated with simul loop
-        if let Some(m) = &self.model {
-            if let Some(wb) = &m.world_body {
-                self.plugins_manager.process_sim_loop(
+        if let Some(m) = &mut self.model {
+            if self.plugins_manager.process_sim_loop(
                     self.view_prop.clone(),
                     self.stamp.elapsed().unwrap(), 
-                    wb.clone());
+                    m) {
+                self.geometries = None;
             }
         }
         
@@ -257,6 +377,7 @@ impl AssemblyTheory {
 
 impl eframe::App for AssemblyTheory {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.poll_loader();
         egui::TopBottomPanel::top("menu-bar").
             frame(egui::Frame::default().
             // fill(egui::Color32::DARK_GRAY).
@@ -266,16 +387,13 @@ impl eframe::App for AssemblyTheory {
                     if ui.button("Import").clicked() {
                         if let Some(file) = FileDialog::new().
                             add_filter("models", &["xml"]).pick_file() {
-                            self.model = Some(Model::load_model(file));
-                            self.view_prop.lock().expect("Expected view prop lock to be available").
-                                model_updated = true;
-                            self.view_prop.lock().expect("Expected view prop lock to be available").
-                                model_loaded = true;
-                            self.view_prop.lock().expect("Expected view prop lock to be available").
-                                texture_loaded = true;
+                            self.start_loading(file, ctx);
                         }
                         ui.close_kind(egui::UiKind::Menu);
                     }
+                    ui.menu_button("Mesh processing", |ui| {
+                        Self::show_mesh_processing(ui, &mut self.mesh_processing);
+                    });
                 });
             });
         if !self.view_prop.lock().expect("Had an issue locking the viewport properties").
@@ -316,6 +434,37 @@ impl eframe::App for AssemblyTheory {
                 ui.vertical_centered(|ui| {
                     ui.heading("Model hierarchy");
                 });
+                if let Some(model) = &mut self.model {
+                    if !model.keyframes.is_empty() {
+                        let label = |index: usize| {
+                            let name = &model.keyframes[index].name;
+                            if name == "" { format!("Key {}", index) } else { name.clone() }
+                        };
+                        let mut selected = self.selected_keyframe;
+                        egui::ComboBox::from_label("Keyframe").
+                            selected_text(selected.map_or("None".to_string(), |i| label(i))).
+                            show_ui(ui, |ui| {
+                                for i in 0..model.keyframes.len() {
+                                    ui.selectable_value(&mut selected, Some(i), label(i));
+                                }
+                            });
+                        if selected != self.selected_keyframe {
+                            if let Some(i) = selected {
+                                model.apply_keyframe(i);
+                                self.geometries = None;
+                                self.view_prop.lock().expect("Expected view prop lock to be available").
+                                    model_updated = true;
+                            }
+                            self.selected_keyframe = selected;
+                        }
+                    }
+                    egui::ScrollArea::vertical().show(ui, |ui| {
+                        let mut poses = None;
+                        for id in model.world_body.iter().chain(model.raw_sections.iter()) {
+                            Self::show_hierarchy(ui, model, &mut poses, *id);
+                        }
+                    });
+                }
             });
         egui::SidePanel::left("toolbar").max_width(40.0).
             frame(egui::Frame::default().
@@ -0,0 +1,479 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use tobj;
+use ahash;
+use crate::primitives;
+
+/* Triangles read from a mesh file, coordinates are flattened x,y,z. Normals
+& texture coordinates, when the file has them, are per vertex */
+#[derive(Debug, Clone)]
+pub struct MeshData {
+    pub vertices: Vec<f32>,
+    pub indices: Vec<u32>,
+    pub normals: Option<Vec<f32>>,
+    pub texcoords: Option<Vec<f32>>,
+    pub submeshes: Vec<SubMesh>,
+    pub materials: Vec<MeshMaterial>
+}
+
+/* A part of the mesh drawn on its own, such as an OBJ sub-object. Its
+triangles are the given range of the mesh's indices */
+#[derive(Debug, Clone)]
+pub struct SubMesh {
+    pub name: String,
+    pub indices: Range<usize>,
+    //Index into the mesh's materials
+    pub material: Option<usize>
+}
+
+/* Material from an OBJ's .mtl library */
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeshMaterial {
+    pub name: String,
+    pub diffuse: [f32;3],
+    pub dissolve: f32,
+    pub diffuse_texture: Option<String>
+}
+
+impl From<tobj::Material> for MeshMaterial {
+    fn from(material: tobj::Material) -> Self {
+        MeshMaterial {
+            name: material.name,
+            diffuse: material.diffuse.unwrap_or([0.8, 0.8, 0.8]),
+            dissolve: material.dissolve.unwrap_or(1.0),
+            diffuse_texture: material.diffuse_texture
+        }
+    }
+}
+
+/* Self contained piece of a mesh, with only the vertices its triangles use,
+ready to be drawn on its own */
+#[derive(Debug, Clone)]
+pub struct MeshPart {
+    pub vertices: Vec<f32>,
+    pub indices: Vec<u32>,
+    pub normals: Option<Vec<f32>>,
+    pub texcoords: Option<Vec<f32>>,
+    pub material: Option<MeshMaterial>
+}
+
+impl MeshData {
+    fn single(vertices: Vec<f32>, indices: Vec<u32>, normals: Option<Vec<f32>>) -> Self {
+        MeshData {
+            submeshes: vec![SubMesh { name: "".to_string(), indices: 0..indices.len(), material: None }],
+            vertices: vertices,
+            indices: indices,
+            normals: normals,
+            texcoords: None,
+            materials: Vec::new()
+        }
+    }
+}
+
+/* Names of the .mtl libraries an OBJ file refers to */
+pub fn material_libraries(data: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(data).lines().filter_map(|line| {
+        line.trim().strip_prefix("mtllib").map(|names| names.trim().to_string())
+    }).filter(|names| !names.is_empty()).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MeshFormat {
+    Obj,
+    Stl,
+    Ply
+}
+
+impl MeshFormat {
+    /* Goes by the file's extension, falling back to the contents for files
+    without a known one */
+    pub fn detect(file: &str, data: &[u8]) -> Option<Self> {
+        let extension = Path::new(file).extension().map(|e| e.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("obj") => Some(MeshFormat::Obj),
+            Some("stl") => Some(MeshFormat::Stl),
+            Some("ply") => Some(MeshFormat::Ply),
+            _ => Self::sniff(data)
+        }
+    }
+
+    fn sniff(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"ply") {
+            return Some(MeshFormat::Ply);
+        }
+        //Binary STL headers may start with "solid" too, the size gives them away
+        if is_binary_stl(data) || trim_start(data).starts_with(b"solid") {
+            return Some(MeshFormat::Stl);
+        }
+        let text = std::str::from_utf8(data).ok()?;
+        if text.lines().any(|line| line.trim_start().starts_with("v ")) {
+            Some(MeshFormat::Obj)
+        } else {
+            None
+        }
+    }
+}
+
+fn trim_start(data: &[u8]) -> &[u8] {
+    let start = data.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(data.len());
+    &data[start..]
+}
+
+/* Reads a mesh file's contents, polygons are split into triangles. OBJ 
+material libraries are looked up by name in materials, missing ones are skipped */
+pub fn parse_mesh(format: MeshFormat, data: &[u8], materials: &HashMap<String,Vec<u8>>) -> Result<MeshData,String> {
+    match format {
+        MeshFormat::Obj => parse_obj(data, materials),
+        MeshFormat::Stl => parse_stl(data),
+        MeshFormat::Ply => parse_ply(data)
+    }
+}
+
+/* OBJ sub-objects are kept as submeshes of a single mesh. Normals & texture
+coordinates are kept when any sub-object has them, others get estimated 
+normals & zero coordinates */
+fn parse_obj(data: &[u8], libraries: &HashMap<String,Vec<u8>>) -> Result<MeshData,String> {
+    let mut buff = std::io::Cursor::new(data);
+    let (models, materials) =
+        tobj::load_obj_buf(&mut buff, &tobj::LoadOptions {
+                triangulate: true,
+                //Normals & texture coordinates share the positions' indices
+                single_index: true,
+                ignore_lines: true,
+                ignore_points: true,
+                ..Default::default()
+            },
+            |path| {
+                match libraries.get(&path.to_string_lossy().to_string()) {
+                    Some(library) => tobj::load_mtl_buf(&mut std::io::Cursor::new(library)),
+                    None => Ok((Vec::new(), ahash::AHashMap::<String,usize>::new()))
+                }
+            }
+        ).map_err(|error| error.to_string())?;
+    let materials: Vec<MeshMaterial> = materials.map_or(Vec::new(), |m| m.into_iter().map(MeshMaterial::from).collect());
+    let has_normals = models.iter().any(|m| !m.mesh.normals.is_empty());
+    let has_texcoords = models.iter().any(|m| !m.mesh.texcoords.is_empty());
+    let mut mesh = MeshData::single(Vec::new(), Vec::new(), None);
+    mesh.submeshes.clear();
+    let mut normals = Vec::new();
+    let mut texcoords = Vec::new();
+    for model in models {
+        let part = model.mesh;
+        //Each sub-object indexes its own positions
+        let offset = (mesh.vertices.len() / 3) as u32;
+        let start = mesh.indices.len();
+        mesh.vertices.extend_from_slice(&part.positions);
+        mesh.indices.extend(part.indices.iter().map(|i| i + offset));
+        if part.normals.len() == part.positions.len() {
+            normals.extend_from_slice(&part.normals);
+        } else {
+            normals.extend(primitives::vertex_normals(&part.positions, &part.indices));
+        }
+        if part.texcoords.len() * 3 == part.positions.len() * 2 {
+            texcoords.extend_from_slice(&part.texcoords);
+        } else {
+            texcoords.resize(texcoords.len() + part.positions.len() / 3 * 2, 0.0);
+        }
+        mesh.submeshes.push(SubMesh {
+            name: model.name,
+            indices: start..mesh.indices.len(),
+            material: part.material_id.filter(|m| *m < materials.len())
+        });
+    }
+    mesh.normals = if has_normals { Some(normals) } else { None };
+    mesh.texcoords = if has_texcoords { Some(texcoords) } else { None };
+    mesh.materials = materials;
+    Ok(mesh)
+}
+
+//80 byte header, triangle count & 50 bytes per triangle, some exporters pad the end
+fn is_binary_stl(data: &[u8]) -> bool {
+    binary_stl_count(data).and_then(|count| count.checked_mul(50)).
+        is_some_and(|size| data.len() - 84 >= size)
+}
+
+fn binary_stl_count(data: &[u8]) -> Option<usize> {
+    data.get(80..84).map(|count| u32::from_le_bytes([count[0], count[1], count[2], count[3]]) as usize)
+}
+
+/* STL stores each triangle with its own three vertices & the facet normal,
+which is given to all three. Facets without a normal get their geometric one */
+fn parse_stl(data: &[u8]) -> Result<MeshData,String> {
+    let mut vertices = Vec::new();
+    let mut normals = Vec::new();
+    if is_binary_stl(data) {
+        let float = |at: usize| f32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        let count = binary_stl_count(data).unwrap_or(0);
+        for facet in 0..count {
+            let at = 84 + 50 * facet;
+            let normal: Vec<f32> = (0..3).map(|k| float(at + 4 * k)).collect();
+            for k in 3..12 {
+                vertices.push(float(at + 4 * k));
+            }
+            for _ in 0..3 {
+                normals.extend_from_slice(&normal);
+            }
+        }
+    } else {
+        let text = std::str::from_utf8(data).map_err(|_| "neither a binary nor an ASCII STL".to_string())?;
+        let mut tokens = text.split_ascii_whitespace();
+        let mut normal = [0.0; 3];
+        let mut facet_vertices = 0;
+        while let Some(token) = tokens.next() {
+            let mut read = |what: &str| -> Result<[f32;3],String> {
+                let mut values = [0.0; 3];
+                for value in values.iter_mut() {
+                    *value = tokens.next().and_then(|t| t.parse().ok()).
+                        ok_or(format!("expected 3 numbers after {}", what))?;
+                }
+                Ok(values)
+            };
+            match token {
+                "normal" => {
+                    normal = read("normal")?;
+                    facet_vertices = 0;
+                },
+                "vertex" => {
+                    vertices.extend_from_slice(&read("vertex")?);
+                    normals.extend_from_slice(&normal);
+                    facet_vertices += 1;
+                },
+                "endfacet" if facet_vertices != 3 => {
+                    return Err(format!("facet with {} vertices, expected 3", facet_vertices));
+                },
+                _ => ()
+            }
+        }
+    }
+    if vertices.is_empty() {
+        return Err("no triangles found".to_string());
+    }
+    let indices: Vec<u32> = (0..(vertices.len() / 3) as u32).collect();
+    fill_missing_normals(&vertices, &mut normals);
+    Ok(MeshData::single(vertices, indices, Some(normals)))
+}
+
+//Facets of unindexed triangles whose normal is zero take the triangle's own
+fn fill_missing_normals(vertices: &[f32], normals: &mut [f32]) {
+    for (triangle, normal) in vertices.chunks_exact(9).zip(normals.chunks_exact_mut(9)) {
+        if normal.iter().any(|n| *n != 0.0) {
+            continue;
+        }
+        let u = [triangle[3] - triangle[0], triangle[4] - triangle[1], triangle[5] - triangle[2]];
+        let v = [triangle[6] - triangle[0], triangle[7] - triangle[1], triangle[8] - triangle[2]];
+        let n = [u[1] * v[2] - u[2] * v[1], u[2] * v[0] - u[0] * v[2], u[0] * v[1] - u[1] * v[0]];
+        let length = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+        if length > 0.0 {
+            for k in 0..9 {
+                normal[k] = n[k % 3] / length;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlyEncoding {
+    Ascii,
+    LittleEndian,
+    BigEndian
+}
+
+#[derive(Debug, Clone)]
+enum PlyProperty {
+    Scalar(String, String),
+    //Name, type of the item count & type of the items
+    List(String, String, String)
+}
+
+#[derive(Debug, Clone)]
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>
+}
+
+/* Reads PLY values one at a time, from whitespace separated text or binary */
+struct PlyReader<'a> {
+    encoding: PlyEncoding,
+    body: &'a [u8],
+    position: usize,
+    tokens: std::str::SplitAsciiWhitespace<'a>
+}
+
+impl<'a> PlyReader<'a> {
+    fn read(&mut self, ty: &str) -> Result<f64,String> {
+        if self.encoding == PlyEncoding::Ascii {
+            return self.tokens.next().and_then(|t| t.parse::<f64>().ok()).
+                ok_or("ran out of values".to_string());
+        }
+        let size = match ty {
+            "char" | "int8" | "uchar" | "uint8" => 1,
+            "short" | "int16" | "ushort" | "uint16" => 2,
+            "int" | "int32" | "uint" | "uint32" | "float" | "float32" => 4,
+            "double" | "float64" => 8,
+            _ => return Err(format!("unknown property type {}", ty))
+        };
+        let bytes = self.body.get(self.position..self.position + size).ok_or("ran out of data".to_string())?;
+        self.position += size;
+        let mut buffer = [0u8; 8];
+        buffer[..size].copy_from_slice(bytes);
+        if self.encoding == PlyEncoding::BigEndian {
+            buffer[..size].reverse();
+        }
+        let b = buffer;
+        Ok(match ty {
+            "char" | "int8" => b[0] as i8 as f64,
+            "uchar" | "uint8" => b[0] as f64,
+            "short" | "int16" => i16::from_le_bytes([b[0], b[1]]) as f64,
+            "ushort" | "uint16" => u16::from_le_bytes([b[0], b[1]]) as f64,
+            "int" | "int32" => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            "uint" | "uint32" => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            "float" | "float32" => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            _ => f64::from_le_bytes(b)
+        })
+    }
+}
+
+/* PLY in any of its encodings. Vertices take x, y, z & optionally nx, ny, nz;
+faces are read from the vertex_indices (or vertex_index) list. Other elements
+& properties are skipped */
+fn parse_ply(data: &[u8]) -> Result<MeshData,String> {
+    let header_end = data.windows(10).position(|w| w == b"end_header").
+        ok_or("missing end_header".to_string())?;
+    let header = std::str::from_utf8(&data[..header_end]).map_err(|_| "header isn't text".to_string())?;
+    //The body starts after the end_header line
+    let body_start = data[header_end..].iter().position(|b| *b == b'\n').map_or(data.len(), |p| header_end + p + 1);
+    let mut encoding = None;
+    let mut elements: Vec<PlyElement> = Vec::new();
+    for line in header.lines() {
+        let words: Vec<&str> = line.split_ascii_whitespace().collect();
+        match words.as_slice() {
+            ["format", format, ..] => {
+                encoding = Some(match *format {
+                    "ascii" => PlyEncoding::Ascii,
+                    "binary_little_endian" => PlyEncoding::LittleEndian,
+                    "binary_big_endian" => PlyEncoding::BigEndian,
+                    _ => return Err(format!("unknown format {}", format))
+                });
+            },
+            ["element", name, count] => {
+                elements.push(PlyElement {
+                    name: name.to_string(),
+                    count: count.parse().map_err(|_| format!("bad {} count", name))?,
+                    properties: Vec::new()
+                });
+            },
+            ["property", "list", count_ty, item_ty, name] => {
+                elements.last_mut().ok_or("property before any element".to_string())?.properties.
+                    push(PlyProperty::List(name.to_string(), count_ty.to_string(), item_ty.to_string()));
+            },
+            ["property", ty, name] => {
+                elements.last_mut().ok_or("property before any element".to_string())?.properties.
+                    push(PlyProperty::Scalar(name.to_string(), ty.to_string()));
+            },
+            _ => ()
+        }
+    }
+    let encoding = encoding.ok_or("missing format".to_string())?;
+    let body = &data[body_start.min(data.len())..];
+    let mut reader = PlyReader {
+        encoding: encoding,
+        body: body,
+        position: 0,
+        tokens: if encoding == PlyEncoding::Ascii {
+            std::str::from_utf8(body).map_err(|_| "ASCII body isn't text".to_string())?.split_ascii_whitespace()
+        } else {
+            "".split_ascii_whitespace()
+        }
+    };
+    let mut vertices = Vec::new();
+    let mut normals = Vec::new();
+    let mut has_normals = false;
+    let mut indices = Vec::new();
+    for element in &elements {
+        let scalar_names: Vec<&str> = element.properties.iter().filter_map(|p| match p {
+            PlyProperty::Scalar(name, _) => Some(name.as_str()),
+            _ => None
+        }).collect();
+        if element.name == "vertex" {
+            has_normals = ["nx", "ny", "nz"].iter().all(|n| scalar_names.contains(n));
+        }
+        for _ in 0..element.count {
+            let mut position = [0.0f32; 3];
+            let mut normal = [0.0f32; 3];
+            for property in &element.properties {
+                match property {
+                    PlyProperty::Scalar(name, ty) => {
+                        let value = reader.read(ty)? as f32;
+                        match name.as_str() {
+                            "x" => position[0] = value,
+                            "y" => position[1] = value,
+                            "z" => position[2] = value,
+                            "nx" => normal[0] = value,
+                            "ny" => normal[1] = value,
+                            "nz" => normal[2] = value,
+                            _ => ()
+                        }
+                    },
+                    PlyProperty::List(name, count_ty, item_ty) => {
+                        let count = reader.read(count_ty)? as usize;
+                        //The count comes from the file, so it isn't trusted to size the list up front
+                        let mut items = Vec::new();
+                        for _ in 0..count {
+                            items.push(reader.read(item_ty)? as u32);
+                        }
+                        if element.name == "face" && (name == "vertex_indices" || name == "vertex_index") {
+                            //Polygons are split into a fan of triangles
+                            for k in 1..count.saturating_sub(1) {
+                                indices.extend_from_slice(&[items[0], items[k], items[k + 1]]);
+                            }
+                        }
+                    }
+                }
+            }
+            if element.name == "vertex" {
+                vertices.extend_from_slice(&position);
+                normals.extend_from_slice(&normal);
+            }
+        }
+    }
+    let vertex_count = (vertices.len() / 3) as u32;
+    if let Some(index) = indices.iter().find(|i| **i >= vertex_count) {
+        return Err(format!("face refers to vertex {} of {}", index, vertex_count));
+    }
+    if indices.is_empty() {
+        return Err("no faces found".to_string());
+    }
+    Ok(MeshData::single(vertices, indices, if has_normals { Some(normals) } else { None }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_stls_may_have_trailing_bytes() {
+        let mut data = vec![0u8; 80];
+        data.extend_from_slice(&1u32.to_le_bytes());
+        for value in [0.0f32, 0.0, 1.0,  0.0, 0.0, 0.0,  1.0, 0.0, 0.0,  0.0, 1.0, 0.0] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(b"padding");
+        assert!(MeshFormat::detect("part", &data) == Some(MeshFormat::Stl));
+        let mesh = parse_mesh(MeshFormat::Stl, &data, &HashMap::new()).expect("Expected the STL to parse");
+        assert_eq!(mesh.vertices, vec![0.0, 0.0, 0.0,  1.0, 0.0, 0.0,  0.0, 1.0, 0.0]);
+        //Files too short for their triangle count aren't binary STLs
+        assert!(!is_binary_stl(&data[..120]));
+    }
+
+    #[test]
+    fn ply_list_counts_past_the_data_are_errors() {
+        let mut data = b"ply\nformat binary_little_endian 1.0\nelement vertex 0\n\
+            element face 1\nproperty list uint uint vertex_indices\nend_header\n".to_vec();
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(parse_mesh(MeshFormat::Ply, &data, &HashMap::new()).err(), Some("ran out of data".to_string()));
+    }
+}
@@ -0,0 +1,449 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::f32::consts::PI;
+use nalgebra::{Matrix4, Vector3, Vector4};
+use crate::mesh_io::MeshPart;
+
+//Boundary edges are held in place by planes this many times heavier than the faces'
+const BOUNDARY_WEIGHT: f64 = 1000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormalMode {
+    Flat,
+    Smooth,
+    //Faces meeting at more than this angle, in radians, get separate normals
+    Crease(f32)
+}
+
+impl NormalMode {
+    fn crease_angle(&self) -> f32 {
+        match self {
+            NormalMode::Flat => 0.0,
+            NormalMode::Smooth => PI,
+            NormalMode::Crease(angle) => *angle
+        }
+    }
+}
+
+/* Processing applied to a mesh, each step is skipped when unset. Steps run
+in the order of the fields */
+#[derive(Debug, Clone, Default)]
+pub struct MeshProcessing {
+    //Vertices closer than this are merged
+    pub weld_tolerance: Option<f32>,
+    pub remove_degenerate: bool,
+    //Meshes with more triangles are decimated down to about this many
+    pub target_triangles: Option<usize>,
+    pub normals: Option<NormalMode>
+}
+
+impl MeshProcessing {
+    pub fn is_noop(&self) -> bool {
+        self.weld_tolerance.is_none() && !self.remove_degenerate &&
+            self.target_triangles.is_none() && self.normals.is_none()
+    }
+
+    pub fn apply(&self, part: &MeshPart) -> MeshPart {
+        let mut part = part.clone();
+        if let Some(tolerance) = self.weld_tolerance {
+            part = weld_vertices(&part, tolerance);
+        }
+        if self.remove_degenerate {
+            part = remove_degenerate(&part);
+        }
+        if let Some(target) = self.target_triangles {
+            part = decimate(&part, target);
+        }
+        if let Some(mode) = self.normals {
+            part = compute_normals(&part, mode);
+        }
+        part
+    }
+}
+
+fn position(vertices: &[f32], i: usize) -> Vector3<f32> {
+    Vector3::new(vertices[3 * i], vertices[3 * i + 1], vertices[3 * i + 2])
+}
+
+fn triangle_count(part: &MeshPart) -> usize {
+    part.indices.len() / 3
+}
+
+/* Part with no geometry, keeping the attributes & material of part */
+fn empty_like(part: &MeshPart) -> MeshPart {
+    MeshPart {
+        vertices: Vec::new(),
+        indices: Vec::new(),
+        normals: part.normals.as_ref().map(|_| Vec::new()),
+        texcoords: part.texcoords.as_ref().map(|_| Vec::new()),
+        material: part.material.clone()
+    }
+}
+
+/* Copies vertex i of part to the end of out, returning its new index */
+fn push_vertex(out: &mut MeshPart, part: &MeshPart, i: usize) -> u32 {
+    out.vertices.extend_from_slice(&part.vertices[3 * i..3 * i + 3]);
+    if let (Some(to), Some(from)) = (out.normals.as_mut(), part.normals.as_ref()) {
+        to.extend_from_slice(&from[3 * i..3 * i + 3]);
+    }
+    if let (Some(to), Some(from)) = (out.texcoords.as_mut(), part.texcoords.as_ref()) {
+        to.extend_from_slice(&from[2 * i..2 * i + 2]);
+    }
+    (out.vertices.len() / 3 - 1) as u32
+}
+
+/* Keeps the given triangles of part, dropping the vertices none of them use */
+fn compact(part: &MeshPart, indices: &[u32]) -> MeshPart {
+    let mut out = empty_like(part);
+    let mut remap: HashMap<u32,u32> = HashMap::new();
+    let new_indices: Vec<u32> = indices.iter().map(|i| {
+        *remap.entry(*i).or_insert_with(|| push_vertex(&mut out, part, *i as usize))
+    }).collect();
+    out.indices = new_indices;
+    out
+}
+
+/* Merges vertices whose positions are within tolerance of each other, going
+by a grid of that size so close vertices straddling a cell may stay apart.
+Vertices with different texture coordinates are kept apart so seams survive,
+a merged vertex keeps the first one's normal */
+pub fn weld_vertices(part: &MeshPart, tolerance: f32) -> MeshPart {
+    //Adding 0 turns -0 into 0, so both weld when matching exactly
+    let quantize = |value: f32| -> i64 {
+        if tolerance > 0.0 {
+            (value / tolerance).round() as i64
+        } else {
+            (value + 0.0).to_bits() as i64
+        }
+    };
+    let mut welded = empty_like(part);
+    let mut keys: HashMap<Vec<i64>,u32> = HashMap::new();
+    let mut remap = Vec::with_capacity(part.vertices.len() / 3);
+    for i in 0..part.vertices.len() / 3 {
+        let mut key: Vec<i64> = part.vertices[3 * i..3 * i + 3].iter().map(|v| quantize(*v)).collect();
+        if let Some(texcoords) = &part.texcoords {
+            key.extend(texcoords[2 * i..2 * i + 2].iter().map(|v| quantize(*v)));
+        }
+        remap.push(*keys.entry(key).or_insert_with(|| push_vertex(&mut welded, part, i)));
+    }
+    welded.indices = part.indices.iter().map(|i| remap[*i as usize]).collect();
+    welded
+}
+
+/* Drops triangles that repeat a vertex or have no area to speak of, along
+with the vertices left unused */
+pub fn remove_degenerate(part: &MeshPart) -> MeshPart {
+    let indices: Vec<u32> = part.indices.chunks_exact(3).filter(|triangle| {
+        if triangle[0] == triangle[1] || triangle[1] == triangle[2] || triangle[0] == triangle[2] {
+            return false;
+        }
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(|i| position(&part.vertices, i as usize));
+        let longest = (b - a).norm_squared().max((c - b).norm_squared()).max((a - c).norm_squared());
+        (b - a).cross(&(c - a)).norm() > f32::EPSILON * longest
+    }).flatten().copied().collect();
+    compact(part, &indices)
+}
+
+/* Area weighted normals, averaged at each vertex over the faces within the
+crease angle of the face being drawn. Vertices are split wherever their
+faces end up with different normals, so weld the mesh first for smooth
+shading across duplicated vertices */
+pub fn compute_normals(part: &MeshPart, mode: NormalMode) -> MeshPart {
+    //Slack so coplanar faces still count as within a crease angle of 0
+    let min_cos = mode.crease_angle().cos() - 1e-4;
+    let faces: Vec<Vector3<f32>> = part.indices.chunks_exact(3).map(|triangle| {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(|i| position(&part.vertices, i as usize));
+        (b - a).cross(&(c - a))
+    }).collect();
+    let units: Vec<Option<Vector3<f32>>> = faces.iter().map(|f| f.try_normalize(0.0)).collect();
+    let mut incident: Vec<Vec<usize>> = vec![Vec::new(); part.vertices.len() / 3];
+    for (t, triangle) in part.indices.chunks_exact(3).enumerate() {
+        for i in triangle {
+            incident[*i as usize].push(t);
+        }
+    }
+    let mut out = MeshPart { normals: None, ..empty_like(part) };
+    let mut normals = Vec::new();
+    let mut corners: HashMap<(u32,[u32;3]),u32> = HashMap::new();
+    for (t, triangle) in part.indices.chunks_exact(3).enumerate() {
+        for i in triangle {
+            //Faces without a normal of their own take all of their neighbours'
+            let normal = incident[*i as usize].iter().filter(|u| {
+                match (units[t], units[**u]) {
+                    (Some(own), Some(other)) => own.dot(&other) >= min_cos,
+                    (None, _) => true,
+                    (Some(_), None) => false
+                }
+            }).fold(Vector3::<f32>::zeros(), |sum, u| sum + faces[*u]);
+            let normal = normal.try_normalize(f32::EPSILON).unwrap_or(Vector3::z());
+            let key = (*i, [normal.x, normal.y, normal.z].map(f32::to_bits));
+            let index = *corners.entry(key).or_insert_with(|| {
+                normals.extend_from_slice(&[normal.x, normal.y, normal.z]);
+                push_vertex(&mut out, part, *i as usize)
+            });
+            out.indices.push(index);
+        }
+    }
+    out.normals = Some(normals);
+    out
+}
+
+/* Edge collapse waiting in the queue, ordered so the cheapest pops first.
+It's stale once either vertex has changed since it was queued */
+struct Collapse {
+    cost: f64,
+    u: usize,
+    v: usize,
+    versions: (u32,u32),
+    target: Vector3<f64>
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost.total_cmp(&other.cost) == Ordering::Equal
+    }
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Collapse {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+fn plane_quadric(normal: Vector3<f64>, point: Vector3<f64>, weight: f64) -> Matrix4<f64> {
+    let plane = Vector4::new(normal.x, normal.y, normal.z, -normal.dot(&point));
+    plane * plane.transpose() * weight
+}
+
+fn quadric_cost(quadric: &Matrix4<f64>, point: &Vector3<f64>) -> f64 {
+    let p = Vector4::new(point.x, point.y, point.z, 1.0);
+    (p.transpose() * quadric * p)[0]
+}
+
+/* Where collapsing into a vertex costs the least under the quadric, falling
+back to the best of the edge's ends & middle when it isn't well defined */
+fn best_collapse(quadric: &Matrix4<f64>, a: Vector3<f64>, b: Vector3<f64>) -> (f64,Vector3<f64>) {
+    let system = quadric.fixed_view::<3,3>(0, 0).into_owned();
+    let optimum = system.try_inverse().filter(|_| system.determinant().abs() > 1e-12).
+        map(|inverse| -(inverse * quadric.fixed_view::<3,1>(0, 3)));
+    optimum.into_iter().chain([a, b, (a + b) / 2.0]).map(|point| {
+        (quadric_cost(quadric, &point), point)
+    }).min_by(|x, y| x.0.total_cmp(&y.0)).expect("Expected collapse candidates")
+}
+
+/* Quadric error edge collapse (Garland & Heckbert) down to about target
+triangles. Boundary edges are weighted to keep open meshes' outlines, and
+collapses that would flip a face are skipped, so meshes may stay above the
+target. Collapsed vertices keep the texture coordinates of the vertex they
+merge into; normals no longer match the moved vertices & are dropped */
+pub fn decimate(part: &MeshPart, target: usize) -> MeshPart {
+    if triangle_count(part) <= target {
+        return part.clone();
+    }
+    let num_vertices = part.vertices.len() / 3;
+    let mut positions: Vec<Vector3<f64>> = (0..num_vertices).map(|i| {
+        position(&part.vertices, i).map(|x| x as f64)
+    }).collect();
+    let mut triangles: Vec<[usize;3]> = part.indices.chunks_exact(3).map(|t| {
+        [t[0] as usize, t[1] as usize, t[2] as usize]
+    }).collect();
+    let mut alive = vec![true; triangles.len()];
+    let mut vertex_triangles: Vec<Vec<usize>> = vec![Vec::new(); num_vertices];
+    let mut quadrics = vec![Matrix4::zeros(); num_vertices];
+    let mut edge_faces: HashMap<(usize,usize),Vec<usize>> = HashMap::new();
+    for (t, triangle) in triangles.iter().enumerate() {
+        let [a, b, c] = triangle.map(|i| positions[i]);
+        let cross = (b - a).cross(&(c - a));
+        let area = cross.norm() / 2.0;
+        if let Some(normal) = cross.try_normalize(0.0) {
+            let quadric = plane_quadric(normal, a, area);
+            triangle.iter().for_each(|i| quadrics[*i] += quadric);
+        }
+        for k in 0..3 {
+            vertex_triangles[triangle[k]].push(t);
+            let (u, v) = (triangle[k], triangle[(k + 1) % 3]);
+            edge_faces.entry((u.min(v), u.max(v))).or_default().push(t);
+        }
+    }
+    for ((u, v), faces) in &edge_faces {
+        if faces.len() != 1 {
+            continue;
+        }
+        let [a, b, c] = triangles[faces[0]].map(|i| positions[i]);
+        let edge = positions[*v] - positions[*u];
+        if let Some(normal) = edge.cross(&(b - a).cross(&(c - a))).try_normalize(0.0) {
+            let quadric = plane_quadric(normal, positions[*u], BOUNDARY_WEIGHT * edge.norm_squared());
+            quadrics[*u] += quadric;
+            quadrics[*v] += quadric;
+        }
+    }
+    let mut versions = vec![0u32; num_vertices];
+    let mut removed = vec![false; num_vertices];
+    let mut queue = BinaryHeap::new();
+    let queue_edge = |queue: &mut BinaryHeap<Collapse>, positions: &[Vector3<f64>],
+            quadrics: &[Matrix4<f64>], versions: &[u32], u: usize, v: usize| {
+        let (cost, target) = best_collapse(&(quadrics[u] + quadrics[v]), positions[u], positions[v]);
+        queue.push(Collapse { cost: cost, u: u, v: v, versions: (versions[u], versions[v]), target: target });
+    };
+    for (u, v) in edge_faces.keys() {
+        queue_edge(&mut queue, &positions, &quadrics, &versions, *u, *v);
+    }
+    //A face flips when moving from to target turns its normal around
+    let flips = |positions: &[Vector3<f64>], triangles: &[[usize;3]], faces: &[usize],
+            from: usize, other: usize, target: Vector3<f64>| {
+        faces.iter().filter(|t| !triangles[**t].contains(&other)).any(|t| {
+            let corners = triangles[*t].map(|i| positions[i]);
+            let moved = triangles[*t].map(|i| if i == from { target } else { positions[i] });
+            let before = (corners[1] - corners[0]).cross(&(corners[2] - corners[0]));
+            let after = (moved[1] - moved[0]).cross(&(moved[2] - moved[0]));
+            before.dot(&after) <= 0.0
+        })
+    };
+    let mut remaining = triangles.len();
+    while remaining > target {
+        let Some(collapse) = queue.pop() else {
+            break;
+        };
+        let (u, v) = (collapse.u, collapse.v);
+        if removed[u] || removed[v] || (versions[u], versions[v]) != collapse.versions {
+            continue;
+        }
+        vertex_triangles[u].retain(|t| alive[*t]);
+        vertex_triangles[v].retain(|t| alive[*t]);
+        if flips(&positions, &triangles, &vertex_triangles[u], u, v, collapse.target) ||
+                flips(&positions, &triangles, &vertex_triangles[v], v, u, collapse.target) {
+            continue;
+        }
+        //v merges into u
+        positions[u] = collapse.target;
+        let merged = quadrics[v];
+        quadrics[u] += merged;
+        removed[v] = true;
+        for t in std::mem::take(&mut vertex_triangles[v]) {
+            if triangles[t].contains(&u) {
+                alive[t] = false;
+                remaining -= 1;
+            } else {
+                triangles[t].iter_mut().filter(|i| **i == v).for_each(|i| *i = u);
+                vertex_triangles[u].push(t);
+            }
+        }
+        vertex_triangles[u].retain(|t| alive[*t]);
+        versions[u] += 1;
+        let neighbours: HashSet<usize> = vertex_triangles[u].iter().flat_map(|t| triangles[*t]).
+            filter(|i| *i != u).collect();
+        for w in neighbours {
+            queue_edge(&mut queue, &positions, &quadrics, &versions, u, w);
+        }
+    }
+    let mut moved = MeshPart { normals: None, ..part.clone() };
+    moved.vertices = positions.iter().flat_map(|p| [p.x as f32, p.y as f32, p.z as f32]).collect();
+    let indices: Vec<u32> = triangles.iter().zip(alive.iter()).filter(|(_, alive)| **alive).
+        flat_map(|(triangle, _)| triangle.map(|i| i as u32)).collect();
+    compact(&moved, &indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(vertices: Vec<f32>, indices: Vec<u32>) -> MeshPart {
+        MeshPart {
+            vertices: vertices,
+            indices: indices,
+            normals: None,
+            texcoords: None,
+            material: None
+        }
+    }
+
+    /* Flat n by n grid of unit squares, two triangles each */
+    fn grid(n: usize) -> MeshPart {
+        let mut vertices = Vec::new();
+        for y in 0..=n {
+            for x in 0..=n {
+                vertices.extend_from_slice(&[x as f32, y as f32, 0.0]);
+            }
+        }
+        let mut indices = Vec::new();
+        for y in 0..n {
+            for x in 0..n {
+                let i = (y * (n + 1) + x) as u32;
+                let row = (n + 1) as u32;
+                indices.extend_from_slice(&[i, i + 1, i + row + 1, i, i + row + 1, i + row]);
+            }
+        }
+        part(vertices, indices)
+    }
+
+    #[test]
+    fn welding_merges_close_vertices() {
+        //Two triangles sharing an edge, each with its own copy of the edge's vertices
+        let square = part(vec![
+            0.0, 0.0, 0.0,  1.0, 0.0, 0.0,  1.0, 1.0, 0.0,
+            0.0, 0.0, 0.0,  1.0, 1.00005, 0.0,  0.0, 1.0, 0.0
+        ], vec![0, 1, 2, 3, 4, 5]);
+        let welded = weld_vertices(&square, 1e-3);
+        assert_eq!(welded.vertices.len() / 3, 4);
+        assert_eq!(welded.indices, vec![0, 1, 2, 0, 2, 3]);
+        //Only exact matches merge without a tolerance
+        assert_eq!(weld_vertices(&square, 0.0).vertices.len() / 3, 5);
+    }
+
+    #[test]
+    fn degenerate_triangles_are_removed() {
+        let mesh = part(vec![
+            0.0, 0.0, 0.0,  1.0, 0.0, 0.0,  0.0, 1.0, 0.0,
+            2.0, 0.0, 0.0,  5.0, 5.0, 5.0
+        ], vec![0, 1, 2,  0, 0, 1,  0, 1, 3,  4, 4, 4]);
+        let cleaned = remove_degenerate(&mesh);
+        assert_eq!(cleaned.indices, vec![0, 1, 2]);
+        //Vertices only used by the removed triangles go with them
+        assert_eq!(cleaned.vertices, vec![0.0, 0.0, 0.0,  1.0, 0.0, 0.0,  0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn decimation_reaches_the_target() {
+        let mesh = grid(16);
+        assert_eq!(triangle_count(&mesh), 512);
+        for target in [256, 100, 32] {
+            let decimated = decimate(&mesh, target);
+            //A collapse removes one triangle on the boundary & two inside
+            let count = triangle_count(&decimated);
+            assert!(count <= target && count + 2 >= target, "{} triangles for a target of {}", count, target);
+            assert!(decimated.vertices.chunks_exact(3).all(|v| v[2].abs() < 1e-4), "The grid should stay flat");
+        }
+        assert_eq!(triangle_count(&decimate(&mesh, 1000)), 512);
+    }
+
+    #[test]
+    fn crease_angles_split_normals() {
+        //Two squares meeting along the x axis, the second folded up by angle
+        let folded = |angle: f32| part(vec![
+            0.0, 0.0, 0.0,  1.0, 0.0, 0.0,  1.0, -1.0, 0.0,  0.0, -1.0, 0.0,
+            1.0, angle.cos(), angle.sin(),  0.0, angle.cos(), angle.sin()
+        ], vec![0, 3, 2, 0, 2, 1,  0, 1, 4, 0, 4, 5]);
+        let vertex_count = |angle: f32, mode: NormalMode| compute_normals(&folded(angle), mode).vertices.len() / 3;
+        let crease = NormalMode::Crease(PI / 4.0);
+        //The edge's vertices are split where the fold is sharper than the crease angle
+        assert_eq!(vertex_count(PI / 2.0, crease), 8);
+        assert_eq!(vertex_count(PI / 18.0, crease), 6);
+        assert_eq!(vertex_count(PI / 2.0, NormalMode::Smooth), 6);
+        assert_eq!(vertex_count(PI / 18.0, NormalMode::Flat), 8);
+
+        let split = compute_normals(&folded(PI / 2.0), crease);
+        let normals = split.normals.expect("Expected normals");
+        for normal in normals.chunks_exact(3) {
+            let normal = Vector3::new(normal[0], normal[1], normal[2]);
+            assert!((normal - Vector3::z()).norm() < 1e-5 || (normal + Vector3::y()).norm() < 1e-5, "{:?}", normal);
+        }
+    }
+}
@@ -1,10 +1,9 @@
-use quick_xml::events::Event;
+use quick_xml::events::{Event,BytesStart};
 use quick_xml::reader::Reader;
 use std::fs;
-use std::str::FromStr;
+use std::fmt;
+use std::path::{Path,PathBuf};
 use image::ImageReader;
-use std::rc::Rc;
-use std::cell::RefCell;
 use crate::node::Node;
 use crate::node::NodeType;
 use crate::node::WorldBody;
@@ -16,7 +15,21 @@ use crate::node::Geom;
 use crate::node::Joint;
 use crate::node::Texture;
 use crate::node::Material;
+use crate::node::Compiler;
+use crate::node::RawElement;
+use crate::node::Actuator;
+use crate::node::Inertial;
+use crate::node::Hfield;
+use crate::node::AttributeError;
+use crate::mesh_io;
+use crate::mesh_ops::MeshProcessing;
+use crate::query;
+use crate::writer;
+use crate::keyframe::Keyframe;
+use crate::scene::{NodeId, SceneGraph};
+use crate::visitor::{NodeVisitorMut, VisitControl, walk_mut, depth_first};
 use std::collections::HashMap;
+use nalgebra::Isometry3;
 use crate::physics_mj::mjModel;
 
 #[derive(Clone)]
@@ -24,314 +37,1276 @@ pub enum FileType {
     Image(u32,u32)
 }
 
+/* Position of an element in the model source, as reported in load errors */
+#[derive(Debug, Clone)]
+pub struct SourceLocation {
+    pub file: PathBuf,
+    pub offset: u64,
+    pub line: usize,
+    pub column: usize
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{} (byte {})", self.file.display(), self.line, self.column, self.offset)
+    }
+}
+
+/* Maps reader byte offsets to line & column numbers. Offsets mostly move 
+forward, so lines are counted incrementally instead of rescanning the file */
+struct LineTracker<'a> {
+    file: PathBuf,
+    contents: &'a str,
+    offset: usize,
+    line: usize,
+    line_start: usize
+}
+
+impl<'a> LineTracker<'a> {
+    fn new(file: PathBuf, contents: &'a str) -> Self {
+        LineTracker {
+            file: file,
+            contents: contents,
+            offset: 0,
+            line: 1,
+            line_start: 0
+        }
+    }
+
+    fn locate(&mut self, offset: u64) -> SourceLocation {
+        let offset_c = (offset as usize).min(self.contents.len());
+        if offset_c < self.offset {
+            self.offset = 0;
+            self.line = 1;
+            self.line_start = 0;
+        }
+        let bytes = self.contents.as_bytes();
+        for i in self.offset..offset_c {
+            if bytes[i] == b'\n' {
+                self.line += 1;
+                self.line_start = i + 1;
+            }
+        }
+        self.offset = offset_c;
+        SourceLocation {
+            file: self.file.clone(),
+            offset: offset,
+            line: self.line,
+            column: offset_c - self.line_start + 1
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ModelLoadError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error
+    },
+    XmlSyntax {
+        location: SourceLocation,
+        message: String
+    },
+    Nesting {
+        location: SourceLocation,
+        message: String
+    },
+    BadAttribute {
+        location: SourceLocation,
+        element: String,
+        error: AttributeError
+    },
+    MissingAsset {
+        kind: String,
+        name: String,
+        path: Option<PathBuf>
+    },
+    InvalidAsset {
+        path: PathBuf,
+        message: String
+    },
+    IncludeCycle {
+        location: SourceLocation,
+        chain: Vec<PathBuf>
+    },
+    DuplicateInclude {
+        location: SourceLocation,
+        path: PathBuf,
+        first: SourceLocation
+    },
+    DuplicateAsset {
+        location: SourceLocation,
+        kind: String,
+        name: String,
+        first: SourceLocation
+    },
+    DuplicateName {
+        location: SourceLocation,
+        kind: String,
+        name: String,
+        first: SourceLocation
+    },
+    //An element names another one that doesn't exist, e.g. an actuator's joint
+    UnknownReference {
+        element: String,
+        kind: String,
+        name: String
+    }
+}
+
+impl fmt::Display for ModelLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModelLoadError::Io { path, error } => {
+                write!(f, "Couldn't read {}: {}", path.display(), error)
+            },
+            ModelLoadError::XmlSyntax { location, message } => {
+                write!(f, "XML error at {}: {}", location, message)
+            },
+            ModelLoadError::Nesting { location, message } => {
+                write!(f, "Invalid structure at {}: {}", location, message)
+            },
+            ModelLoadError::BadAttribute { location, element, error } => {
+                write!(f, "Bad value \"{}\" for {} attribute '{}' at {}: {}",
+                    error.value, element, error.key, location, error.reason)
+            },
+            ModelLoadError::MissingAsset { kind, name, path } => {
+                match path {
+                    Some(p) => write!(f, "Missing {} asset '{}' (looked for {})", kind, name, p.display()),
+                    None => write!(f, "Missing {} asset '{}'", kind, name)
+                }
+            },
+            ModelLoadError::InvalidAsset { path, message } => {
+                write!(f, "Couldn't load asset {}: {}", path.display(), message)
+            },
+            ModelLoadError::IncludeCycle { location, chain } => {
+                let chain_s: Vec<String> = chain.iter().map(|p| p.display().to_string()).collect();
+                write!(f, "Include cycle at {}: {}", location, chain_s.join(" -> "))
+            },
+            ModelLoadError::DuplicateInclude { location, path, first } => {
+                write!(f, "{} is included again at {}, first included at {}", path.display(), location, first)
+            },
+            ModelLoadError::DuplicateAsset { location, kind, name, first } => {
+                write!(f, "Duplicate {} asset '{}' at {}, first defined at {}", kind, name, location, first)
+            },
+            ModelLoadError::DuplicateName { location, kind, name, first } => {
+                write!(f, "Duplicate {} name '{}' at {}, first defined at {}", kind, name, location, first)
+            },
+            ModelLoadError::UnknownReference { element, kind, name } => {
+                write!(f, "{} refers to unknown {} '{}'", element, kind, name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ModelLoadError {}
+
+/* Steps reported while a model loads, e.g. to show progress in the UI */
+#[derive(Debug, Clone)]
+pub enum LoadProgress {
+    Parsing(PathBuf),
+    ResolvingElements,
+    LoadingTexture { file: String, index: usize, count: usize },
+    LoadingMesh { file: String, index: usize, count: usize },
+    //An OBJ's material library that couldn't be read, the mesh is drawn without it
+    SkippedMaterialLibrary(PathBuf),
+    LoadingHfield { file: String, index: usize, count: usize },
+    ParsingMeshes { count: usize },
+    BuildingHull { name: String, index: usize, count: usize },
+    ProcessingMeshes { count: usize }
+}
+
+impl fmt::Display for LoadProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadProgress::Parsing(path) => {
+                write!(f, "Parsing {}", path.display())
+            },
+            LoadProgress::ResolvingElements => {
+                write!(f, "Applying defaults & linking assets")
+            },
+            LoadProgress::LoadingTexture { file, index, count } => {
+                write!(f, "Decoding texture {} ({}/{})", file, index, count)
+            },
+            LoadProgress::LoadingMesh { file, index, count } => {
+                write!(f, "Reading mesh {} ({}/{})", file, index, count)
+            },
+            LoadProgress::SkippedMaterialLibrary(path) => {
+                write!(f, "Skipping missing material library {}", path.display())
+            },
+            LoadProgress::LoadingHfield { file, index, count } => {
+                write!(f, "Decoding height field {} ({}/{})", file, index, count)
+            },
+            LoadProgress::ParsingMeshes { count } => {
+                write!(f, "Parsing {} mesh files", count)
+            },
+            LoadProgress::BuildingHull { name, index, count } => {
+                write!(f, "Building inline mesh {} ({}/{})", name, index, count)
+            },
+            LoadProgress::ProcessingMeshes { count } => {
+                write!(f, "Processing {} meshes", count)
+            }
+        }
+    }
+}
+
+/* Nodes collected while parsing a model & all of its included files */
+struct ParseState {
+    graph: SceneGraph,
+    wbody: Option<NodeId>,
+    defaults: Option<NodeId>,
+    assets: Option<NodeId>,
+    compiler: Option<NodeId>,
+    //Top level elements that aren't interpreted (e.g. <option>, <actuator>), in document order
+    raw_sections: Vec<NodeId>,
+    //Every top level section, in the order each first appears
+    sections: Vec<NodeId>,
+    //Attributes of the model's <mujoco> root, e.g. its name
+    attributes: HashMap<String,String>,
+    p_stack: Vec<NodeId>,
+    //Files currently being parsed, outermost first, for include cycle detection
+    include_stack: Vec<PathBuf>,
+    //Where each file was first included, a file may only be included once
+    includes: HashMap<PathBuf,SourceLocation>,
+    //Where each (element type, name) was first seen, names are unique per element type
+    name_locations: HashMap<(String,String),SourceLocation>
+}
+
+impl ParseState {
+    fn new() -> Self {
+        ParseState {
+            graph: SceneGraph::new(),
+            wbody: None,
+            defaults: None,
+            assets: None,
+            compiler: None,
+            raw_sections: Vec::new(),
+            sections: Vec::new(),
+            attributes: HashMap::new(),
+            p_stack: Vec::new(),
+            include_stack: Vec::new(),
+            includes: HashMap::new(),
+            name_locations: HashMap::new()
+        }
+    }
+}
+
+/* Resolves the orientation of every body & geom, & joint angle units, against the compiler settings */
+struct OrientationResolver<'a> {
+    compiler: &'a Compiler
+}
+
+impl<'a> NodeVisitorMut for OrientationResolver<'a> {
+    fn visit_body_mut(&mut self, body: &mut Body) -> VisitControl {
+        body.resolve_orientation(self.compiler);
+        VisitControl::Continue
+    }
+
+    fn visit_geom_mut(&mut self, geom: &mut Geom) -> VisitControl {
+        geom.resolve_orientation(self.compiler);
+        VisitControl::Continue
+    }
+
+    fn visit_joint_mut(&mut self, joint: &mut Joint) -> VisitControl {
+        joint.resolve_angles(self.compiler);
+        VisitControl::Continue
+    }
+
+    fn visit_inertial_mut(&mut self, inertial: &mut Inertial) -> VisitControl {
+        inertial.resolve_orientation(self.compiler);
+        VisitControl::Continue
+    }
+}
+
+/* A loaded model. All of its nodes live in graph, the remaining node 
+fields are ids of the top level sections within it. Cloning a model 
+gives an independent snapshot */
+#[derive(Clone)]
 pub struct Model {
-    pub world_body: Option<Rc<RefCell<Node>>>,
-    pub defaults: Option<Rc<RefCell<Node>>>,
-    pub asset_manager: Option<Rc<RefCell<Node>>>,
-    pub asset_files: HashMap<String,(FileType,Vec<u8>)>
+    pub graph: SceneGraph,
+    pub world_body: Option<NodeId>,
+    pub defaults: Option<NodeId>,
+    pub asset_manager: Option<NodeId>,
+    pub compiler: Option<NodeId>,
+    //Top level elements that aren't interpreted, e.g. <option> or <actuator>
+    pub raw_sections: Vec<NodeId>,
+    //Every top level section in the order it first appears, so the model is written back the same way
+    pub sections: Vec<NodeId>,
+    //Attributes of the <mujoco> root, e.g. model="ur5e"
+    pub attributes: HashMap<String,String>,
+    //Directory of the loaded model file, asset paths are resolved relative to it
+    pub model_dir: PathBuf,
+    pub asset_files: HashMap<String,(FileType,Vec<u8>)>,
+    pub keyframes: Vec<Keyframe>,
+    //Named worldbody nodes keyed by (element type, name), built at load time
+    name_index: HashMap<(String,String),NodeId>
 }
 
 impl Model {
     pub fn get_textures_data(&self) -> Vec<Texture> {
         let mut textures = Vec::new();
         let assets = &self.asset_files;
-        if let Some(asset_manager) = &self.asset_manager {
-            match &*asset_manager.borrow() {
-                Node::Assets(ass) => {
-                    let available_textures = ass.get_textures_data();
-                    for mut tex in available_textures {
-                        if assets.contains_key(&tex.file) {
-                            tex.file_data = assets.get(&tex.file).
-                                expect("Expected asset to be available").1.clone();
-                            textures.push(tex);
+        if let Some(asset_manager) = self.asset_manager {
+            let available_textures = AssetsManager::get_textures_data(&self.graph, asset_manager);
+            for mut tex in available_textures {
+                if assets.contains_key(&tex.file) {
+                    tex.file_data = assets.get(&tex.file).
+                        expect("Expected asset to be available").1.clone();
+                    textures.push(tex);
+                }
+            }
+            textures.extend(AssetsManager::get_mesh_textures(&self.graph, asset_manager));
+        }
+        textures
+    }
+
+    fn read_attributes(e: &BytesStart, location: &SourceLocation) 
+            -> Result<Vec<(String,String)>,ModelLoadError> {
+        let mut attributes: Vec<(String,String)> = Vec::new();
+        for attr in e.attributes() {
+            match attr {
+                Err(err) => {
+                    return Err(ModelLoadError::XmlSyntax {
+                        location: location.clone(),
+                        message: format!("Malformed attribute: {}", err)
+                    });
+                },
+                Ok(quick_xml::events::attributes::Attribute{key:k,value:v}) => {
+                    let key = std::str::from_utf8(k.into_inner());
+                    let value = std::str::from_utf8(&v);
+                    match (key,value) {
+                        (Ok(k),Ok(v)) => {
+                            attributes.push((k.to_string(), v.to_string()));
+                        }
+                        _ => {
+                            return Err(ModelLoadError::XmlSyntax {
+                                location: location.clone(),
+                                message: "Attribute is not valid UTF-8".to_string()
+                            });
                         }
                     }
-                },
+                }
+            }
+        }
+        Ok(attributes)
+    }
+
+    /* Builds a node of the given kind & sets its attributes, 
+    reporting bad attribute values against the element's location */
+    fn new_node(mut node: Node, attributes: Vec<(String,String)>, location: &SourceLocation) 
+            -> Result<Node,ModelLoadError> {
+        let element = node.get_node_name();
+        node.add_attrs(attributes).map_err(|error| {
+            ModelLoadError::BadAttribute {
+                location: location.clone(),
+                element: element,
+                error: error
+            }
+        })?;
+        Ok(node)
+    }
+
+    fn nesting_error(location: &SourceLocation, message: String) -> ModelLoadError {
+        ModelLoadError::Nesting {
+            location: location.clone(),
+            message: message
+        }
+    }
+
+    /* Names have to be unique per element type across the model & all of its 
+    included files, all actuator types counting as one. Unnamed elements are skipped */
+    fn check_unique_name(state: &mut ParseState, node: &Node, location: &SourceLocation, 
+            is_asset: bool) -> Result<(),ModelLoadError> {
+        //Actuators of all types share their names
+        let kind = match node {
+            Node::Actuator(_) => "actuator".to_string(),
+            _ => node.get_node_name()
+        };
+        let key = (kind, node.get_name());
+        if key.1 == "" {
+            return Ok(());
+        }
+        if let Some(first) = state.name_locations.get(&key) {
+            let first = first.clone();
+            return Err(if is_asset {
+                ModelLoadError::DuplicateAsset {
+                    location: location.clone(),
+                    kind: key.0,
+                    name: key.1,
+                    first: first
+                }
+            } else {
+                ModelLoadError::DuplicateName {
+                    location: location.clone(),
+                    kind: key.0,
+                    name: key.1,
+                    first: first
+                }
+            });
+        }
+        state.name_locations.insert(key, location.clone());
+        Ok(())
+    }
+
+    /* Adds node under parent, passing down the parent's childclass. The 
+    node's own class, set from its attributes, takes precedence */
+    fn add_child(graph: &mut SceneGraph, parent: NodeId, mut node: Node) -> NodeId {
+        if let Some(childclass) = graph.get(parent).get_set_attributes_list().get("childclass") {
+            match node {
+                Node::Raw(ref mut raw) => raw.inherit_class(childclass.clone()),
+                //Inertials don't take defaults
+                Node::Inertial(_) => (),
+                _ => {
+                    let _ = node.add_attr("class".to_string(), childclass.clone());
+                }
+            }
+            //Only bodies take a childclass, nested bodies inherit it through them
+            if let Node::Body(_) = node {
+                let _ = node.add_attr("childclass".to_string(), childclass.clone());
+            }
+        }
+        graph.add_child(parent, node)
+    }
+
+    /* Places an unrecognised element: as a top level section, as defaults when 
+    within a default, or as a child of the enclosing element. Returns the node 
+    if it was added to the graph, so that its own children can go under it */
+    fn add_raw(state: &mut ParseState, node: Node, location: &SourceLocation) 
+            -> Result<Option<NodeId>,ModelLoadError> {
+        match state.p_stack.last().copied() {
+            None => {
+                let x = state.graph.add_root(node);
+                state.raw_sections.push(x);
+                state.sections.push(x);
+                Ok(Some(x))
+            },
+            Some(parent) => {
+                if let Node::Defaults(d) = state.graph.get_mut(parent) {
+                    d.set_element_defaults(&node);
+                    return Ok(None);
+                }
+                Self::check_unique_name(state, &node, location, false)?;
+                Ok(Some(Self::add_child(&mut state.graph, parent, node)))
+            }
+        }
+    }
+
+    /* Adds any element other than the worldbody, default & asset sections, 
+    whether it's written as <x/> or as <x>...</x>. Returns the node if it was 
+    added to the graph, so that the element's children can go under it */
+    fn add_element(state: &mut ParseState, tag: &[u8], attributes: Vec<(String,String)>, 
+            location: &SourceLocation, file_dir: &Path) -> Result<Option<NodeId>,ModelLoadError> {
+        let node = match tag {
+            b"include" => {
+                let include_file = attributes.iter().find(|(k, _)| k == "file").
+                    map(|(_, v)| v.clone()).ok_or(Self::nesting_error(location,
+                        "Include is missing its file attribute".to_string()))?;
+                //Included files are resolved relative to the including file
+                let include_path = file_dir.join(&include_file);
+                let canonical = include_path.canonicalize().map_err(|error| {
+                    ModelLoadError::Io { path: include_path.clone(), error: error }
+                })?;
+                if state.include_stack.contains(&canonical) {
+                    let mut chain = state.include_stack.clone();
+                    chain.push(canonical);
+                    return Err(ModelLoadError::IncludeCycle {
+                        location: location.clone(),
+                        chain: chain
+                    });
+                }
+                if let Some(first) = state.includes.get(&canonical) {
+                    return Err(ModelLoadError::DuplicateInclude {
+                        location: location.clone(),
+                        path: canonical,
+                        first: first.clone()
+                    });
+                }
+                state.includes.insert(canonical.clone(), location.clone());
+                state.include_stack.push(canonical);
+                Self::parse_file(&include_path, state)?;
+                state.include_stack.pop();
+                None
+            },
+            b"compiler" => {
+                if !state.p_stack.is_empty() {
+                    return Err(Self::nesting_error(location,
+                        "Wasn't expecting compiler to be nested within a parent".to_string()));
+                }
+                //Multiple compiler elements are merged into a single node
+                let x = match state.compiler {
+                    Some(x) => x,
+                    None => {
+                        let x = state.graph.add_root(Node::Compiler(Compiler::new()));
+                        state.compiler = Some(x);
+                        state.sections.push(x);
+                        x
+                    }
+                };
+                state.graph.get_mut(x).add_attrs(attributes).map_err(|error| {
+                    ModelLoadError::BadAttribute {
+                        location: location.clone(),
+                        element: "compiler".to_string(),
+                        error: error
+                    }
+                })?;
+                None
+            },
+            b"body" => {
+                println!("body detected.");
+                let parent_type = state.p_stack.last().map_or("".to_string(), |p| {
+                    state.graph.get(*p).get_node_name()
+                });
+                if parent_type != "worldbody" && parent_type != "body" {
+                    return Err(Self::nesting_error(location,
+                        format!("Body can't be nested within {}", parent_type)));
+                }
+                Some(Self::new_node(Node::Body(Body::default()), attributes, location)?)
+            },
+            b"geom" => {
+                println!("Geom detected.");
+                Some(Self::new_node(Node::Geom(Geom::default()), attributes, location)?)
+            },
+            b"joint" => {
+                println!("joint detected.");
+                Some(Self::new_node(Node::Joint(Joint::default()), attributes, location)?)
+            },
+            b"inertial" => {
+                let parent_type = state.p_stack.last().map_or("".to_string(), |p| {
+                    state.graph.get(*p).get_node_name()
+                });
+                if parent_type != "body" {
+                    return Err(Self::nesting_error(location,
+                        format!("Inertial can't be nested within {}", parent_type)));
+                }
+                let body = *state.p_stack.last().expect("Expected the inertial's body");
+                if state.graph.children(body).iter().any(|c| matches!(state.graph.get(*c), Node::Inertial(_))) {
+                    return Err(Self::nesting_error(location,
+                        "A body can have a single inertial".to_string()));
+                }
+                Some(Self::new_node(Node::Inertial(Inertial::new()), attributes, location)?)
+            },
+            b"mesh" => {
+                println!("Mesh detected.");
+                Some(Self::new_node(Node::Mesh(Mesh::new("".to_string())), attributes, location)?)
+            },
+            b"material" => {
+                println!("Material detected.");
+                Some(Self::new_node(Node::Material(Material::new()), attributes, location)?)
+            },
+            b"texture" => {
+                println!("Texture detected.");
+                Some(Self::new_node(Node::Texture(Texture::new()), attributes, location)?)
+            },
+            b"hfield" => {
+                let parent_type = state.p_stack.last().map_or("".to_string(), |p| {
+                    state.graph.get(*p).get_node_name()
+                });
+                if parent_type != "asset" {
+                    return Err(Self::nesting_error(location,
+                        format!("Hfield can't be nested within {}", parent_type)));
+                }
+                Some(Self::new_node(Node::Hfield(Hfield::new()), attributes, location)?)
+            },
+            b"motor" | b"general" | b"position" | b"velocity" => {
+                let parent_type = state.p_stack.last().map_or("".to_string(), |p| {
+                    state.graph.get(*p).get_node_name()
+                });
+                if parent_type != "actuator" && parent_type != "default" {
+                    return Err(Self::nesting_error(location,
+                        format!("Actuator can't be nested within {}", parent_type)));
+                }
+                let actuator = Actuator::from_tag(&String::from_utf8_lossy(tag)).
+                    expect("Expected an actuator tag");
+                Some(Self::new_node(Node::Actuator(actuator), attributes, location)?)
+            },
+            b"mujoco" => {
+                //Included files have their own <mujoco> root, only the model's is kept
+                if state.include_stack.len() <= 1 {
+                    state.attributes.extend(attributes);
+                }
+                None
+            },
+            tag => {
+                let tag = String::from_utf8_lossy(tag).to_string();
+                let node = Self::new_node(Node::Raw(RawElement::new(tag, Some(location.clone()))), 
+                    attributes, location)?;
+                return Self::add_raw(state, node, location);
+            }
+        };
+        let Some(node) = node else {
+            return Ok(None);
+        };
+        let parent = state.p_stack.last().copied().ok_or(Self::nesting_error(location,
+            format!("No parent detected when parsing {} tag", node.get_node_name())))?;
+        let parent_type = state.graph.get(parent).get_node_name();
+        if parent_type == "default" {
+            //Elements within defaults are templates, only their attributes are kept
+            match state.graph.get_mut(parent) {
+                Node::Defaults(d) => d.set_element_defaults(&node),
                 _ => ()
             }
+            Ok(None)
+        } else {
+            Self::check_unique_name(state, &node, location, parent_type == "asset")?;
+            Ok(Some(Self::add_child(&mut state.graph, parent, node)))
         }
-        textures
     }
 
-    pub fn load_model(file: std::path::PathBuf) -> Self {
-        let contents = fs::read_to_string(file).unwrap();
+    /* Parses a single MJCF file into the shared parse state. 
+    <include> elements recurse into this with the included file, so its 
+    elements land wherever the include appeared in the including file */
+    fn parse_file(file: &Path, state: &mut ParseState) -> Result<(),ModelLoadError> {
+        let contents = fs::read_to_string(file).map_err(|error| {
+            ModelLoadError::Io { path: file.to_path_buf(), error: error }
+        })?;
+        let file_dir = file.parent().map_or(PathBuf::new(), |dir| dir.to_path_buf());
+        let mut lines = LineTracker::new(file.to_path_buf(), contents.as_str());
         let mut reader = Reader::from_str(contents.as_str());
         let mut buf = Vec::new();
-        let mut wbody: Option<Rc<RefCell<Node>>> = None;
-        let mut defaults: Option<Rc<RefCell<Node>>> = None;
-        let mut assets: Option<Rc<RefCell<Node>>> = None;
-        let mut p_stack: Vec<Rc<RefCell<Node>>> = Vec::new();
+        let stack_depth = state.p_stack.len();
         loop {
+            let location = lines.locate(reader.buffer_position());
             match reader.read_event_into(&mut buf) {
-                Err(e) => panic!("Error at position {}: {:?}", reader.error_position(), e),
+                Err(e) => {
+                    return Err(ModelLoadError::XmlSyntax {
+                        location: lines.locate(reader.error_position()),
+                        message: e.to_string()
+                    });
+                },
                 // exits the loop when reaching end of file
                 Ok(Event::Eof) => break,
 
                 Ok(Event::End(e)) => {
-                    let current_node = p_stack.pop();
-                    if let Some(current_node) = current_node {
-                        let current_node_type = current_node.borrow().get_node_name();
-                        match e.name().as_ref() {
-                            b"worldbody" => {
-                                if current_node_type != "worldbody" {
-                                    panic!("Expected worldbody on the p_stack. Got {}",current_node_type);
+                    let tag = String::from_utf8_lossy(e.name().as_ref()).to_string();
+                    match tag.as_str() {
+                        "worldbody" | "body" | "default" | "asset" => {
+                            if state.p_stack.len() <= stack_depth {
+                                return Err(Self::nesting_error(&location, 
+                                    format!("Closing </{}> without a matching opening tag", tag)));
+                            }
+                            let current_node = state.p_stack.pop().expect("Expected node on the p_stack");
+                            let current_node_type = state.graph.get(current_node).get_node_name();
+                            if current_node_type != tag {
+                                return Err(Self::nesting_error(&location, 
+                                    format!("Expected </{}> to close <{}>", tag, current_node_type)));
+                            }
+                        },
+                        _ => {
+                            //Other elements written with a closing tag were pushed if they were added to the graph
+                            if let Some(current_node) = state.p_stack.last().copied() {
+                                if state.p_stack.len() > stack_depth && state.graph.get(current_node).get_node_name() == tag {
+                                    state.p_stack.pop();
                                 }
-                            },
-                            b"body" => {
-                                if current_node_type != "body" {
-                                    panic!("Expected body on the p_stack. Got {}",current_node_type);
-                                } 
-                            },
-                            b"default" => {
-                                if current_node_type != "default" {
-                                    panic!("Expected default on the p_stack. Got {}",current_node_type);
-                                } 
-                            },
-                            b"asset" => {
-                                if current_node_type != "asset" {
-                                    panic!("Expected asset on the p_stack. Got {}",current_node_type);
-                                } 
-                            },
-                            _ => ()
+                            }
                         }
                     }
                 },
                 
                 Ok(Event::Start(e)) => {
-                    let mut attributes: Vec<(String,String)> = Vec::new();
-                    for attr in e.attributes() {
-                        match attr {
-                            Err(e) => panic!("Error extracting attributes."),
-                            Ok(quick_xml::events::attributes::Attribute{key:k,value:v}) => {
-                                let key = std::str::from_utf8(k.into_inner());
-                                let value = std::str::from_utf8(&v);
-                                match (key,value) {
-                                    (Ok(k),Ok(v)) => {
-                                        attributes.push((String::from_str(k).expect("error deriving string"),
-                                                String::from_str(v).expect("error deriving string")));
-                                    }
-                                    _ => panic!("Error deriving attributes")
-                                }
-                            }
-                        }
-                    }
+                    let attributes = Self::read_attributes(&e, &location)?;
                     let child = match e.name().as_ref() {
                         b"worldbody" => {
                             println!("Worldbody detected.");
-                            //process attributes
-                            let x = Rc::new(RefCell::new(Node::WorldBody(WorldBody::default())));
-                            x.borrow_mut().add_attrs(attributes);
-                            wbody = Some(x.clone());
-                            Some(x)
+                            if !state.p_stack.is_empty() {
+                                return Err(Self::nesting_error(&location,
+                                    "Wasn't expecting worldbody to be nested within a parent".to_string()));
+                            }
+                            //Later worldbody sections (e.g. from included files) extend the first one
+                            match state.wbody {
+                                Some(x) => {
+                                    state.graph.get_mut(x).add_attrs(attributes).map_err(|error| {
+                                        ModelLoadError::BadAttribute {
+                                            location: location.clone(),
+                                            element: "worldbody".to_string(),
+                                            error: error
+                                        }
+                                    })?;
+                                    Some(x)
+                                },
+                                None => {
+                                    //process attributes
+                                    let node = Self::new_node(Node::WorldBody(WorldBody::default()), attributes, &location)?;
+                                    let x = state.graph.add_root(node);
+                                    state.wbody = Some(x);
+                                    state.sections.push(x);
+                                    Some(x)
+                                }
+                            }
                         },
                         b"default" => {
-                            let parent = p_stack.last();
+                            let node = Self::new_node(Node::Defaults(Defaults::new()), attributes, &location)?;
+                            let parent = state.p_stack.last().copied();
                             match parent {
                                 Some(e) => {
                                     println!("Nested default detected.");
-                                    let mut parent = e.borrow_mut();
-                                    let mut node = Node::Defaults(Defaults::new());
-                                    node.add_attrs(attributes);
-                                    let child = parent.add_child(node);  
+                                    let parent_type = state.graph.get(e).get_node_name();
+                                    if parent_type != "default" {
+                                        return Err(Self::nesting_error(&location,
+                                            format!("Default can't be nested within {}", parent_type)));
+                                    }
+                                    let child = state.graph.add_child(e, node);
                                     Some(child)
                                 },
                                 None => {
                                     println!("Root level default detected");
-                                    let x = Rc::new(RefCell::new(Node::Defaults(Defaults::new())));
-                                    x.borrow_mut().add_attrs(attributes);
-                                    defaults = Some(x.clone());
-                                    Some(x)
+                                    match state.defaults {
+                                        Some(x) => {
+                                            Some(x)
+                                        },
+                                        None => {
+                                            let x = state.graph.add_root(node);
+                                            state.defaults = Some(x);
+                                            state.sections.push(x);
+                                            Some(x)
+                                        }
+                                    }
                                 }
                             }
                         },
                         b"asset" => {
-                            let parent = p_stack.last();
+                            let parent = state.p_stack.last();
                             match parent {
-                                Some(e) => {
-                                    panic!("Wasn't expecting asset to be nested within a parent"); 
-                                    None
+                                Some(_) => {
+                                    return Err(Self::nesting_error(&location,
+                                        "Wasn't expecting asset to be nested within a parent".to_string()));
                                 },
                                 None => {
                                     println!("Root level asset detected");
-                                    let x = Rc::new(RefCell::new(Node::Assets(AssetsManager::new())));
-                                    x.borrow_mut().add_attrs(attributes);
-                                    assets = Some(x.clone());
-                                    Some(x)
+                                    match state.assets {
+                                        Some(x) => {
+                                            Some(x)
+                                        },
+                                        None => {
+                                            let node = Self::new_node(Node::Assets(AssetsManager::new()), attributes, &location)?;
+                                            let x = state.graph.add_root(node);
+                                            state.assets = Some(x);
+                                            state.sections.push(x);
+                                            Some(x)
+                                        }
+                                    }
                                 }
                             }
                         },
-                        b"body" => {
-                            println!("body detected.");
-                            let parent = p_stack.last();
-                            match parent {
-                                Some(e) => {
-                                    let mut parent = e.borrow_mut();
-                                    let mut node = Node::Body(Body::default());
-                                    node.add_attrs(attributes);
-                                    let child = parent.add_child(node);  
-                                    Some(child)
-                                },
-                                None => {
-                                    panic!("No parent detected in the p_stack when parsing body start tag.");
-                                    None
-                                }
-                            }
-                        }
-                        _ => None
+                        tag => Self::add_element(state, tag, attributes, &location, &file_dir)?
                     };
                     child.map_or((), |e| {
-                        p_stack.push(e);
+                        state.p_stack.push(e);
                     })
                 },
 
                 Ok(Event::Empty(e)) => {
-                    let mut attributes: Vec<(String,String)> = Vec::new();
-                    for attr in e.attributes() {
-                        match attr {
-                            Err(e) => panic!("Error extracting attributes."),
-                            Ok(quick_xml::events::attributes::Attribute{key:k,value:v}) => {
-                                let key = std::str::from_utf8(k.into_inner());
-                                let value = std::str::from_utf8(&v);
-                                match (key,value) {
-                                    (Ok(k),Ok(v)) => {
-                                        attributes.push((String::from_str(k).expect("error deriving string"),String::from_str(v).expect("error deriving string")));
-                                    }
-                                    _ => panic!("Error deriving attributes")
-                                }
-                            }
-                        }
-                    }
-                    match e.name().as_ref() {
-                        b"geom" => {
-                            println!("Geom detected.");
-                            let parent = p_stack.last();
-                            if let Some(parent) = parent {
-                                let mut node = Node::Geom(Geom::default());
-                                node.add_attrs(attributes);
-                                parent.borrow_mut().add_child(node);
-                            } else {
-                                panic!("No parent detected in the p_stack when parsing joint tag.");
-                            }
-                        },
-                        b"joint" => {
-                            println!("joint detected.");
-                            let parent = p_stack.last();
-                            if let Some(parent) = parent {
-                                let mut node = Node::Joint(Joint::default());
-                                node.add_attrs(attributes);
-                                parent.borrow_mut().add_child(node);
-                            } else {
-                                panic!("No parent detected in the p_stack when parsing joint tag.");
-                            }
-                        },
-                        b"mesh" => {
-                            println!("Mesh detected.");
-                            let parent = p_stack.last();
-                            match parent {
-                                Some(e) => {
-                                    let mut parent = e.borrow_mut();
-                                    let mut node = Node::Mesh(Mesh::new("".to_string()));
-                                    node.add_attrs(attributes);
-                                    parent.add_child(node);  
-                                },
-                                None => {
-                                    panic!("No parent detected in the p_stack when parsing mesh tag.");
-                                }
-                            }
-                        },
-                        b"material" => {
-                            println!("Material detected.");
-                            let parent = p_stack.last();
-                            match parent {
-                                Some(e) => {
-                                    let mut parent = e.borrow_mut();
-                                    let mut node = Node::Material(Material::new());
-                                    node.add_attrs(attributes);
-                                    parent.add_child(node);  
-                                },
-                                None => {
-                                    panic!("No parent detected in the p_stack when parsing material tag.");
-                                }
-                            }
-                        },
-                        b"texture" => {
-                            println!("Texture detected.");
-                            let parent = p_stack.last();
-                            match parent {
-                                Some(e) => {
-                                    let mut parent = e.borrow_mut();
-                                    let mut node = Node::Texture(Texture::new());
-                                    node.add_attrs(attributes);
-                                    parent.add_child(node);  
-                                },
-                                None => {
-                                    panic!("No parent detected in the p_stack when parsing texture tag.");
-                                }
-                            }
-                        },
-                        _ => ()
-                    };
+                    let attributes = Self::read_attributes(&e, &location)?;
+                    Self::add_element(state, e.name().as_ref(), attributes, &location, &file_dir)?;
                 },
                 _ => (),
             }
             // if we don't keep a borrow elsewhere, we can clear the buffer to keep memory usage low
             buf.clear();
         }
-        if let Some(e) = &defaults {
-            if let Some(w) = &wbody {
-                match &mut *e.borrow_mut() {
-                    Node::Defaults(d) => {
-                        d.apply_defaults(w.clone(),None);
-                    },
-                    _ => {}
-                }
+        if state.p_stack.len() > stack_depth {
+            let unclosed = state.graph.get(*state.p_stack.last().expect("Expected node on the p_stack")).
+                get_node_name();
+            return Err(Self::nesting_error(&lines.locate(reader.buffer_position()),
+                format!("Reached end of file with <{}> still open", unclosed)));
+        }
+        Ok(())
+    }
+
+    pub fn load_model(file: std::path::PathBuf) -> Result<Self,ModelLoadError> {
+        Self::load_model_with_progress(file, |_| ())
+    }
+
+    /* Same as load_model, calling progress as each loading step starts */
+    pub fn load_model_with_progress<F: FnMut(LoadProgress)>(file: std::path::PathBuf, 
+            progress: F) -> Result<Self,ModelLoadError> {
+        Self::load_model_with_options(file, &MeshProcessing::default(), progress)
+    }
+
+    /* Same as load_model_with_progress, running mesh_processing over the mesh
+    assets once they're read, e.g. to decimate large CAD meshes */
+    pub fn load_model_with_options<F: FnMut(LoadProgress)>(file: std::path::PathBuf, 
+            mesh_processing: &MeshProcessing, mut progress: F) -> Result<Self,ModelLoadError> {
+        let model_dir = file.parent().map_or(PathBuf::new(), |dir| dir.to_path_buf());
+        let mut state = ParseState::new();
+        if let Ok(canonical) = file.canonicalize() {
+            state.include_stack.push(canonical);
+        }
+        progress(LoadProgress::Parsing(file.clone()));
+        Self::parse_file(&file, &mut state)?;
+        progress(LoadProgress::ResolvingElements);
+        let mut graph = state.graph;
+        let wbody = state.wbody;
+        let defaults = state.defaults;
+        let assets = state.assets;
+        let raw_sections = state.raw_sections;
+        let compiler = state.compiler.unwrap_or_else(|| {
+            graph.add_root(Node::Compiler(Compiler::new()))
+        });
+        let actuator_sections: Vec<NodeId> = raw_sections.iter().copied().filter(|id| {
+            graph.get(*id).get_node_name() == "actuator"
+        }).collect();
+        if let Some(d) = defaults {
+            for root in wbody.iter().chain(actuator_sections.iter()) {
+                Defaults::apply_defaults(&mut graph, d, *root, None);
             }
         }
 
         let mut image_files_map: HashMap<String,(FileType,Vec<u8>)> = HashMap::new();
-        let mut obj_files_map: HashMap<String,String> = HashMap::new();
-        if let Some(e) = &mut assets {
-            if let Some(w) = &wbody {
-                 w.borrow_mut().apply_assets(e.clone());
-                println!("{:?}",*w.borrow());
-            }
-            let mut asset_manager = e.borrow_mut();
-            match &mut *asset_manager {
-                Node::Assets(ref mut m) => {
-                    let files = m.to_load_files();
-                    println!("Loading files now: {:?}",files);
-                    let mut dimensions_map: HashMap<String,(u32,u32)> = HashMap::new();
-                    for file in &files {
-                        // println!("Opening file: {}",file);
-                        if file.ends_with(".png") {
-                            let img = 
-                                ImageReader::open(("assets/assets/".to_string() + file.as_str()).as_str()).
-                                expect("Expected to obtain asset file").decode().
-                                expect("Expected to be able to decode asset file").to_rgb8();
-                            //TODO: Do we really need to do a costly clone here? 
-                            let raw_img = img.as_raw().clone();
-                            // println!("Image loaded of dimensions {} {}",img.width(),img.height());
-                            image_files_map.insert(file.clone(),
-                                (FileType::Image(img.width(),img.height()),raw_img));
-                            dimensions_map.insert(file.clone(),(img.width(),img.height()));
-                        } else if file.ends_with(".obj") {
-                            let file_raw = 
-                                fs::read_to_string("assets/assets/".
-                                    to_string() + file).expect("Expected to have the obj file.");
-                            obj_files_map.insert(file.clone(), file_raw);
+        let mut mesh_files_map: HashMap<String,(Vec<u8>,HashMap<String,Vec<u8>>)> = HashMap::new();
+        let compiler_node = match graph.get(compiler) {
+            Node::Compiler(c) => c.clone(),
+            _ => Compiler::new()
+        };
+        //Orientations depend on the compiler's angle settings & on defaults, so they're resolved last
+        if let Some(w) = wbody {
+            walk_mut(&mut graph, w, &mut OrientationResolver { compiler: &compiler_node });
+        }
+        if let Some(e) = assets {
+            AssetsManager::process_materials(&mut graph, e);
+            if let Some(w) = wbody {
+                let nodes: Vec<NodeId> = depth_first(&graph, w).collect();
+                for id in nodes {
+                    //Nodes are linked against a copy, so the graph can be read meanwhile
+                    let mut node = graph.get(id).clone();
+                    node.apply_assets(&graph, e).map_err(|missing| {
+                        ModelLoadError::MissingAsset { 
+                            kind: missing.kind, 
+                            name: missing.name, 
+                            path: None 
                         }
-                    }      
-                    m.load_dimensions(dimensions_map);
-                    m.load_obj_meshes(obj_files_map);
-                },
-                _ => ()
+                    })?;
+                    *graph.get_mut(id) = node;
+                }
+            }
+            let c = &compiler_node;
+            let texture_files = AssetsManager::to_load_texture_files(&graph, e);
+            let mesh_files = AssetsManager::to_load_mesh_files(&graph, e);
+            let mut dimensions_map: HashMap<String,(u32,u32)> = HashMap::new();
+            for (i, file) in texture_files.iter().enumerate() {
+                progress(LoadProgress::LoadingTexture {
+                    file: file.clone(),
+                    index: i + 1,
+                    count: texture_files.len()
+                });
+                let path = c.resolve_texture_path(&model_dir, file);
+                // println!("Opening file: {}",path.display());
+                let img = ImageReader::open(&path).map_err(|_| {
+                        ModelLoadError::MissingAsset {
+                            kind: "texture".to_string(),
+                            name: file.clone(),
+                            path: Some(path.clone())
+                        }
+                    })?.decode().map_err(|error| {
+                        ModelLoadError::InvalidAsset {
+                            path: path.clone(),
+                            message: error.to_string()
+                        }
+                    })?.to_rgb8();
+                //TODO: Do we really need to do a costly clone here? 
+                let raw_img = img.as_raw().clone();
+                // println!("Image loaded of dimensions {} {}",img.width(),img.height());
+                image_files_map.insert(file.clone(),
+                    (FileType::Image(img.width(),img.height()),raw_img));
+                dimensions_map.insert(file.clone(),(img.width(),img.height()));
+            }
+            for (i, file) in mesh_files.iter().enumerate() {
+                progress(LoadProgress::LoadingMesh {
+                    file: file.clone(),
+                    index: i + 1,
+                    count: mesh_files.len()
+                });
+                let path = c.resolve_mesh_path(&model_dir, file);
+                let file_raw = fs::read(&path).map_err(|_| {
+                    ModelLoadError::MissingAsset {
+                        kind: "mesh".to_string(),
+                        name: file.clone(),
+                        path: Some(path.clone())
+                    }
+                })?;
+                //Material libraries sit next to the OBJ, those that can't be read are skipped
+                let mut material_files = HashMap::new();
+                if mesh_io::MeshFormat::detect(file, &file_raw) == Some(mesh_io::MeshFormat::Obj) {
+                    for library in mesh_io::material_libraries(&file_raw) {
+                        let library_path = path.parent().map_or(PathBuf::from(&library), |p| p.join(&library));
+                        match fs::read(&library_path) {
+                            Ok(contents) => {
+                                material_files.insert(library, contents);
+                            },
+                            Err(_) => progress(LoadProgress::SkippedMaterialLibrary(library_path))
+                        }
+                    }
+                }
+                mesh_files_map.insert(file.clone(), (file_raw, material_files));
+            }
+            //Height fields are read as grayscale, whatever the image's format
+            let hfield_files = AssetsManager::to_load_hfield_files(&graph, e);
+            let mut hfield_files_map: HashMap<String,(u32,u32,Vec<u8>)> = HashMap::new();
+            for (i, file) in hfield_files.iter().enumerate() {
+                progress(LoadProgress::LoadingHfield {
+                    file: file.clone(),
+                    index: i + 1,
+                    count: hfield_files.len()
+                });
+                let path = c.resolve_mesh_path(&model_dir, file);
+                let img = ImageReader::open(&path).map_err(|_| {
+                        ModelLoadError::MissingAsset {
+                            kind: "hfield".to_string(),
+                            name: file.clone(),
+                            path: Some(path.clone())
+                        }
+                    })?.decode().map_err(|error| {
+                        ModelLoadError::InvalidAsset {
+                            path: path.clone(),
+                            message: error.to_string()
+                        }
+                    })?.to_luma8();
+                hfield_files_map.insert(file.clone(), (img.width(), img.height(), img.into_raw()));
+            }
+            AssetsManager::load_hfields(&mut graph, e, hfield_files_map).map_err(|(name, message)| {
+                ModelLoadError::InvalidAsset {
+                    path: file.clone(),
+                    message: format!("height field '{}' {}", name, message)
+                }
+            })?;
+            AssetsManager::load_dimensions(&mut graph, e, dimensions_map);
+            if !mesh_files_map.is_empty() {
+                progress(LoadProgress::ParsingMeshes { count: mesh_files_map.len() });
+            }
+            AssetsManager::load_mesh_files(&mut graph, e, mesh_files_map).map_err(|(file, message)| {
+                ModelLoadError::InvalidAsset {
+                    path: c.resolve_mesh_path(&model_dir, &file),
+                    message: message
+                }
+            })?;
+            //Diffuse maps of OBJ materials, like their libraries those that can't be found are skipped
+            let map_files = AssetsManager::to_load_mesh_texture_files(&graph, e);
+            let mut map_files_map: HashMap<String,(u32,u32,Vec<u8>)> = HashMap::new();
+            for (i, file) in map_files.iter().enumerate() {
+                progress(LoadProgress::LoadingTexture {
+                    file: file.clone(),
+                    index: i + 1,
+                    count: map_files.len()
+                });
+                let path = c.resolve_mesh_path(&model_dir, file);
+                let Ok(reader) = ImageReader::open(&path) else {
+                    continue;
+                };
+                let img = reader.decode().map_err(|error| {
+                        ModelLoadError::InvalidAsset {
+                            path: path.clone(),
+                            message: error.to_string()
+                        }
+                    })?.to_rgb8();
+                map_files_map.insert(file.clone(), (img.width(), img.height(), img.into_raw()));
+            }
+            AssetsManager::load_mesh_textures(&mut graph, e, map_files_map);
+            //Inline meshes without faces are drawn as their convex hull, which is too costly to build per frame
+            let inline_meshes = AssetsManager::inline_meshes(&graph, e);
+            for (i, id) in inline_meshes.iter().enumerate() {
+                if let Node::Mesh(m) = graph.get_mut(*id) {
+                    let name = m.get_name();
+                    progress(LoadProgress::BuildingHull {
+                        name: name.clone(),
+                        index: i + 1,
+                        count: inline_meshes.len()
+                    });
+                    m.build_hull().map_err(|error| {
+                        ModelLoadError::InvalidAsset {
+                            path: file.clone(),
+                            message: format!("inline mesh '{}' is invalid: {}", name, error)
+                        }
+                    })?;
+                }
+            }
+            if !mesh_processing.is_noop() {
+                let count = graph.children(e).iter().filter(|id| matches!(graph.get(**id), Node::Mesh(_))).count();
+                progress(LoadProgress::ProcessingMeshes { count: count });
+                AssetsManager::process_meshes(&mut graph, e, mesh_processing);
+            }
+        }
+        //Without an asset section there's no mesh, height field or material a geom could refer to
+        if let (None, Some(w)) = (assets, wbody) {
+            for id in depth_first(&graph, w) {
+                if let Some(missing) = match graph.get(id) {
+                    Node::Geom(g) => g.get_asset_ref(),
+                    _ => None
+                } {
+                    return Err(ModelLoadError::MissingAsset {
+                        kind: missing.kind,
+                        name: missing.name,
+                        path: None
+                    });
+                }
+            }
+        }
+        let mut name_index = wbody.map_or(HashMap::new(), |w| {
+            query::build_name_index(&graph, w)
+        });
+        for section in actuator_sections {
+            name_index.extend(query::build_name_index(&graph, section));
+        }
+        Self::link_actuators(&mut graph, &name_index)?;
+        let nq: usize = wbody.map_or(Vec::new(), |w| Self::joints_in(&graph, w)).iter().map(|id| {
+            match graph.get(*id) {
+                Node::Joint(j) => j.nq(),
+                _ => 0
+            }
+        }).sum();
+        let nu = raw_sections.iter().flat_map(|section| depth_first(&graph, *section)).filter(|id| {
+            matches!(graph.get(*id), Node::Actuator(_))
+        }).count();
+        let mut keyframes = Vec::new();
+        for section in raw_sections.iter().filter(|id| graph.get(**id).get_node_name() == "keyframe") {
+            for key in graph.children(*section) {
+                if let Node::Raw(raw) = graph.get(*key) {
+                    if raw.get_node_name() != "key" {
+                        continue;
+                    }
+                    keyframes.push(Keyframe::from_key(raw, nq, nu).map_err(|error| {
+                        ModelLoadError::BadAttribute {
+                            location: raw.get_location().cloned().expect("Expected parsed keys to have a location"),
+                            element: "key".to_string(),
+                            error: error
+                        }
+                    })?);
+                }
             }
         }
-        Self {
+        let mut model = Self {
+            graph: graph,
             world_body: wbody,
             defaults: defaults,
             asset_manager: assets,
-            asset_files: image_files_map
+            compiler: Some(compiler),
+            raw_sections: raw_sections,
+            sections: state.sections,
+            attributes: state.attributes,
+            model_dir: model_dir,
+            asset_files: image_files_map,
+            keyframes: keyframes,
+            name_index: name_index
+        };
+        model.reset_qpos();
+        Ok(model)
+    }
+
+    /* Looks up a named element of the given type, e.g. ("body", "core") */
+    pub fn find_by_name(&self, node_type: &str, name: &str) -> Option<NodeId> {
+        self.name_index.get(&(node_type.to_string(), name.to_string())).copied()
+    }
+
+    pub fn find_all_of_type(&self, node_type: &str) -> Vec<NodeId> {
+        self.world_body.map_or(Vec::new(), |w| {
+            query::find_all_of_type(&self.graph, w, node_type)
+        })
+    }
+
+    pub fn find_by_class(&self, class: &str) -> Vec<NodeId> {
+        self.world_body.map_or(Vec::new(), |w| {
+            query::find_by_class(&self.graph, w, class)
+        })
+    }
+
+    /* Resolves paths starting at the worldbody, e.g. "worldbody/core/cubelet_12" */
+    pub fn find_by_path(&self, path: &str) -> Option<NodeId> {
+        self.world_body.and_then(|w| query::find_by_path(&self.graph, w, path))
+    }
+
+    /* The model as MJCF, see writer::write_mjcf */
+    pub fn to_mjcf(&self) -> String {
+        let mut out = Vec::new();
+        writer::write_mjcf(self, &mut out).expect("Writing to memory failed");
+        String::from_utf8(out).expect("MJCF output isn't valid UTF-8")
+    }
+
+    /* Writes the model to path. Asset paths aren't rewritten, so the file 
+    should go where the original assets can still be found from */
+    pub fn save_model(&self, path: &Path) -> std::io::Result<()> {
+        let file = fs::File::create(path)?;
+        writer::write_mjcf(self, std::io::BufWriter::new(file))
+    }
+
+    /* Points every actuator driving a joint at that joint's node */
+    fn link_actuators(graph: &mut SceneGraph, name_index: &HashMap<(String,String),NodeId>) 
+            -> Result<(),ModelLoadError> {
+        let ids: Vec<NodeId> = graph.ids().collect();
+        for id in ids {
+            if let Node::Actuator(a) = graph.get_mut(id) {
+                if a.get_joint_name() == "" {
+                    continue;
+                }
+                let joint = name_index.get(&("joint".to_string(), a.get_joint_name().to_string())).
+                    ok_or(ModelLoadError::UnknownReference {
+                        element: if a.get_name() == "" {
+                            a.get_node_name()
+                        } else {
+                            format!("{} '{}'", a.get_node_name(), a.get_name())
+                        },
+                        kind: "joint".to_string(),
+                        name: a.get_joint_name().to_string()
+                    })?;
+                a.set_joint(*joint);
+            }
+        }
+        Ok(())
+    }
+
+    /* All actuators, in the order they were declared */
+    pub fn actuators(&self) -> Vec<NodeId> {
+        let mut actuators = Vec::new();
+        for section in &self.raw_sections {
+            for id in depth_first(&self.graph, *section) {
+                if let Node::Actuator(_) = self.graph.get(id) {
+                    actuators.push(id);
+                }
+            }
+        }
+        actuators
+    }
+
+    /* Actuator names are unique across actuator types, so a name alone finds one */
+    pub fn find_actuator(&self, name: &str) -> Option<NodeId> {
+        self.actuators().into_iter().find(|id| self.graph.get(*id).get_name() == name)
+    }
+
+    /* Sets an actuator's control input, see Actuator::set_ctrl */
+    pub fn set_ctrl(&mut self, actuator: NodeId, ctrl: f32) {
+        if let Node::Actuator(a) = self.graph.get_mut(actuator) {
+            a.set_ctrl(ctrl);
+        }
+    }
+
+    /* Joints below root in MuJoCo's order: bodies depth first, each body's 
+    joints in the order they were declared */
+    fn joints_in(graph: &SceneGraph, root: NodeId) -> Vec<NodeId> {
+        let mut joints = Vec::new();
+        for body in depth_first(graph, root) {
+            for child in graph.children(body) {
+                if let Node::Joint(_) = graph.get(*child) {
+                    joints.push(*child);
+                }
+            }
+        }
+        joints
+    }
+
+    /* All joints, ordered as their values are in qpos */
+    pub fn joints(&self) -> Vec<NodeId> {
+        self.world_body.map_or(Vec::new(), |w| Self::joints_in(&self.graph, w))
+    }
+
+    /* Number of joint position values */
+    pub fn nq(&self) -> usize {
+        self.joints().iter().map(|id| {
+            match self.graph.get(*id) {
+                Node::Joint(j) => j.nq(),
+                _ => 0
+            }
+        }).sum()
+    }
+
+    /* Number of degrees of freedom, i.e. of joint velocities */
+    pub fn nv(&self) -> usize {
+        self.joints().iter().map(|id| {
+            match self.graph.get(*id) {
+                Node::Joint(j) => j.nv(),
+                _ => 0
+            }
+        }).sum()
+    }
+
+    pub fn get_qpos(&self) -> Vec<f32> {
+        let mut qpos = Vec::new();
+        for id in self.joints() {
+            if let Node::Joint(j) = self.graph.get(id) {
+                qpos.extend_from_slice(j.get_qpos());
+            }
+        }
+        qpos
+    }
+
+    /* Sets the joint positions & moves the bodies accordingly. Joints past 
+    the end of a short qpos keep their current position, limited hinges & 
+    slides are kept within their range as in set_joint_qpos */
+    pub fn set_qpos(&mut self, qpos: &[f32]) {
+        let mut offset = 0;
+        for id in self.joints() {
+            if let Node::Joint(j) = self.graph.get_mut(id) {
+                if offset + j.nq() > qpos.len() {
+                    break;
+                }
+                let mut joint_qpos = qpos[offset..offset + j.nq()].to_vec();
+                if j.nq() == 1 {
+                    joint_qpos[0] = j.clamp_to_range(joint_qpos[0]);
+                }
+                j.set_qpos(&joint_qpos);
+                offset += j.nq();
+            }
+        }
+        self.update_joint_motions();
+    }
+
+    /* Sets a single joint's position, e.g. a hinge angle in radians. Hinges 
+    & slides are kept within their range when limited */
+    pub fn set_joint_qpos(&mut self, joint: NodeId, qpos: &[f32]) {
+        if let Node::Joint(j) = self.graph.get_mut(joint) {
+            if qpos.len() < j.nq() {
+                return;
+            }
+            let mut qpos = qpos.to_vec();
+            if j.nq() == 1 {
+                qpos[0] = j.clamp_to_range(qpos[0]);
+            }
+            j.set_qpos(&qpos);
+        }
+        self.update_joint_motions();
+    }
+
+    /* Puts every joint back at the position the model was defined in */
+    pub fn reset_qpos(&mut self) {
+        let mut qpos = Vec::new();
+        for id in self.joints() {
+            if let (Node::Joint(j), Some(Node::Body(b))) = (self.graph.get(id), 
+                    self.graph.parent(id).map(|p| self.graph.get(p))) {
+                qpos.extend(j.qpos0(&b.get_model_pose().isometry));
+            }
+        }
+        self.set_qpos(&qpos);
+    }
+
+    /* Recomputes each body's displacement from its joints' current positions */
+    fn update_joint_motions(&mut self) {
+        let bodies: Vec<NodeId> = self.world_body.map_or(Vec::new(), |w| depth_first(&self.graph, w).collect());
+        for body in bodies {
+            let Node::Body(b) = self.graph.get(body) else {
+                continue;
+            };
+            let body_pose = b.get_model_pose().isometry;
+            let mut motion = Isometry3::identity();
+            for child in self.graph.children(body) {
+                if let Node::Joint(j) = self.graph.get(*child) {
+                    motion = motion * j.motion(&body_pose);
+                }
+            }
+            if let Node::Body(b) = self.graph.get_mut(body) {
+                b.set_joint_motion(motion);
+            }
+        }
+    }
+
+    /* Moves the model to a keyframe: its joint positions & actuator controls. 
+    Values the keyframe doesn't give are reset to the model's defaults */
+    pub fn apply_keyframe(&mut self, index: usize) {
+        let Some(keyframe) = self.keyframes.get(index).cloned() else {
+            return;
+        };
+        if keyframe.qpos.is_empty() {
+            self.reset_qpos();
+        } else {
+            self.set_qpos(&keyframe.qpos);
+        }
+        for (i, id) in self.actuators().into_iter().enumerate() {
+            self.set_ctrl(id, keyframe.ctrl.get(i).copied().unwrap_or(0.0));
         }
     }
 
@@ -339,3 +1314,261 @@ impl Model {
         mjModel {}
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use std::path::PathBuf;
+    use std::sync::OnceLock;
+    use super::*;
+
+    /* Loads a model written out to a temporary file, name keeps the files of different tests apart */
+    pub fn load_str(name: &str, xml: &str) -> Result<Model,ModelLoadError> {
+        load_str_with_options(name, xml, &MeshProcessing::default())
+    }
+
+    /* Same as load_str, running mesh_processing over the model's meshes */
+    pub fn load_str_with_options(name: &str, xml: &str, mesh_processing: &MeshProcessing) -> Result<Model,ModelLoadError> {
+        let path = std::env::temp_dir().join(format!("assembly-theory-{}-{}.xml", name, std::process::id()));
+        std::fs::write(&path, xml).expect("Expected the test model to be written");
+        let model = Model::load_model_with_options(path.clone(), mesh_processing, |_| ());
+        let _ = std::fs::remove_file(&path);
+        model
+    }
+
+    /* The UR5e model with its meshes, copied to a temporary directory. Meshes
+    it lists that aren't in the checkout (upperarm_3.obj) are stood in for by
+    a single triangle, the model is only used for its kinematics */
+    pub fn ur5e_path() -> PathBuf {
+        static PATH: OnceLock<PathBuf> = OnceLock::new();
+        PATH.get_or_init(|| {
+            let source = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
+            let dir = std::env::temp_dir().join(format!("assembly-theory-ur5e-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("assets")).expect("Expected the fixture directory to be created");
+            let xml = std::fs::read_to_string(source.join("ur5e.xml")).expect("Expected ur5e.xml to be readable");
+            for entry in std::fs::read_dir(source.join("assets")).expect("Expected the asset directory") {
+                let entry = entry.expect("Expected the asset directory to be readable");
+                std::fs::copy(entry.path(), dir.join("assets").join(entry.file_name()))
+                    .expect("Expected the asset to be copied");
+            }
+            for file in xml.split("file=\"").skip(1).filter_map(|rest| rest.split('"').next()) {
+                let path = dir.join("assets").join(file);
+                if !path.exists() {
+                    std::fs::write(&path, "v 0 0 0\nv 0.01 0 0\nv 0 0.01 0\nf 1 2 3\n")
+                        .expect("Expected the stand-in mesh to be written");
+                }
+            }
+            std::fs::write(dir.join("ur5e.xml"), xml).expect("Expected ur5e.xml to be copied");
+            dir.join("ur5e.xml")
+        }).clone()
+    }
+
+    #[test]
+    fn elements_with_closing_tags_load_like_empty_ones() {
+        let model = load_str("closing-tags", r#"<mujoco model="tags">
+            <compiler angle="radian"></compiler>
+            <worldbody>
+                <body name="arm">
+                    <joint name="hinge" type="hinge"></joint>
+                    <geom name="ball" type="sphere" size="0.1"></geom>
+                    <geom name="hull" type="mesh" mesh="tetra"/>
+                </body>
+            </worldbody>
+            <asset>
+                <texture name="grid" type="2d" builtin="checker" width="8" height="8"></texture>
+                <material name="paint" texture="grid"></material>
+                <mesh name="tetra" vertex="0 0 0  1 0 0  0 1 0  0 0 1"></mesh>
+            </asset>
+        </mujoco>"#).expect("Expected the model to load");
+        let arm = model.find_by_name("body", "arm").expect("Expected the arm");
+        for (kind, name) in [("joint", "hinge"), ("geom", "ball"), ("geom", "hull")] {
+            let id = model.find_by_name(kind, name).expect("Expected the element");
+            assert_eq!(model.graph.parent(id), Some(arm), "{} {}", kind, name);
+        }
+        let assets = model.asset_manager.expect("Expected the assets");
+        let kinds: Vec<String> = model.graph.children(assets).iter().map(|id| model.graph.get(*id).get_node_name()).collect();
+        assert_eq!(kinds, vec!["texture", "material", "mesh"]);
+        assert_eq!(model.graph.get(model.compiler.expect("Expected the compiler")).get_set_attributes_list().get("angle"),
+            Some(&"radian".to_string()));
+        assert_eq!(model.nq(), 1);
+    }
+
+    #[test]
+    fn root_attributes_and_section_order_are_kept() {
+        let model = load_str("section-order", r#"<mujoco model="ordered">
+            <option timestep="0.01"/>
+            <worldbody>
+                <body name="arm"><joint name="hinge"/></body>
+            </worldbody>
+            <compiler angle="radian"/>
+            <actuator><motor name="drive" joint="hinge"/></actuator>
+        </mujoco>"#).expect("Expected the model to load");
+        assert_eq!(model.attributes.get("model"), Some(&"ordered".to_string()));
+        let mjcf = model.to_mjcf();
+        assert!(mjcf.starts_with("<mujoco model=\"ordered\">"), "{}", mjcf);
+        let order: Vec<usize> = ["<option", "<worldbody", "<compiler", "<actuator"].iter()
+            .map(|tag| mjcf.find(tag).expect("Expected the section to be written")).collect();
+        assert!(order.windows(2).all(|pair| pair[0] < pair[1]), "{}", mjcf);
+    }
+
+    #[test]
+    fn obj_diffuse_maps_are_drawn_with_their_texture_coordinates() {
+        let dir = std::env::temp_dir().join(format!("assembly-theory-diffuse-map-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("meshes")).expect("Expected the mesh directory to be created");
+        std::fs::write(dir.join("meshes/tile.obj"), "mtllib tile.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\n\
+            vt 0 0\nvt 1 0\nvt 0 1\nusemtl wood\nf 1/1 2/2 3/3\n").expect("Expected the OBJ to be written");
+        std::fs::write(dir.join("meshes/tile.mtl"), "newmtl wood\nKd 1 0 0\nmap_Kd wood.png\n").
+            expect("Expected the material library to be written");
+        image::RgbImage::from_pixel(2, 2, image::Rgb([200, 100, 50])).save(dir.join("meshes/wood.png")).
+            expect("Expected the diffuse map to be written");
+        std::fs::write(dir.join("tile.xml"), r#"<mujoco>
+            <compiler meshdir="meshes"/>
+            <asset><mesh file="tile.obj"/></asset>
+            <worldbody><geom type="mesh" mesh="tile"/></worldbody>
+        </mujoco>"#).expect("Expected the model to be written");
+        let model = Model::load_model(dir.join("tile.xml")).expect("Expected the model to load");
+        let textures = model.get_textures_data();
+        assert_eq!(textures.len(), 1);
+        assert_eq!(textures[0].get_name(), "wood.png");
+        assert!(matches!(textures[0].t_type, crate::node::TextureType::TwoD));
+        assert_eq!(textures[0].texture_image_dimensions, (2, 2));
+        assert_eq!(textures[0].file_data.len(), 2 * 2 * 3);
+
+        let poses = crate::kinematics::forward_kinematics(&model, &[]);
+        let meshes = crate::kinematics::geometries(&model, &poses).drawable_meshes;
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].texture_data.as_ref().map(|t| t.get_name()), Some("wood.png".to_string()));
+        let filling = meshes[0].vertices_filling.as_ref().expect("Expected a filling");
+        let uvs: Vec<(f32,f32)> = filling.iter().map(|f| match f {
+            crate::drawable::VertexFilling::TwoDTextureCoordinates(u, v) => (*u, *v),
+            other => panic!("Expected texture coordinates, got {:?}", other)
+        }).collect();
+        assert_eq!(uvs.len(), 3);
+        assert!(uvs.contains(&(1.0, 1.0)) && uvs.contains(&(0.0, 0.0)), "{:?}", uvs);
+        std::fs::remove_dir_all(&dir).expect("Expected the test files to be removed");
+    }
+
+    #[test]
+    fn actuator_types_share_their_class_default_and_names() {
+        let model = load_str("actuator-defaults", r#"<mujoco>
+            <default>
+                <default class="arm">
+                    <general ctrlrange="-1 1" gear="3"/>
+                    <motor gear="5"/>
+                    <default class="wrist">
+                        <position ctrlrange="-2 2"/>
+                    </default>
+                </default>
+            </default>
+            <worldbody>
+                <body name="link"><joint name="hinge"/><joint name="twist"/><joint name="roll"/></body>
+            </worldbody>
+            <actuator>
+                <general name="drive" class="arm" joint="hinge"></general>
+                <position name="steer" class="arm" joint="twist"/>
+                <velocity name="spin" class="wrist" joint="roll"/>
+            </actuator>
+        </mujoco>"#).expect("Expected the model to load");
+        let actuator = |name: &str, kind: &str| match model.graph.get(model.find_by_name(kind, name).expect("Expected the actuator")) {
+            Node::Actuator(a) => a.clone(),
+            _ => panic!("Expected {} to be an actuator", name)
+        };
+        for (name, kind) in [("drive", "general"), ("steer", "position")] {
+            assert_eq!(actuator(name, kind).get_gear(), 5.0, "{}", name);
+            assert_eq!(actuator(name, kind).get_ctrl_range(), Some((-1.0, 1.0)), "{}", name);
+        }
+        assert_eq!(actuator("spin", "velocity").get_gear(), 5.0);
+        assert_eq!(actuator("spin", "velocity").get_ctrl_range(), Some((-2.0, 2.0)));
+
+        let duplicate = load_str("actuator-names", r#"<mujoco>
+            <worldbody><body name="link"><joint name="hinge"/></body></worldbody>
+            <actuator>
+                <motor name="drive" joint="hinge"/>
+                <position name="drive" joint="hinge"/>
+            </actuator>
+        </mujoco>"#);
+        assert!(matches!(duplicate, Err(ModelLoadError::DuplicateName { ref kind, ref name, .. }) 
+            if kind == "actuator" && name == "drive"), "{:?}", duplicate.err());
+    }
+
+    #[test]
+    fn geom_assets_without_an_asset_section_are_reported() {
+        for (kind, geom) in [
+            ("hfield", r#"<geom name="floor" type="hfield" hfield="terrain"/>"#),
+            ("mesh", r#"<geom name="part" type="mesh" mesh="terrain"/>"#),
+            ("material", r#"<geom name="ball" size="0.1" material="terrain"/>"#)
+        ] {
+            let model = load_str("geom-no-assets", &format!("<mujoco><worldbody>{}</worldbody></mujoco>", geom));
+            assert!(matches!(model, Err(ModelLoadError::MissingAsset { kind: ref k, ref name, .. })
+                if k == kind && name == "terrain"), "{}: {:?}", kind, model.err());
+        }
+    }
+
+    #[test]
+    fn set_qpos_keeps_limited_joints_in_range() {
+        let mut model = load_str("qpos-range", r#"<mujoco>
+            <compiler angle="radian"/>
+            <worldbody>
+                <body name="arm">
+                    <joint name="hinge" range="-0.5 0.5" limited="true"/>
+                    <joint name="slide" type="slide" range="0 0.1"/>
+                </body>
+            </worldbody>
+        </mujoco>"#).expect("Expected the model to load");
+        model.set_qpos(&[2.0, 1.0]);
+        //Ranges limit the joint unless told otherwise
+        assert_eq!(model.get_qpos(), vec![0.5, 0.1]);
+        let hinge = model.find_by_name("joint", "hinge").expect("Expected the hinge");
+        model.set_joint_qpos(hinge, &[-2.0]);
+        assert_eq!(model.get_qpos(), vec![-0.5, 0.1]);
+    }
+
+    #[test]
+    fn compiler_directories_resolve_assets_next_to_the_model() {
+        let dir = std::env::temp_dir().join(format!("assembly-theory-compiler-dirs-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("parts")).expect("Expected the mesh directory to be created");
+        std::fs::create_dir_all(dir.join("images")).expect("Expected the texture directory to be created");
+        std::fs::write(dir.join("parts/tri.obj"), "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").
+            expect("Expected the OBJ to be written");
+        image::RgbImage::from_pixel(2, 2, image::Rgb([10, 20, 30])).save(dir.join("images/paint.png")).
+            expect("Expected the texture to be written");
+        //The compiler is written with a closing tag, it must still drive the asset paths
+        std::fs::write(dir.join("dirs.xml"), r#"<mujoco>
+            <compiler meshdir="parts" texturedir="images"></compiler>
+            <asset>
+                <mesh file="tri.obj"/>
+                <texture name="paint" type="2d" file="paint.png"/>
+            </asset>
+            <worldbody><geom type="mesh" mesh="tri"/></worldbody>
+        </mujoco>"#).expect("Expected the model to be written");
+        let model = Model::load_model(dir.join("dirs.xml")).expect("Expected the model to load");
+        let textures = model.get_textures_data();
+        assert_eq!(textures.len(), 1);
+        assert_eq!(textures[0].texture_image_dimensions, (2, 2));
+        let poses = crate::kinematics::forward_kinematics(&model, &[]);
+        assert_eq!(crate::kinematics::geometries(&model, &poses).drawable_meshes.len(), 1);
+        std::fs::remove_dir_all(&dir).expect("Expected the test files to be removed");
+    }
+
+    #[test]
+    fn files_are_included_only_once() {
+        let dir = std::env::temp_dir().join(format!("assembly-theory-includes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("Expected the include directory to be created");
+        std::fs::write(dir.join("part.xml"), r#"<mujoco><worldbody><body name="part"/></worldbody></mujoco>"#).
+            expect("Expected the included file to be written");
+        std::fs::write(dir.join("other.xml"), r#"<mujoco><include file="part.xml"/></mujoco>"#).
+            expect("Expected the included file to be written");
+        for (name, includes) in [("twice", vec!["part.xml", "part.xml"]), ("nested", vec!["part.xml", "other.xml"])] {
+            let includes: String = includes.iter().map(|file| format!(r#"<include file="{}"/>"#, file)).collect();
+            std::fs::write(dir.join(format!("{}.xml", name)), format!("<mujoco>{}</mujoco>", includes)).
+                expect("Expected the model to be written");
+            let model = Model::load_model(dir.join(format!("{}.xml", name)));
+            assert!(matches!(model, Err(ModelLoadError::DuplicateInclude { ref path, .. }) if path.ends_with("part.xml")),
+                "{}: {:?}", name, model.err());
+        }
+        std::fs::write(dir.join("once.xml"), r#"<mujoco><include file="other.xml"/></mujoco>"#).
+            expect("Expected the model to be written");
+        let model = Model::load_model(dir.join("once.xml")).expect("Expected the model to load");
+        assert!(model.find_by_name("body", "part").is_some());
+        std::fs::remove_dir_all(&dir).expect("Expected the test files to be removed");
+    }
+}
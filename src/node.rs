@@ -1,27 +1,29 @@
-use std::rc::{Rc,Weak};
-use std::cell::RefCell;
 use std::str::FromStr;
-use nalgebra::{DMatrix, Matrix3};
+use nalgebra::{DMatrix, Matrix3, Quaternion, UnitQuaternion, Unit, Vector3, Rotation3, Translation3, Isometry3};
 use qhull::{Qh};
 use std::collections::HashMap;
+use std::path::{Path,PathBuf};
 use crate::drawable::{MeshCollection,VertexFilling};
-use tobj;
-use ahash;
+use crate::primitives;
+use crate::mesh_io;
+use crate::mesh_ops;
+use crate::transform::{self, Transform};
+use crate::scene::{NodeId, SceneGraph};
+use crate::model::SourceLocation;
 
 pub trait NodeType {
-    fn add_attr(&mut self, key: String, value: String) -> bool;
-    fn add_attrs(&mut self,attrs: Vec<(String,String)>) -> ();
-    fn add_child(&mut self, child: Node) -> Rc<RefCell<Node>>;
-    fn add_child_ref(&mut self, child: Rc<RefCell<Node>>) -> Rc<RefCell<Node>>;
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError>;
+    fn add_attrs(&mut self,attrs: Vec<(String,String)>) -> Result<(),AttributeError>;
     fn get_node_name(&self) -> String;
-    fn get_children(&self) -> Vec<Rc<RefCell<Node>>>;
     fn get_name(&self) -> String;
+    /* Attributes as they were set on the element (or applied from defaults), 
+    including ones the node doesn't interpret, so they're kept when saving */
     fn get_set_attributes_list(&self) -> HashMap<String,String>;
-    fn set_parent(&mut self, parent: Weak<RefCell<Node>>) -> ();
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>>;
 
-    fn apply_assets(&mut self,asset_manager: Rc<RefCell<Node>>) {
-        println!("Assets not allowed on this node type.")
+    /* Links references to assets (meshes, materials) held in the asset_manager 
+    node of graph. Only called on nodes outside the asset section */
+    fn apply_assets(&mut self, graph: &SceneGraph, asset_manager: NodeId) -> Result<(),MissingAssetRef> {
+        Ok(())
     }
 
     fn get_class(&self) -> String {
@@ -29,7 +31,7 @@ pub trait NodeType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Node {
     Geom(Geom),
     WorldBody(WorldBody),
@@ -39,16 +41,26 @@ pub enum Node {
     Assets(AssetsManager),
     Mesh(Mesh),
     Texture(Texture),
-    Material(Material)
+    Material(Material),
+    Compiler(Compiler),
+    Raw(RawElement),
+    Actuator(Actuator),
+    Inertial(Inertial),
+    Hfield(Hfield)
 }
 
-#[derive(Debug)]
+/* Orientation as written in the MJCF. Angles are kept in the units they 
+were written in until resolved against the compiler's angle & eulerseq */
+#[derive(Debug, Clone)]
 pub enum Orientation {
-    Euler(Euler),
-    AxisAngle
+    Quat(Quaternion<f32>),
+    AxisAngle(Vector3<f32>, f32),
+    XYAxes(Vector3<f32>, Vector3<f32>),
+    ZAxis(Vector3<f32>),
+    Euler(Euler)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum GeomType {
     Plane, 
     Hfield, 
@@ -61,7 +73,7 @@ pub enum GeomType {
     Sdf
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum JointType {
     Free,
     Ball,
@@ -69,6 +81,26 @@ pub enum JointType {
     Hinge
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActuatorType {
+    Motor,
+    General,
+    Position,
+    Velocity
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GainType {
+    Fixed,
+    Affine
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BiasType {
+    None,
+    Affine
+}
+
 #[derive(Debug, Clone)]
 pub enum TextureType {
     TwoD,
@@ -76,15 +108,181 @@ pub enum TextureType {
     Skybox
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Euler {
     x: f32, 
     y: f32,
     z: f32
 }
 
+//Attributes that set an element's orientation; MJCF allows only one of them per element
+pub const ORIENTATION_ATTRS: [&str; 5] = ["quat", "axisangle", "xyaxes", "zaxis", "euler"];
+
+impl Orientation {
+    pub fn identity() -> Self {
+        Orientation::Quat(Quaternion::identity())
+    }
+
+    /* Rejects an element's attributes when they set its orientation more than once */
+    pub fn check_single(attrs: &[(String,String)]) -> Result<(),AttributeError> {
+        let mut keys = attrs.iter().filter(|(key, _)| ORIENTATION_ATTRS.contains(&key.as_str()));
+        if let (Some((first, _)), Some((key, value))) = (keys.next(), keys.next()) {
+            return Err(AttributeError::new(key, value, format!("can't be combined with {}", first)));
+        }
+        Ok(())
+    }
+
+    pub fn from_attr(key: &str, value: &str) -> Result<Self,AttributeError> {
+        match key {
+            "quat" => {
+                let values = parse_attr_values::<f32>(key, value, 4..=4)?;
+                let q = Quaternion::new(values[0], values[1], values[2], values[3]);
+                if q.norm() == 0.0 {
+                    return Err(AttributeError::new(key, value, "quaternion has zero norm".to_string()));
+                }
+                Ok(Orientation::Quat(q))
+            },
+            "axisangle" => {
+                let values = parse_attr_values::<f32>(key, value, 4..=4)?;
+                let axis = Vector3::new(values[0], values[1], values[2]);
+                if axis.norm() == 0.0 {
+                    return Err(AttributeError::new(key, value, "rotation axis has zero length".to_string()));
+                }
+                Ok(Orientation::AxisAngle(axis, values[3]))
+            },
+            "xyaxes" => {
+                let values = parse_attr_values::<f32>(key, value, 6..=6)?;
+                let x = Vector3::new(values[0], values[1], values[2]);
+                let y = Vector3::new(values[3], values[4], values[5]);
+                //y only needs a component orthogonal to x, it gets orthogonalized when resolving
+                if x.norm() == 0.0 || x.cross(&y).norm() == 0.0 {
+                    return Err(AttributeError::new(key, value, "axes are zero or parallel".to_string()));
+                }
+                Ok(Orientation::XYAxes(x, y))
+            },
+            "zaxis" => {
+                let values = parse_attr_values::<f32>(key, value, 3..=3)?;
+                let z = Vector3::new(values[0], values[1], values[2]);
+                if z.norm() == 0.0 {
+                    return Err(AttributeError::new(key, value, "axis has zero length".to_string()));
+                }
+                Ok(Orientation::ZAxis(z))
+            },
+            "euler" => {
+                let values = parse_attr_values::<f32>(key, value, 3..=3)?;
+                Ok(Orientation::Euler(Euler {x: values[0], y: values[1], z: values[2]}))
+            },
+            _ => Err(AttributeError::new(key, value, "not an orientation attribute".to_string()))
+        }
+    }
+
+    /* Converts to a unit quaternion using the compiler's angle units 
+    (for axisangle & euler) & euler rotation sequence */
+    pub fn to_quaternion(&self, compiler: &Compiler) -> UnitQuaternion<f32> {
+        match self {
+            Orientation::Quat(q) => {
+                UnitQuaternion::from_quaternion(*q)
+            },
+            Orientation::AxisAngle(axis, angle) => {
+                UnitQuaternion::from_axis_angle(&Unit::new_normalize(*axis), 
+                    compiler.angle_to_radians(*angle))
+            },
+            Orientation::XYAxes(x, y) => {
+                let x = x.normalize();
+                let y = (y - x * x.dot(y)).normalize();
+                let z = x.cross(&y);
+                let rotation_m = Matrix3::from_columns(&[x, y, z]);
+                UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(rotation_m))
+            },
+            Orientation::ZAxis(z) => {
+                //Minimal rotation taking the frame's z axis onto the given one
+                UnitQuaternion::rotation_between(&Vector3::z(), z).unwrap_or(
+                    UnitQuaternion::from_axis_angle(&Vector3::x_axis(), std::f32::consts::PI))
+            },
+            Orientation::Euler(e) => {
+                let angles = [e.x, e.y, e.z];
+                let mut rotation = UnitQuaternion::identity();
+                for (axis_c, angle) in compiler.get_eulerseq().chars().zip(angles) {
+                    let axis = match axis_c.to_ascii_lowercase() {
+                        'x' => Vector3::x_axis(),
+                        'y' => Vector3::y_axis(),
+                        _ => Vector3::z_axis()
+                    };
+                    let applied = UnitQuaternion::from_axis_angle(&axis, compiler.angle_to_radians(angle));
+                    //Lower case axes move with the frame, upper case axes stay fixed
+                    if axis_c.is_ascii_lowercase() {
+                        rotation = rotation * applied;
+                    } else {
+                        rotation = applied * rotation;
+                    }
+                }
+                rotation
+            }
+        }
+    }
+}
+
+/* Raised by add_attr when an attribute value can't be interpreted,
+e.g. a non-numeric pos or the wrong number of rgba components */
+#[derive(Debug, Clone)]
+pub struct AttributeError {
+    pub key: String,
+    pub value: String,
+    pub reason: String
+}
+
+impl AttributeError {
+    pub fn new(key: &str, value: &str, reason: String) -> Self {
+        AttributeError {
+            key: key.to_string(),
+            value: value.to_string(),
+            reason: reason
+        }
+    }
+}
+
+/* Raised by apply_assets when a node references an asset
+(mesh, material) that the asset manager doesn't hold */
+#[derive(Debug, Clone)]
+pub struct MissingAssetRef {
+    pub kind: String,
+    pub name: String
+}
+
+/* Parses a whitespace separated list of values for the attribute `key`,
+checking that the number of values lies within `count` */
+pub fn parse_attr_values<T: FromStr>(key: &str, value: &str,
+        count: std::ops::RangeInclusive<usize>) -> Result<Vec<T>,AttributeError> {
+    let mut values = Vec::new();
+    for token in value.split_whitespace() {
+        match token.parse::<T>() {
+            Ok(v) => values.push(v),
+            Err(_) => {
+                return Err(AttributeError::new(key, value,
+                    format!("'{}' is not a valid number", token)));
+            }
+        }
+    }
+    if !count.contains(&values.len()) {
+        let expected = if count.start() == count.end() {
+            format!("{}", count.start())
+        } else {
+            format!("{} to {}", count.start(), count.end())
+        };
+        return Err(AttributeError::new(key, value,
+            format!("expected {} values, got {}", expected, values.len())));
+    }
+    Ok(values)
+}
+
+pub fn asset_name_from_file(file: &str) -> String {
+    Path::new(file).file_stem().map_or("".to_string(), |stem| {
+        stem.to_string_lossy().to_string()
+    })
+}
+
 impl NodeType for Node {
-    fn add_attr(&mut self, name: String, value: String) -> bool {
+    fn add_attr(&mut self, name: String, value: String) -> Result<bool,AttributeError> {
         match self {
             Node::Geom(e) => {
                 e.add_attr(name,value)
@@ -112,11 +310,26 @@ impl NodeType for Node {
             },
             Node::Material(e) => {
                 e.add_attr(name,value)
+            },
+            Node::Compiler(e) => {
+                e.add_attr(name,value)
+            },
+            Node::Raw(e) => {
+                e.add_attr(name,value)
+            },
+            Node::Actuator(e) => {
+                e.add_attr(name,value)
+            },
+            Node::Inertial(e) => {
+                e.add_attr(name,value)
+            },
+            Node::Hfield(e) => {
+                e.add_attr(name,value)
             }
         }
     }
 
-    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> () {
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
         match self {
             Node::Geom(e) => {
                 e.add_attrs(attrs)
@@ -144,70 +357,21 @@ impl NodeType for Node {
             },
             Node::Material(e) => {
                 e.add_attrs(attrs)
-            }
-        }
-    }
-
-    fn add_child(&mut self, child: Node) -> Rc<RefCell<Node>> {
-        match self {
-            Node::Geom(e) => {
-                e.add_child(child)
-            },
-            Node::WorldBody(e) => {
-                e.add_child(child)
-            },
-            Node::Body(e) => {
-                e.add_child(child)
-            },
-            Node::Joint(e) => {
-                e.add_child(child)
-            },
-            Node::Defaults(e) => {
-                e.add_child(child)
-            },
-            Node::Assets(e) => {
-                e.add_child(child)
-            },
-            Node::Mesh(e) => {
-                e.add_child(child)
-            },
-            Node::Texture(e) => {
-                e.add_child(child)
-            },
-            Node::Material(e) => {
-                e.add_child(child)
-            }
-        }
-    }
-
-    fn add_child_ref(&mut self, child: Rc<RefCell<Node>>) -> Rc<RefCell<Node>> {
-        match self {
-            Node::Geom(e) => {
-                e.add_child_ref(child)
-            },
-            Node::WorldBody(e) => {
-                e.add_child_ref(child)
-            },
-            Node::Body(e) => {
-                e.add_child_ref(child)
             },
-            Node::Joint(e) => {
-                e.add_child_ref(child)
-            },
-            Node::Defaults(e) => {
-                e.add_child_ref(child)
+            Node::Compiler(e) => {
+                e.add_attrs(attrs)
             },
-            Node::Assets(e) => {
-                e.add_child_ref(child)
+            Node::Raw(e) => {
+                e.add_attrs(attrs)
             },
-            Node::Mesh(e) => {
-                e.add_child_ref(child)
+            Node::Actuator(e) => {
+                e.add_attrs(attrs)
             },
-            Node::Texture(e) => {
-                e.add_child_ref(child)
+            Node::Inertial(e) => {
+                e.add_attrs(attrs)
             },
-            Node::Material(e) => {
-                e.add_child_ref(child)
+            Node::Hfield(e) => {
+                e.add_attrs(attrs)
             }
         }
     }
@@ -240,38 +404,21 @@ impl NodeType for Node {
             },
             Node::Material(e) => {
                 e.get_node_name()
-            }
-        }
-    }
-
-    fn set_parent(&mut self, parent: Weak<RefCell<Node>>) -> () {
-        match self {
-            Node::Geom(e) => {
-                e.set_parent(parent)
-            },
-            Node::WorldBody(e) => {
-                e.set_parent(parent)
-            },
-            Node::Body(e) => {
-                e.set_parent(parent)
-            },
-            Node::Joint(e) => {
-                e.set_parent(parent)
             },
-            Node::Defaults(e) => {
-                e.set_parent(parent)
+            Node::Compiler(e) => {
+                e.get_node_name()
             },
-            Node::Assets(e) => {
-                e.set_parent(parent)
+            Node::Raw(e) => {
+                e.get_node_name()
             },
-            Node::Mesh(e) => {
-                e.set_parent(parent)
+            Node::Actuator(e) => {
+                e.get_node_name()
             },
-            Node::Texture(e) => {
-                e.set_parent(parent)
+            Node::Inertial(e) => {
+                e.get_node_name()
             },
-            Node::Material(e) => {
-                e.set_parent(parent)
+            Node::Hfield(e) => {
+                e.get_node_name()
             }
         }
     }
@@ -304,70 +451,68 @@ impl NodeType for Node {
             },
             Node::Material(e) => {
                 e.get_class()
-            }
-        }
-    }
-
-    fn get_children(&self) -> Vec<Rc<RefCell<Node>>> {
-        match self {
-            Node::Geom(e) => {
-                e.get_children()
             },
-            Node::WorldBody(e) => {
-                e.get_children()
-            },
-            Node::Body(e) => {
-                e.get_children()
-            },
-            Node::Joint(e) => {
-                e.get_children()
-            },
-            Node::Defaults(e) => {
-                e.get_children()
+            Node::Compiler(e) => {
+                e.get_class()
             },
-            Node::Assets(e) => {
-                e.get_children()
+            Node::Raw(e) => {
+                e.get_class()
             },
-            Node::Mesh(e) => {
-                e.get_children()
+            Node::Actuator(e) => {
+                e.get_class()
             },
-            Node::Texture(e) => {
-                e.get_children()
+            Node::Inertial(e) => {
+                e.get_class()
             },
-            Node::Material(e) => {
-                e.get_children()
+            Node::Hfield(e) => {
+                e.get_class()
             }
         }
     }
 
-    fn apply_assets(&mut self,asset_manager: Rc<RefCell<Node>>) {
+    fn apply_assets(&mut self, graph: &SceneGraph, asset_manager: NodeId) -> Result<(),MissingAssetRef> {
         match self {
             Node::Geom(e) => {
-                e.apply_assets(asset_manager)
+                e.apply_assets(graph, asset_manager)
             },
             Node::WorldBody(e) => {
-                e.apply_assets(asset_manager)
+                e.apply_assets(graph, asset_manager)
             },
             Node::Body(e) => {
-                e.apply_assets(asset_manager)
+                e.apply_assets(graph, asset_manager)
             },
             Node::Joint(e) => {
-                e.apply_assets(asset_manager)
+                e.apply_assets(graph, asset_manager)
             },
             Node::Defaults(e) => {
-                e.apply_assets(asset_manager)
+                e.apply_assets(graph, asset_manager)
             },
             Node::Assets(e) => {
-                e.apply_assets(asset_manager)
+                e.apply_assets(graph, asset_manager)
             },
             Node::Mesh(e) => {
-                e.apply_assets(asset_manager)
+                e.apply_assets(graph, asset_manager)
             },
             Node::Texture(e) => {
-                e.apply_assets(asset_manager)
+                e.apply_assets(graph, asset_manager)
             },
             Node::Material(e) => {
-                e.apply_assets(asset_manager)
+                e.apply_assets(graph, asset_manager)
+            },
+            Node::Compiler(e) => {
+                e.apply_assets(graph, asset_manager)
+            },
+            Node::Raw(e) => {
+                e.apply_assets(graph, asset_manager)
+            },
+            Node::Actuator(e) => {
+                e.apply_assets(graph, asset_manager)
+            },
+            Node::Inertial(e) => {
+                e.apply_assets(graph, asset_manager)
+            },
+            Node::Hfield(e) => {
+                e.apply_assets(graph, asset_manager)
             }
         }
     }
@@ -400,6 +545,21 @@ impl NodeType for Node {
             },
             Node::Material(e) => {
                 e.get_name()
+            },
+            Node::Compiler(e) => {
+                e.get_name()
+            },
+            Node::Raw(e) => {
+                e.get_name()
+            },
+            Node::Actuator(e) => {
+                e.get_name()
+            },
+            Node::Inertial(e) => {
+                e.get_name()
+            },
+            Node::Hfield(e) => {
+                e.get_name()
             }
         }
     }
@@ -432,185 +592,328 @@ impl NodeType for Node {
             },
             Node::Material(e) => {
                 e.get_set_attributes_list()
-            }
-        }
-    }
-
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>> {
-        match self {
-            Node::Geom(e) => {
-                e.get_parent()
-            },
-            Node::WorldBody(e) => {
-                e.get_parent()
-            },
-            Node::Body(e) => {
-                e.get_parent()
-            },
-            Node::Joint(e) => {
-                e.get_parent()
             },
-            Node::Defaults(e) => {
-                e.get_parent()
+            Node::Compiler(e) => {
+                e.get_set_attributes_list()
             },
-            Node::Assets(e) => {
-                e.get_parent()
+            Node::Raw(e) => {
+                e.get_set_attributes_list()
             },
-            Node::Mesh(e) => {
-                e.get_parent()
+            Node::Actuator(e) => {
+                e.get_set_attributes_list()
             },
-            Node::Texture(e) => {
-                e.get_parent()
+            Node::Inertial(e) => {
+                e.get_set_attributes_list()
             },
-            Node::Material(e) => {
-                e.get_parent()
+            Node::Hfield(e) => {
+                e.get_set_attributes_list()
             }
         }
     }
+
 }
 
-#[derive(Debug)]
-pub struct Defaults {
-    children: Vec<Rc<RefCell<Node>>>,
-    parent: Weak<RefCell<Node>>,
-    class: String,
-    element_attrs: HashMap<String,HashMap<String,String>>,
+/* Holds the <compiler> settings. Asset paths are resolved here following MuJoCo:
+meshdir & texturedir take precedence over assetdir for their asset kind, relative
+directories are taken from the model file's directory & absolute file paths are
+used as they are */
+#[derive(Debug, Clone)]
+pub struct Compiler {
+    meshdir: String,
+    texturedir: String,
+    assetdir: String,
+    angle_radian: bool,
+    eulerseq: String,
+    //None is "auto": geoms are used for bodies without an inertial
+    inertiafromgeom: Option<bool>,
     attrs_map: HashMap<String,String>
 }
 
-impl Defaults {
+impl Compiler {
     pub fn new() -> Self {
-        Defaults {
-            children: Vec::new(),
-            class: "main".to_string(),
-            parent: Weak::new(),
-            element_attrs: HashMap::new(),
+        Compiler {
+            meshdir: "".to_string(),
+            texturedir: "".to_string(),
+            assetdir: "".to_string(),
+            angle_radian: false,
+            eulerseq: "xyz".to_string(),
+            inertiafromgeom: None,
             attrs_map: HashMap::new()
         }
     }
 
-    pub fn set_element_attrs(&mut self, parent_element_attrs: &HashMap<String,HashMap<String,String>>) {
-        /*This function assumes that all children have been added to this defaults node 
-        This means that all the overriden attributes are already set */
-        for (element,attrs) in parent_element_attrs {
-            if self.element_attrs.contains_key(element) {
-                let element_attrs = self.element_attrs.get_mut(element).
-                    expect("Failed to get a from the  elements attribute map");
-                for (key, value) in attrs {
-                    if !element_attrs.contains_key(key) {
-                        element_attrs.insert(key.clone(), value.clone());
-                    }
-                }
-            } else {
-                self.element_attrs.insert(element.clone(), attrs.clone());
-            }
+    pub fn angle_to_radians(&self, angle: f32) -> f32 {
+        if self.angle_radian {
+            angle
+        } else {
+            angle.to_radians()
         }
     }
 
-    pub fn apply_defaults(&mut self, body: Rc<RefCell<Node>>, 
-            parent_element_attrs: Option<&HashMap<String,HashMap<String,String>>>) {
-        //First push all the parent element attributes 
-        if let Some(e) = parent_element_attrs {
-            self.set_element_attrs(&e);
-        }
-        {
-            //Set all relevant attributes from self if the body node matches
-            // println!("Applying defaults. Checking self class {} & node {}'s class {}",
-            //     self.class,b.get_node_name(),b.get_class());
-            if body.borrow().get_class() == self.class {
-                let mut b = body.borrow_mut();
-                let attrs_o = self.element_attrs.get(&b.get_node_name());
-                if let Some(attrs) = attrs_o {
-                    for (key,value) in attrs.iter() {
-                        b.add_attr(key.clone(), value.clone());
-                    }
-                }
-            } else {
-                //Check all other children defaults to see if any default application needs to be done
-                for child in &self.children {
-                    match &mut (*child.borrow_mut()) {
-                        Node::Defaults(e) => {
-                            e.apply_defaults(body.clone(),
-                                Some(&self.element_attrs));
-                        }
-                        _ => {}
-                    }
-                }
-            }
-        }
-        //Apply defaults to all children of body
-        {
-            let b = body.borrow_mut();
-            let children = b.get_children();
-            for child in children {
-                self.apply_defaults(child, None)
-            }
-        }
-    } 
-}
-
-impl NodeType for Defaults {
-    fn add_attr(&mut self, key: String, value: String) -> bool {
-        if self.attrs_map.contains_key(&key) {
-            false
-        } else {
-            match key.as_str() {
-                "class" => {
-                    self.class = value.clone();
-                    self.attrs_map.insert(key.clone(), value.clone());
-                    true
-                },
-                _ => false
-            }
-        }  
+    pub fn get_eulerseq(&self) -> String {
+        self.eulerseq.clone()
     }
 
-    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> () {
-        for (key, value) in attrs {
-            self.add_attr(key, value);
-        }
+    pub fn get_inertia_from_geom(&self) -> Option<bool> {
+        self.inertiafromgeom
     }
 
-    fn add_child(&mut self, child: Node) -> Rc<RefCell<Node>> {
-        if child.get_node_name() == "default" {
-            self.children.insert(0,Rc::new(RefCell::new(child)));
-            self.children.get(0).expect("Issue returning child").clone()
+    fn resolve_path(model_dir: &Path, asset_dir: &str, file: &str) -> PathBuf {
+        let file_p = Path::new(file);
+        if file_p.is_absolute() {
+            file_p.to_path_buf()
         } else {
-            /*We don't take non-default children, they're only relevant anyway if 
-            attributes are set for them, which we strip them of here */
-            let attrs = child.get_set_attributes_list();
-            self.element_attrs.insert(child.get_node_name(), attrs);
-            Rc::new(RefCell::new(child))
+            //join replaces model_dir when asset_dir is itself absolute
+            model_dir.join(asset_dir).join(file_p)
         }
     }
 
-    fn add_child_ref(&mut self, child: Rc<RefCell<Node>>) -> Rc<RefCell<Node>> {
-        let child_ref = child.borrow();
-        if child_ref.get_node_name() == "default" {
-            self.children.insert(0,child.clone());
+    pub fn get_mesh_dir(&self) -> String {
+        if self.attrs_map.contains_key("meshdir") {
+            self.meshdir.clone()
         } else {
-            /*We don't take non-default children, they're only relevant anyway if 
-            attributes are set for them, which we strip them of here */
-            let attrs = child_ref.get_set_attributes_list();
-            self.element_attrs.insert(child_ref.get_node_name(), attrs);
+            self.assetdir.clone()
         }
-        child.clone()
     }
 
-    fn get_node_name(&self) -> String {
-        String::from_str("default").expect("Error deriving string")
+    pub fn get_texture_dir(&self) -> String {
+        if self.attrs_map.contains_key("texturedir") {
+            self.texturedir.clone()
+        } else {
+            self.assetdir.clone()
+        }
     }
 
-    fn get_name(&self) -> String {
-        "".to_string()
+    pub fn resolve_mesh_path(&self, model_dir: &Path, file: &str) -> PathBuf {
+        Self::resolve_path(model_dir, &self.get_mesh_dir(), file)
     }
 
-    fn set_parent(&mut self, parent: Weak<RefCell<Node>>) -> () {
-        self.parent = parent;
-    }
-    
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>> {
-        self.parent.upgrade()
+    pub fn resolve_texture_path(&self, model_dir: &Path, file: &str) -> PathBuf {
+        Self::resolve_path(model_dir, &self.get_texture_dir(), file)
+    }
+}
+
+impl NodeType for Compiler {
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError> {
+        if self.attrs_map.contains_key(&key) {
+            Ok(false)
+        } else {
+            match key.as_str() {
+                "meshdir" => {
+                    self.meshdir = value.clone();
+                },
+                "texturedir" => {
+                    self.texturedir = value.clone();
+                },
+                "assetdir" => {
+                    self.assetdir = value.clone();
+                },
+                "angle" => {
+                    self.angle_radian = match value.as_str() {
+                        "radian" => true,
+                        "degree" => false,
+                        _ => {
+                            return Err(AttributeError::new(&key, &value,
+                                "expected degree or radian".to_string()));
+                        }
+                    };
+                },
+                "eulerseq" => {
+                    if value.len() != 3 || !value.chars().all(|c| "xyzXYZ".contains(c)) {
+                        return Err(AttributeError::new(&key, &value,
+                            "expected 3 characters from xyzXYZ".to_string()));
+                    }
+                    self.eulerseq = value.clone();
+                },
+                "inertiafromgeom" => {
+                    self.inertiafromgeom = match value.as_str() {
+                        "true" => Some(true),
+                        "false" => Some(false),
+                        "auto" => None,
+                        _ => {
+                            return Err(AttributeError::new(&key, &value,
+                                "expected false, true or auto".to_string()));
+                        }
+                    };
+                },
+                _ => {}
+            }
+            //Keep the remaining compiler settings around as they are
+            self.attrs_map.insert(key.clone(), value.clone());
+            Ok(true)
+        }
+    }
+
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
+        for (key, value) in attrs {
+            self.add_attr(key, value)?;
+        }
+        Ok(())
+    }
+
+    fn get_node_name(&self) -> String {
+        String::from_str("compiler").expect("Error deriving string")
+    }
+
+    fn get_name(&self) -> String {
+        "".to_string()
+    }
+
+    fn get_set_attributes_list(&self) -> HashMap<String,String> {
+        self.attrs_map.clone()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Defaults {
+    class: String,
+    element_attrs: HashMap<String,HashMap<String,String>>,
+    attrs_map: HashMap<String,String>
+}
+
+impl Defaults {
+    pub fn new() -> Self {
+        Defaults {
+            class: "main".to_string(),
+            element_attrs: HashMap::new(),
+            attrs_map: HashMap::new()
+        }
+    }
+
+    /* Records the attributes of an element declared within this default 
+    (e.g. <geom .../>). Such elements don't become nodes of their own, they're 
+    only relevant for the attributes set on them */
+    pub fn set_element_defaults(&mut self, element: &Node) {
+        let attrs = element.get_set_attributes_list();
+        if let Node::Actuator(_) = element {
+            //As in MuJoCo, all actuator types of a class share a single default, later elements override earlier ones
+            for tag in ACTUATOR_TAGS {
+                if let Some(other) = self.element_attrs.get_mut(tag) {
+                    other.retain(|key, _| !attrs.contains_key(key));
+                }
+            }
+            self.element_attrs.entry(element.get_node_name()).or_default().extend(attrs);
+        } else {
+            self.element_attrs.insert(element.get_node_name(), attrs);
+        }
+    }
+
+    /* The class's actuator default, merged from whichever actuator elements declared it */
+    pub fn get_actuator_attrs(&self) -> HashMap<String,String> {
+        let mut attrs = HashMap::new();
+        for tag in ACTUATOR_TAGS {
+            if let Some(element) = self.element_attrs.get(tag) {
+                attrs.extend(element.iter().map(|(key, value)| (key.clone(), value.clone())));
+            }
+        }
+        attrs
+    }
+
+    /* Attributes recorded per element type, e.g. "geom" -> {"rgba": ..} */
+    pub fn get_element_attrs(&self) -> &HashMap<String,HashMap<String,String>> {
+        &self.element_attrs
+    }
+
+    pub fn set_element_attrs(&mut self, parent_element_attrs: &HashMap<String,HashMap<String,String>>) {
+        /*This function assumes that all children have been added to this defaults node 
+        This means that all the overriden attributes are already set */
+        let own_actuator_attrs = self.get_actuator_attrs();
+        for (element,attrs) in parent_element_attrs {
+            //An actuator attribute set in this class overrides the inherited one, whichever actuator type set either
+            let is_actuator = ACTUATOR_TAGS.contains(&element.as_str());
+            let attrs: HashMap<String,String> = attrs.iter().filter(|(key, _)| {
+                !is_actuator || !own_actuator_attrs.contains_key(*key)
+            }).map(|(key, value)| (key.clone(), value.clone())).collect();
+            if self.element_attrs.contains_key(element) {
+                let element_attrs = self.element_attrs.get_mut(element).
+                    expect("Failed to get a from the  elements attribute map");
+                for (key, value) in attrs {
+                    if !element_attrs.contains_key(&key) {
+                        element_attrs.insert(key, value);
+                    }
+                }
+            } else {
+                self.element_attrs.insert(element.clone(), attrs);
+            }
+        }
+    }
+
+    /* Applies the default node `defaults` (& its nested defaults) to `body` 
+    & all of its descendants in graph */
+    pub fn apply_defaults(graph: &mut SceneGraph, defaults: NodeId, body: NodeId, 
+            parent_element_attrs: Option<&HashMap<String,HashMap<String,String>>>) {
+        //First push all the parent element attributes 
+        let d = match graph.get_mut(defaults) {
+            Node::Defaults(d) => {
+                if let Some(e) = parent_element_attrs {
+                    d.set_element_attrs(&e);
+                }
+                d.clone()
+            },
+            _ => return
+        };
+        {
+            //Set all relevant attributes from self if the body node matches
+            if graph.get(body).get_class() == d.class {
+                let b = graph.get_mut(body);
+                let attrs_o = match b {
+                    Node::Actuator(_) => Some(d.get_actuator_attrs()),
+                    _ => d.element_attrs.get(&b.get_node_name()).cloned()
+                };
+                if let Some(attrs) = attrs_o {
+                    for (key,value) in attrs.iter() {
+                        //Default values were already validated when the default element was parsed
+                        let _ = b.add_attr(key.clone(), value.clone());
+                    }
+                }
+            } else {
+                //Check all other children defaults to see if any default application needs to be done
+                let children = graph.children(defaults).to_vec();
+                for child in children {
+                    Self::apply_defaults(graph, child, body, Some(&d.element_attrs));
+                }
+            }
+        }
+        //Apply defaults to all children of body
+        let children = graph.children(body).to_vec();
+        for child in children {
+            Self::apply_defaults(graph, defaults, child, None)
+        }
+    } 
+}
+
+impl NodeType for Defaults {
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError> {
+        if self.attrs_map.contains_key(&key) {
+            Ok(false)
+        } else {
+            match key.as_str() {
+                "class" => {
+                    self.class = value.clone();
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                },
+                _ => {
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                }
+            }
+        }  
+    }
+
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
+        for (key, value) in attrs {
+            self.add_attr(key, value)?;
+        }
+        Ok(())
+    }
+
+    fn get_node_name(&self) -> String {
+        String::from_str("default").expect("Error deriving string")
+    }
+
+    fn get_name(&self) -> String {
+        "".to_string()
     }
 
     fn get_class(&self) -> String {
@@ -621,34 +924,39 @@ impl NodeType for Defaults {
         }
     }
 
-    fn get_children(&self) -> Vec<Rc<RefCell<Node>>> {
-        let mut children_c = Vec::new();
-        for child in &self.children {
-            children_c.push(child.clone());
-        }
-        children_c
-    }
-
     fn get_set_attributes_list(&self) -> HashMap<String,String> {
         self.attrs_map.clone()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum MeshType {
-    ObjFile,
+    File,
     Inline
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Mesh {
     name: String,
     vertices: Vec<f32>,
     indices: Vec<u32>,
+    //Per vertex normals & texture coordinates, when the file or the inline
+    //attributes provide them
+    normals: Option<Vec<f32>>,
+    texcoords: Option<Vec<f32>>,
+    //Parts of file meshes drawn separately, with their own material
+    submeshes: Vec<mesh_io::SubMesh>,
+    materials: Vec<mesh_io::MeshMaterial>,
+    //Diffuse maps of the materials that could be read, keyed by diffuse_texture
+    textures: HashMap<String,Texture>,
     scale: (f32,f32,f32),
+    refpos: Vector3<f32>,
+    refquat: UnitQuaternion<f32>,
     file: String,
     attrs_map: HashMap<String,String>,
-    pub mesh_contents_type: MeshType
+    pub mesh_contents_type: MeshType,
+    //Convex hull of inline meshes without faces, built once at load time
+    hull: Option<(Vec<f32>,Vec<u32>)>
 }
 
 impl Mesh {
@@ -656,35 +964,87 @@ impl Mesh {
         Mesh {
             name: name,
             vertices: Vec::new(),
+            normals: None,
+            texcoords: None,
+            submeshes: Vec::new(),
+            materials: Vec::new(),
+            textures: HashMap::new(),
             scale: (1.0,1.0,1.0),
+            refpos: Vector3::zeros(),
+            refquat: UnitQuaternion::identity(),
             attrs_map: HashMap::new(),
             mesh_contents_type: MeshType::Inline,
             indices: Vec::new(),
             file: "".to_string(),
+            hull: None
         }
     }
 
     pub fn getAllGeometries(&self) -> (Vec<f32>,Vec<u32>) {
         match self.mesh_contents_type {
-            MeshType::Inline => {
-                /* Pushing test vertices for now, 
-                to get the orientation & translation applied vertices */
-                let vertices_t = self.getVertices();
-                let mut x: Vec<f64> = vertices_t.iter().map(|&val| val as f64).collect();
-                let y = 
-                    Self::generate_triangulated_convex_hull(x.as_mut_slice()).expect("Qhull error");
-                let y_prime: Vec<f64> = y.0.iter().flat_map(|array| *array).collect();;
-                let z: (Vec<f32>,Vec<u32>) = (y_prime.iter().map(|&val| val as f32).collect(),y.1);
-                // println!("The sets are {} {}",z.0.len(),z.1.len());
-                z
-                // (self.getVertices(),self.getIndices())
-            },
-            MeshType::ObjFile => {
+            MeshType::Inline if self.is_convex_hull() => {
+                match &self.hull {
+                    Some(hull) => hull.clone(),
+                    None => self.compute_hull().expect("Qhull error")
+                }
+            },
+            MeshType::Inline | MeshType::File => {
                 (self.getVertices(),self.getIndices())
             }
         }
     }
 
+    /* As in MuJoCo, inline meshes given without faces are drawn as the 
+    convex hull of their vertices */
+    pub fn is_convex_hull(&self) -> bool {
+        matches!(self.mesh_contents_type, MeshType::Inline) && self.indices.is_empty()
+    }
+
+    /* Checks the inline attributes agree with each other, then builds & 
+    caches the convex hull of meshes without faces, so it isn't recomputed 
+    whenever the geometries are extracted */
+    pub fn build_hull(&mut self) -> Result<(),String> {
+        if let MeshType::Inline = self.mesh_contents_type {
+            self.check_inline()?;
+            if self.is_convex_hull() {
+                self.hull = Some(self.compute_hull().map_err(|error| {
+                    format!("convex hull failed: {}", error)
+                })?);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_inline(&self) -> Result<(),String> {
+        let num_vertices = self.vertices.len() / 3;
+        if num_vertices == 0 {
+            return Err("no vertices given".to_string());
+        }
+        if let Some(index) = self.indices.iter().find(|i| **i as usize >= num_vertices) {
+            return Err(format!("face refers to vertex {} of {}", index, num_vertices));
+        }
+        if let Some(normals) = &self.normals {
+            if normals.len() != self.vertices.len() {
+                return Err(format!("expected {} normals, got {}", num_vertices, normals.len() / 3));
+            }
+        }
+        if let Some(texcoords) = &self.texcoords {
+            if texcoords.len() / 2 != num_vertices {
+                return Err(format!("expected {} texcoords, got {}", num_vertices, texcoords.len() / 2));
+            }
+        }
+        Ok(())
+    }
+
+    //qhull's errors borrow the points, so they're turned into messages here
+    fn compute_hull(&self) -> Result<(Vec<f32>,Vec<u32>),String> {
+        let vertices_t = self.getVertices();
+        let mut x: Vec<f64> = vertices_t.iter().map(|&val| val as f64).collect();
+        let y = Self::generate_triangulated_convex_hull(x.as_mut_slice()).map_err(|error| error.to_string())?;
+        let y_prime: Vec<f64> = y.0.iter().flat_map(|array| *array).collect();
+        Ok((y_prime.iter().map(|&val| val as f32).collect(),y.1))
+    }
+
     pub fn getVertices(&self) -> Vec<f32> {
         self.apply_transforms(self.vertices.clone())
     }
@@ -693,6 +1053,117 @@ impl Mesh {
         self.indices.clone()
     }
 
+    /* Normals read from the mesh file or given inline, following the 
+    vertices through refquat & scale. Meshes drawn as hulls have none */
+    pub fn get_normals(&self) -> Option<Vec<f32>> {
+        if self.is_convex_hull() {
+            return None;
+        }
+        let normals = self.normals.as_ref()?;
+        let inverse_scale = Vector3::new(1.0 / self.scale.0, 1.0 / self.scale.1, 1.0 / self.scale.2);
+        Some(normals.chunks_exact(3).flat_map(|n| {
+            let normal = (self.refquat.inverse() * Vector3::new(n[0], n[1], n[2])).component_mul(&inverse_scale);
+            let normal = normal.try_normalize(f32::EPSILON).unwrap_or(Vector3::z());
+            [normal.x, normal.y, normal.z]
+        }).collect())
+    }
+
+    /* Texture coordinates from the file or given inline, 2 per vertex with v
+    going up the image */
+    pub fn get_texcoords(&self) -> Option<Vec<f32>> {
+        self.texcoords.clone()
+    }
+
+    /* The mesh as its submeshes, each with its vertices transformed like 
+    getVertices & its material. Meshes without submeshes are a single part */
+    pub fn get_parts(&self) -> Vec<mesh_io::MeshPart> {
+        let (vertices, indices) = self.getAllGeometries();
+        let texcoords = if self.is_convex_hull() { None } else { self.get_texcoords() };
+        self.split_parts(vertices, indices, self.get_normals(), texcoords)
+    }
+
+    fn split_parts(&self, vertices: Vec<f32>, indices: Vec<u32>, normals: Option<Vec<f32>>,
+            texcoords: Option<Vec<f32>>) -> Vec<mesh_io::MeshPart> {
+        if self.is_convex_hull() || self.submeshes.len() <= 1 {
+            let material = self.submeshes.first().and_then(|s| s.material).
+                and_then(|m| self.materials.get(m).cloned());
+            return vec![mesh_io::MeshPart {
+                vertices: vertices,
+                indices: indices,
+                normals: normals,
+                texcoords: texcoords,
+                material: material
+            }];
+        }
+        self.submeshes.iter().map(|submesh| {
+            //Renumbers the vertices used by the submesh's triangles
+            let mut remap: HashMap<u32,u32> = HashMap::new();
+            let mut used = Vec::new();
+            let part_indices = indices[submesh.indices.clone()].iter().map(|i| {
+                *remap.entry(*i).or_insert_with(|| {
+                    used.push(*i as usize);
+                    (used.len() - 1) as u32
+                })
+            }).collect();
+            let gather = |values: &[f32], width: usize| -> Vec<f32> {
+                used.iter().flat_map(|i| values[width * i..width * (i + 1)].to_vec()).collect()
+            };
+            mesh_io::MeshPart {
+                vertices: gather(&vertices, 3),
+                indices: part_indices,
+                normals: normals.as_ref().map(|n| gather(n, 3)),
+                texcoords: texcoords.as_ref().map(|t| gather(t, 2)),
+                material: submesh.material.and_then(|m| self.materials.get(m).cloned())
+            }
+        }).collect()
+    }
+
+    /* Runs processing over each submesh, in the mesh's own frame before 
+    refpos, refquat & scale. A triangle target is shared out between the 
+    submeshes by their size. Meshes drawn as hulls are left as they are */
+    pub fn process(&mut self, processing: &mesh_ops::MeshProcessing) {
+        if self.is_convex_hull() || processing.is_noop() {
+            return;
+        }
+        let total = (self.indices.len() / 3).max(1);
+        let parts = self.split_parts(self.vertices.clone(), self.indices.clone(),
+            self.normals.clone(), self.texcoords.clone());
+        let parts: Vec<mesh_io::MeshPart> = parts.iter().map(|part| {
+            let share = mesh_ops::MeshProcessing {
+                target_triangles: processing.target_triangles.map(|target| {
+                    (target * (part.indices.len() / 3) / total).max(1)
+                }),
+                ..processing.clone()
+            };
+            share.apply(part)
+        }).collect();
+        //Inline meshes without faces are drawn as hulls, so one left with none keeps its faces
+        if matches!(self.mesh_contents_type, MeshType::Inline) && parts.iter().all(|part| part.indices.is_empty()) {
+            return;
+        }
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        let mut normals = Vec::new();
+        let mut texcoords = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            let offset = (vertices.len() / 3) as u32;
+            let start = indices.len();
+            vertices.extend_from_slice(&part.vertices);
+            indices.extend(part.indices.iter().map(|index| index + offset));
+            normals.extend_from_slice(part.normals.as_deref().unwrap_or(&[]));
+            texcoords.extend_from_slice(part.texcoords.as_deref().unwrap_or(&[]));
+            if let Some(submesh) = self.submeshes.get_mut(i) {
+                submesh.indices = start..indices.len();
+            }
+        }
+        self.normals = if parts.iter().all(|p| p.normals.is_some()) { Some(normals) } else { None };
+        self.texcoords = if parts.iter().all(|p| p.texcoords.is_some()) { Some(texcoords) } else { None };
+        self.vertices = vertices;
+        self.indices = indices;
+    }
+
+    /* As MuJoCo does, the mesh is moved so refpos is its origin & rotated
+    so refquat is its orientation, then scaled */
     pub fn apply_transforms(&self,vertices: Vec<f32>) -> Vec<f32> {
         // println!("Applying scale to mesh {}: {} {} {} ", self.name,self.scale.0,self.scale.1,self.scale.2);
         let num_vertices = vertices.len() / 3;
@@ -700,8 +1171,11 @@ impl Mesh {
                 self.scale.0, 0.0, 0.0,
                 0.0, self.scale.1, 0.0,
                 0.0, 0.0, self.scale.2,
-        );
-        let vertices_m = DMatrix::from_vec(3,num_vertices,vertices);
+        ) * self.refquat.inverse().to_rotation_matrix().matrix();
+        let mut vertices_m = DMatrix::from_vec(3,num_vertices,vertices);
+        for mut vertex in vertices_m.column_iter_mut() {
+            vertex -= &self.refpos;
+        }
         let vertices_mr = rotation_s * vertices_m;
         // let vertices_mr = vertices_m;
         let vertices_r : Vec<f32> = Vec::from(vertices_mr.as_slice());
@@ -720,9 +1194,10 @@ impl Mesh {
     /
     / A `Result` containing a tuple with two elements:
     / 1. A `Vec<[f64; 3]>` representing the ordered array of unique vertices on the hull.
-    / 2. A `Vec<usize>` representing the index array for drawing with triangle primitives.
+    / 2. A `Vec<u32>` representing the index array for drawing with triangle primitives.
     /
-    / or a `qhull::Error` if the computation fails. */
+    / or a `qhull::Error` if the computation fails. Facets sharing a vertex 
+    / refer to the same entry of the vertex array. */
     pub fn generate_triangulated_convex_hull(points: &mut [f64])
         -> Result<(Vec<[f64; 3]>, Vec<u32>), qhull::QhError>
     {
@@ -731,73 +1206,80 @@ impl Mesh {
         
         let mut indices = Vec::new();
         let mut hull_vertices = Vec::new();
-        let mut i = 0;
+        //Input points are identified by their exact coordinates
+        let mut seen: HashMap<[u64;3],u32> = HashMap::new();
         for facet in qh.facets() {
             if let Some(vertices) = facet.vertices() {
                 let num_vertices = vertices.size(&qh);
-                // println!("Vertices length is {}",num_vertices);
                 assert!(num_vertices == 3,"Expected 3 vertices in the facet given triangulation");
-                // The `vertex.index(&qh)` method is the new way to get this mapping.
                 for vertex in vertices.iter() {
                     let coords = vertex.point().expect("Expected the triangle vertices");
-                    hull_vertices.push([coords[0], coords[1], coords[2]]);
+                    let point = [coords[0], coords[1], coords[2]];
+                    let index = *seen.entry(point.map(f64::to_bits)).or_insert_with(|| {
+                        hull_vertices.push(point);
+                        (hull_vertices.len() - 1) as u32
+                    });
+                    indices.push(index);
                 }
-                indices.push(i);        
-                indices.push(i+1);
-                indices.push(i+2);
-                i+=3;
+            }
+        }
+        //Qhull doesn't wind the facets consistently, each is turned to face away from the centroid, which is inside
+        let centroid = hull_vertices.iter().fold(Vector3::<f64>::zeros(), |sum, v| sum + Vector3::from(*v)) / 
+            hull_vertices.len().max(1) as f64;
+        for triangle in indices.chunks_exact_mut(3) {
+            let [a, b, c] = [0, 1, 2].map(|k| Vector3::from(hull_vertices[triangle[k] as usize]));
+            if (b - a).cross(&(c - a)).dot(&(a - centroid)) < 0.0 {
+                triangle.swap(1, 2);
             }
         }
         Ok((hull_vertices, indices))
     }
 
-    pub fn get_indices(&self) -> Vec<u32> {
-        let mut indices = Vec::new();
-        // indices.push(0);indices.push(1);indices.push(2);
-        // indices.push(3);indices.push(4);indices.push(5);
-        // indices.push(6);indices.push(7);indices.push(8);
-        // indices.push(1);indices.push(2);indices.push(3);
-        // indices.push(2);indices.push(3);indices.push(4);
-        // indices.push(3);indices.push(4);indices.push(5);
-        for i in 0..(self.vertices.len() / 3) {
-            indices.push(i as u32);
-            // indices.push(((i+1)%self.vertices.len()) as u32);
-            // indices.push(((i+2)%self.vertices.len()) as u32);
+    /* Fills the mesh from its file's contents, OBJ, STL or PLY, given the
+    contents of the .mtl libraries an OBJ refers to */
+    pub fn setup_with_file_data(&mut self, data: &[u8], materials: &HashMap<String,Vec<u8>>) -> Result<(),String> {
+        let format = mesh_io::MeshFormat::detect(&self.file, data).
+            ok_or("unrecognized mesh format".to_string())?;
+        let parsed = mesh_io::parse_mesh(format, data, materials)?;
+        self.mesh_contents_type = MeshType::File;
+        self.vertices = parsed.vertices;
+        self.indices = parsed.indices;
+        self.normals = parsed.normals;
+        self.texcoords = parsed.texcoords;
+        self.submeshes = parsed.submeshes;
+        //Maps are named relative to the OBJ, they're kept relative to the meshdir like the OBJ itself
+        let dir = Path::new(&self.file).parent().map_or(PathBuf::new(), |dir| dir.to_path_buf());
+        self.materials = parsed.materials.into_iter().map(|mut material| {
+            material.diffuse_texture = material.diffuse_texture.map(|map| {
+                dir.join(map).to_string_lossy().to_string()
+            });
+            material
+        }).collect();
+        Ok(())
+    }
+
+    /* Diffuse maps the mesh's materials refer to, relative to the meshdir */
+    pub fn get_diffuse_texture_files(&self) -> Vec<String> {
+        self.materials.iter().filter_map(|m| m.diffuse_texture.clone()).collect()
+    }
+
+    /* Sets up the diffuse maps from their decoded RGB images, keyed by file. 
+    Materials whose map is missing are drawn with their diffuse color */
+    pub fn load_textures(&mut self, files: &HashMap<String,(u32,u32,Vec<u8>)>) {
+        for file in self.get_diffuse_texture_files() {
+            if let Some((width, height, rgb)) = files.get(&file) {
+                self.textures.insert(file.clone(), Texture::from_image(file, (*width, *height), rgb.clone()));
+            }
         }
-        indices
     }
 
-    pub fn setup_with_obj_stream(&mut self,raw_obj: String) {
-        self.mesh_contents_type = MeshType::ObjFile;
-        let parsed_objs = Self::parse_obj_stream(raw_obj);
-        self.vertices = parsed_objs.0;
-        self.indices = parsed_objs.1;
+    /* The loaded diffuse map of a material of this mesh, if it has one */
+    pub fn get_diffuse_texture(&self, material: &mesh_io::MeshMaterial) -> Option<Texture> {
+        material.diffuse_texture.as_ref().and_then(|file| self.textures.get(file)).cloned()
     }
 
-    fn parse_obj_stream(raw_obj: String) -> (Vec<f32>,Vec<u32>) {
-        let mut vertices = Vec::new();
-        let mut indices = Vec::new();
-        let mut buff = std::io::Cursor::new(raw_obj);
-        let models = 
-            tobj::load_obj_buf(&mut buff, &tobj::LoadOptions {
-                    triangulate: true,
-                    ignore_lines: true,
-                    ignore_points: true,
-                    ..Default::default()
-                },
-                |_p| {
-                     Ok((Vec::new(), ahash::AHashMap::<String,usize>::new()))
-                }
-            ).
-            expect("Expected to be able to parse the obj file");
-        for model in models.0 {
-            let mesh = model.mesh;
-            let mut positions = mesh.positions;
-            let mut inds = mesh.indices;
-            vertices.append(&mut positions);
-            indices.append(&mut inds);
-        }
-        (vertices,indices)
+    pub fn get_diffuse_textures(&self) -> Vec<Texture> {
+        self.textures.values().cloned().collect()
     }
 
     // #[deprecated]
@@ -830,88 +1312,111 @@ impl Mesh {
 }
 
 impl NodeType for Mesh {
-    fn add_attr(&mut self, key: String, value: String) -> bool { 
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError> {
         if self.attrs_map.contains_key(&key) {
-            false
+            Ok(false)
         } else {
             match key.as_str() {
                 "name" => {
                     self.name = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "file" => {
-                    self.mesh_contents_type = MeshType::ObjFile;
+                    self.mesh_contents_type = MeshType::File;
                     self.file = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "vertex" => {
                     self.mesh_contents_type = MeshType::Inline;
-                    let values = value.split_whitespace();
-                    let values: Vec<&str> = values.collect();
-                    for value in values {
-                        self.vertices.push(value.parse::<f32>().unwrap())
+                    let mut values = parse_attr_values::<f32>(&key, &value, 3..=usize::MAX)?;
+                    if values.len() % 3 != 0 {
+                        return Err(AttributeError::new(&key, &value,
+                            "expected a multiple of 3 values".to_string()));
+                    }
+                    self.vertices.append(&mut values);
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                },
+                //Checked against the vertices once all attributes are read
+                "face" => {
+                    self.mesh_contents_type = MeshType::Inline;
+                    let values = parse_attr_values::<u32>(&key, &value, 3..=usize::MAX)?;
+                    if values.len() % 3 != 0 {
+                        return Err(AttributeError::new(&key, &value,
+                            "expected a multiple of 3 values".to_string()));
                     }
+                    self.indices = values;
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
+                },
+                "normal" => {
+                    let values = parse_attr_values::<f32>(&key, &value, 3..=usize::MAX)?;
+                    if values.len() % 3 != 0 {
+                        return Err(AttributeError::new(&key, &value,
+                            "expected a multiple of 3 values".to_string()));
+                    }
+                    self.normals = Some(values);
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                },
+                "texcoord" => {
+                    let values = parse_attr_values::<f32>(&key, &value, 2..=usize::MAX)?;
+                    if values.len() % 2 != 0 {
+                        return Err(AttributeError::new(&key, &value,
+                            "expected a multiple of 2 values".to_string()));
+                    }
+                    self.texcoords = Some(values);
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
                 },
                 "scale" => {
-                    let values = value.split_whitespace();
-                    let values: Vec<&str> = values.collect();
-                    self.scale.0 = values.get(0).expect("Expected position 0 for scale").parse::<f32>().unwrap();
-                    self.scale.1 = values.get(1).expect("Expected position 1 for scale").parse::<f32>().unwrap();
-                    self.scale.2 = values.get(2).expect("Expected position 2 for scale").parse::<f32>().unwrap();
+                    let values = parse_attr_values::<f32>(&key, &value, 3..=3)?;
+                    self.scale = (values[0], values[1], values[2]);
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                },
+                "refpos" => {
+                    let values = parse_attr_values::<f32>(&key, &value, 3..=3)?;
+                    self.refpos = Vector3::new(values[0], values[1], values[2]);
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
-                _ => false
+                "refquat" => {
+                    let values = parse_attr_values::<f32>(&key, &value, 4..=4)?;
+                    let quat = Quaternion::new(values[0], values[1], values[2], values[3]);
+                    self.refquat = UnitQuaternion::try_new(quat, f32::EPSILON).ok_or(
+                        AttributeError::new(&key, &value, "quaternion must be non-zero".to_string()))?;
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                },
+                _ => {
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                }
             }
         }
         
     }
 
-    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> () { 
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
         for (key, value) in attrs {
-            self.add_attr(key, value);
+            self.add_attr(key, value)?;
         }
+        Ok(())
     }
     
-    fn add_child(&mut self, child: Node) -> Rc<RefCell<Node>> {
-        println!("Warning: Meshes can't have children. Skipping this.");
-        Rc::new(RefCell::new(child))
-    }
-
-    fn set_parent(&mut self, parent: Weak<RefCell<Node>>) -> () {
-        // self.parent = parent;
-    }
-    
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>> {
-        None
-        // self.parent.upgrade()
-    }
-
     fn get_node_name(&self) -> String {
         String::from_str("mesh").expect("Error deriving string")
     }
 
-    fn add_child_ref(&mut self, child: Rc<RefCell<Node>>) -> Rc<RefCell<Node>> {
-        println!("Warning: Meshes can't have children. Skipping this.");
-        child
-    }
-
-    fn get_children(&self) -> Vec<Rc<RefCell<Node>>> {
-        let mut children_c = Vec::new();
-        children_c
-    }
-
     fn get_name(&self) -> String {
         if self.attrs_map.contains_key("name") {
             self.name.clone()
         } else if self.attrs_map.contains_key("file") {
-            //Warning: there can be a path here & in textures, remove that 
-            self.attrs_map.get("file").expect("Expected file name in texture").
-                split(".").nth(0).expect("Expected file name in texture").to_string()
+            //Assets named after their file drop the directory & extension
+            asset_name_from_file(self.attrs_map.get("file").expect("Expected file name in mesh"))
         } else {
             "".to_string()
         }
@@ -936,6 +1441,19 @@ pub struct Texture {
 }
 
 impl Texture {
+    /* A 2D texture from a decoded RGB image, e.g. an OBJ material's diffuse map */
+    pub fn from_image(name: String, dimensions: (u32,u32), rgb: Vec<u8>) -> Self {
+        Texture {
+            attrs_map: HashMap::from([("name".to_string(), name.clone())]),
+            file: name.clone(),
+            name: name,
+            t_type: TextureType::TwoD,
+            texture_image_dimensions: dimensions,
+            file_data: rgb,
+            ..Texture::new()
+        }
+    }
+
     pub fn new() -> Self {
         Texture {
             attrs_map: HashMap::new(),
@@ -1003,34 +1521,35 @@ impl Texture {
 }
 
 impl NodeType for Texture {
-    fn add_attr(&mut self, key: String, value: String) -> bool {
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError> {
         if self.attrs_map.contains_key(&key) {
-            false
+            Ok(false)
         } else {
             match key.as_str() {
                 "name" => {
                     self.name = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "file" => {
                     self.file = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "gridsize" => {
-                    let values = value.split(" ");
-                    let values: Vec<&str> = values.collect();
-                    assert!(values.len() == 2,"Expected 2 gridsize values only");
-                    self.gridsize.0 = values.get(0).expect("Expected position 0 in gridsize").parse::<u32>().unwrap();
-                    self.gridsize.1 = values.get(1).expect("Expected position 1 in gridsize").parse::<u32>().unwrap();
+                    let values = parse_attr_values::<u32>(&key, &value, 2..=2)?;
+                    if values[0] == 0 || values[1] == 0 {
+                        return Err(AttributeError::new(&key, &value,
+                            "gridsize values must be positive".to_string()));
+                    }
+                    self.gridsize = (values[0], values[1]);
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "gridlayout" => {
                     self.gridlayout = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "type" => {
                     match value.as_str() {
@@ -1040,46 +1559,27 @@ impl NodeType for Texture {
                         _ => {}
                     }
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
-                _ => false
+                _ => {
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                }
             }
         } 
     }
 
-    fn add_attrs(&mut self,attrs: Vec<(String,String)>) -> () {
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
         for (key, value) in attrs {
-            self.add_attr(key, value);
+            self.add_attr(key, value)?;
         }
+        Ok(())
     }
     
-    fn add_child(&mut self, child: Node) -> Rc<RefCell<Node>> {
-        println!("Warning: Textures can't have children. Skipping add_child.");
-        Rc::new(RefCell::new(child))
-    }
-
-    fn add_child_ref(&mut self, child: Rc<RefCell<Node>>) -> Rc<RefCell<Node>> {
-        println!("Warning: Textures can't have children. Skipping add_child_ref.");
-        child
-    }
-
     fn get_node_name(&self) -> String {
         String::from_str("texture").expect("Error deriving string")
     }
 
-    fn set_parent(&mut self, parent: Weak<RefCell<Node>>) -> () {
-        //Do nothing
-    }
-    
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>> {
-        None
-    }
-
-    fn get_children(&self) -> Vec<Rc<RefCell<Node>>> {
-        let children_c = Vec::new();
-        children_c
-    }
-
     fn get_set_attributes_list(&self) -> HashMap<String,String> {
         self.attrs_map.clone()
     }
@@ -1088,22 +1588,20 @@ impl NodeType for Texture {
         if self.attrs_map.contains_key("name") {
             self.name.clone()
         } else if self.attrs_map.contains_key("file") {
-            self.attrs_map.get("file").expect("Expected file name in texture").
-                split(".").nth(0).expect("Expected file name in texture").to_string()
+            asset_name_from_file(self.attrs_map.get("file").expect("Expected file name in texture"))
         } else {
             "".to_string()
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Material {
     name: String,
     texture_name: String,
     rgba: (f32,f32,f32,f32),
-    texture: Option<Rc<RefCell<Node>>>,
-    attrs_map: HashMap<String,String>,
-    parent: Option<Weak<RefCell<Node>>>,
+    texture: Option<NodeId>,
+    attrs_map: HashMap<String,String>
 }
 
 impl Material {
@@ -1113,14 +1611,13 @@ impl Material {
             texture_name: "".to_string(),
             texture: None,
             attrs_map: HashMap::new(),
-            parent: None,
             rgba: (1.0,1.0,1.0,1.0)
         }
     }
 
-    pub fn to_load_file(&self) -> Option<String> {
-        if let Some(texture) = &self.texture {
-            match &*texture.borrow() {
+    pub fn to_load_file(&self, graph: &SceneGraph) -> Option<String> {
+        if let Some(texture) = self.texture {
+            match graph.get(texture) {
                 Node::Texture(txt) => {
                     Some(txt.to_load_file())
                 },
@@ -1131,15 +1628,15 @@ impl Material {
         }
     }
 
-    pub fn add_texture(&mut self, texture: Rc<RefCell<Node>>) {
+    pub fn add_texture(&mut self, texture: NodeId) {
         if let None = self.texture {
             self.texture = Some(texture);
         }
     }
 
-    pub fn get_textures_data(&self) -> Option<Texture> {
-        self.texture.as_ref().map_or(None, |e| {
-            match &*e.borrow() {
+    pub fn get_textures_data(&self, graph: &SceneGraph) -> Option<Texture> {
+        self.texture.map_or(None, |e| {
+            match graph.get(e) {
                 Node::Texture(t) => {
                     Some(t.clone())
                 },
@@ -1174,11 +1671,11 @@ impl Material {
         re_centered_vertices
     }
 
-    pub fn get_vertices_filling(&self,vertices: &Vec<f32>) -> Option<Vec<VertexFilling>> {
+    pub fn get_vertices_filling(&self, graph: &SceneGraph, vertices: &Vec<f32>) -> Option<Vec<VertexFilling>> {
         let mut filling: Vec<VertexFilling> = Vec::new();
         let vertices = Self::get_centered_vertices(vertices);
-        if let Some(e) = self.texture.as_ref() {
-            match &*e.borrow() {
+        if let Some(e) = self.texture {
+            match graph.get(e) {
                 Node::Texture(t) => {
                     match t.t_type {
                         TextureType::Cube => {
@@ -1207,72 +1704,48 @@ impl Material {
 }
 
 impl NodeType for Material {
-    fn add_attr(&mut self, key: String, value: String) -> bool {
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError> {
         if self.attrs_map.contains_key(&key) {
-            false
+            Ok(false)
         } else {
             match key.as_str() {
                 "name" => {
                     self.name = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "rgba" => {
-                    let values = value.split(" ");
-                    let values: Vec<&str> = values.collect();
-                    assert!(values.len() == 4,"Expected 4 position values only");
-                    self.rgba.0 = values.get(0).expect("Expected position 0").parse::<f32>().unwrap();
-                    self.rgba.1 = values.get(1).expect("Expected position 1").parse::<f32>().unwrap();
-                    self.rgba.2 = values.get(2).expect("Expected position 2").parse::<f32>().unwrap();
-                    self.rgba.3 = values.get(3).expect("Expected position 3").parse::<f32>().unwrap();
+                    let values = parse_attr_values::<f32>(&key, &value, 4..=4)?;
+                    self.rgba = (values[0], values[1], values[2], values[3]);
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "texture" => {
                     //check if the mesh asset is available, if yes, attach it
                     //otherwise skip & just attach the name 
                     self.texture_name = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
-                _ => false
+                _ => {
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                }
             }
         } 
     }
 
-    fn add_attrs(&mut self,attrs: Vec<(String,String)>) -> () {
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
         for (key, value) in attrs {
-            self.add_attr(key, value);
+            self.add_attr(key, value)?;
         }
+        Ok(())
     }
     
-    fn add_child(&mut self, child: Node) -> Rc<RefCell<Node>> {
-        println!("Warning: Material can't have children. Skipping add_child.");
-        Rc::new(RefCell::new(child))
-    }
-
-    fn add_child_ref(&mut self, child: Rc<RefCell<Node>>) -> Rc<RefCell<Node>> {
-        println!("Warning: Material can't have children. Skipping add_child_ref.");
-        child
-    }
-
     fn get_node_name(&self) -> String {
         String::from_str("material").expect("Error deriving string")
     }
 
-    fn set_parent(&mut self, parent: Weak<RefCell<Node>>) -> () {
-        self.parent = Some(parent);
-    }
-    
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>> {
-        None
-    }
-
-    fn get_children(&self) -> Vec<Rc<RefCell<Node>>> {
-        let children_c = Vec::new();
-        children_c
-    }
-
     fn get_set_attributes_list(&self) -> HashMap<String,String> {
         self.attrs_map.clone()
     }
@@ -1282,81 +1755,261 @@ impl NodeType for Material {
     }
 }
 
-#[derive(Debug)]
-pub struct AssetsManager {
-    children: Vec<Rc<RefCell<Node>>>
+/* A height field asset: a grid of nrow x ncol elevations, given inline or 
+loaded from a grayscale image. As in MuJoCo the elevations are normalised to 
+[0,1] & scaled by size = "rx ry elev base": the grid spans [-rx,rx] x [-ry,ry], 
+row 0 at -ry, its top reaches elev & a base of depth base lies below z = 0 */
+#[derive(Debug, Clone)]
+pub struct Hfield {
+    name: String,
+    file: String,
+    nrow: usize,
+    ncol: usize,
+    //Row major, normalised once known
+    elevation: Vec<f32>,
+    size: [f32;4],
+    attrs_map: HashMap<String,String>
 }
 
-impl AssetsManager {
+impl Hfield {
     pub fn new() -> Self {
-        AssetsManager {
-            children: Vec::new()
+        Hfield {
+            name: String::new(),
+            file: String::new(),
+            nrow: 0,
+            ncol: 0,
+            elevation: Vec::new(),
+            size: [0.0;4],
+            attrs_map: HashMap::new()
         }
     }
 
-    pub fn get_mesh(&self, name: String) -> Option<Rc<RefCell<Node>>> {
-        let mut mesh_iq = None;
-        for child in &self.children {
-            let child_name = child.borrow().get_name();
-            let child_node_name = child.borrow().get_node_name();
-            // println!("Searched meshes, got: {} was searching for {}", child_name, name);
-            if child_name == name && child_node_name == "mesh" {
-               mesh_iq = Some(child.clone());
-            }
+    pub fn get_file(&self) -> String {
+        self.file.clone()
+    }
+
+    pub fn get_size(&self) -> [f32;4] {
+        self.size
+    }
+
+    pub fn nrow(&self) -> usize {
+        self.nrow
+    }
+
+    pub fn ncol(&self) -> usize {
+        self.ncol
+    }
+
+    /* Normalised elevation of a grid point */
+    pub fn get_elevation(&self, row: usize, col: usize) -> f32 {
+        self.elevation[row * self.ncol + col]
+    }
+
+    /* Takes the elevations from a grayscale image. The image's top row is the
+    far (+y) edge of the field */
+    pub fn load_image(&mut self, width: u32, height: u32, luma: &[u8]) {
+        self.nrow = height as usize;
+        self.ncol = width as usize;
+        self.elevation = (0..self.nrow).rev().flat_map(|row| {
+            luma[row * self.ncol..(row + 1) * self.ncol].iter().map(|v| *v as f32)
+        }).collect();
+        self.normalize();
+    }
+
+    /* Checks the elevations against the grid size, called once any file has 
+    been loaded. A grid without elevations is flat */
+    pub fn finish(&mut self) -> Result<(),String> {
+        if self.size[0] <= 0.0 || self.size[1] <= 0.0 || self.size[2] < 0.0 || self.size[3] < 0.0 {
+            return Err("size must be set with positive extents".to_string());
         }
-        mesh_iq
-    }
-
-    fn process_materials(&self) {
-        let mut texture_map: HashMap<String,Rc<RefCell<Node>>> = HashMap::new();
-        let mut material_map: HashMap<String,Rc<RefCell<Node>>> = HashMap::new();
-        for child in &self.children {
-            let child_name = child.borrow().get_name();
-            let child_node_name = child.borrow().get_node_name();
-            if child_node_name == "material" {
-               material_map.insert(child_name, child.clone());
-            } else if child_node_name == "texture" {
-               texture_map.insert(child_name, child.clone());
-            } 
-        }
-        for material in material_map.iter() {
-            if texture_map.contains_key(material.0) {
-                match &mut *material.1.borrow_mut() {
-                    Node::Material(ref mut m) => {
-                        m.add_texture(texture_map.get(material.0).
-                            expect("Expected texture in the map when processing materials").
-                            clone());
-                    },
-                    _ => ()
-                }
-            }
+        if self.nrow < 2 || self.ncol < 2 {
+            return Err(format!("needs at least 2 x 2 elevations, got {} x {}", self.nrow, self.ncol));
+        }
+        if self.elevation.is_empty() {
+            self.elevation = vec![0.0; self.nrow * self.ncol];
         }
+        if self.elevation.len() != self.nrow * self.ncol {
+            return Err(format!("has {} elevations for {} x {} grid points", 
+                self.elevation.len(), self.nrow, self.ncol));
+        }
+        self.normalize();
+        Ok(())
     }
 
-    pub fn get_material(&self, name: String) -> Option<Rc<RefCell<Node>>> {
-        //process materials to link textures with them first
-        self.process_materials();
-        let mut material_iq = None;
-        for child in &self.children {
-            let child_name = child.borrow().get_name();
-            let child_node_name = child.borrow().get_node_name();
-            if child_name == name && child_node_name == "material" {
-               material_iq = Some(child.clone());
-            }
+    fn normalize(&mut self) {
+        let min = self.elevation.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = self.elevation.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        if max > min {
+            self.elevation.iter_mut().for_each(|v| *v = (*v - min) / (max - min));
+        } else {
+            self.elevation.iter_mut().for_each(|v| *v = 0.0);
         }
-        material_iq
     }
 
-    pub fn to_load_files(&self) -> Vec<String> {
-        let mut files = Vec::new();
-        for child in &self.children {
-            match &*child.borrow() {
-                Node::Texture(t) => {
-                    files.push(t.file.clone());
-                },
+    /* Position of a grid point in the field's frame */
+    pub fn grid_point(&self, row: usize, col: usize) -> Vector3<f32> {
+        let x = self.size[0] * (2.0 * col as f32 / (self.ncol - 1) as f32 - 1.0);
+        let y = self.size[1] * (2.0 * row as f32 / (self.nrow - 1) as f32 - 1.0);
+        Vector3::new(x, y, self.size[2] * self.get_elevation(row, col))
+    }
+
+    /* Height of the surface above (x, y) in the field's frame, interpolated 
+    over the same triangles the field is drawn with. None outside the field */
+    pub fn height_at(&self, x: f32, y: f32) -> Option<f32> {
+        if self.nrow < 2 || self.ncol < 2 || x.abs() > self.size[0] || y.abs() > self.size[1] {
+            return None;
+        }
+        let u = (x / self.size[0] + 1.0) / 2.0 * (self.ncol - 1) as f32;
+        let v = (y / self.size[1] + 1.0) / 2.0 * (self.nrow - 1) as f32;
+        let col = (u.floor() as usize).min(self.ncol - 2);
+        let row = (v.floor() as usize).min(self.nrow - 2);
+        let (s, t) = (u - col as f32, v - row as f32);
+        let h = |r: usize, c: usize| self.size[2] * self.get_elevation(r, c);
+        //Cells are split along the diagonal from (row, col) to (row + 1, col + 1)
+        let height = if s >= t {
+            h(row, col) + s * (h(row, col + 1) - h(row, col)) + t * (h(row + 1, col + 1) - h(row, col + 1))
+        } else {
+            h(row, col) + t * (h(row + 1, col) - h(row, col)) + s * (h(row + 1, col + 1) - h(row + 1, col))
+        };
+        Some(height)
+    }
+
+    /* The field's surface, sides & base as triangles in its own frame */
+    pub fn triangulate(&self) -> primitives::TriangleMesh {
+        let points: Vec<Vector3<f32>> = (0..self.nrow).flat_map(|row| {
+            (0..self.ncol).map(move |col| (row, col))
+        }).map(|(row, col)| self.grid_point(row, col)).collect();
+        primitives::height_field(self.nrow, self.ncol, &points, self.size[3])
+    }
+}
+
+impl NodeType for Hfield {
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError> {
+        if self.attrs_map.contains_key(&key) {
+            return Ok(false);
+        }
+        match key.as_str() {
+            "name" => {
+                self.name = value.clone();
+            },
+            "file" => {
+                self.file = value.clone();
+            },
+            "nrow" | "ncol" => {
+                let count = parse_attr_values::<usize>(&key, &value, 1..=1)?[0];
+                if key == "nrow" {
+                    self.nrow = count;
+                } else {
+                    self.ncol = count;
+                }
+            },
+            "elevation" => {
+                self.elevation = parse_attr_values::<f32>(&key, &value, 1..=usize::MAX)?;
+            },
+            "size" => {
+                let values = parse_attr_values::<f32>(&key, &value, 4..=4)?;
+                self.size = [values[0], values[1], values[2], values[3]];
+            },
+            _ => ()
+        }
+        self.attrs_map.insert(key, value);
+        Ok(true)
+    }
+
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
+        for (key, value) in attrs {
+            self.add_attr(key, value)?;
+        }
+        Ok(())
+    }
+
+    fn get_node_name(&self) -> String {
+        "hfield".to_string()
+    }
+
+    fn get_name(&self) -> String {
+        if self.attrs_map.contains_key("name") {
+            self.name.clone()
+        } else {
+            asset_name_from_file(&self.file)
+        }
+    }
+
+    fn get_set_attributes_list(&self) -> HashMap<String,String> {
+        self.attrs_map.clone()
+    }
+}
+
+/* The <asset> section. The assets themselves are its children in the scene 
+graph, so lookups take the graph & the asset node's id */
+#[derive(Debug, Clone)]
+pub struct AssetsManager {}
+
+impl AssetsManager {
+    pub fn new() -> Self {
+        AssetsManager {}
+    }
+
+    fn find_asset(graph: &SceneGraph, assets: NodeId, kind: &str, name: &str) -> Option<NodeId> {
+        graph.children(assets).iter().copied().find(|child| {
+            let node = graph.get(*child);
+            node.get_node_name() == kind && node.get_name() == name
+        })
+    }
+
+    pub fn get_mesh(graph: &SceneGraph, assets: NodeId, name: &str) -> Option<NodeId> {
+        Self::find_asset(graph, assets, "mesh", name)
+    }
+
+    pub fn get_material(graph: &SceneGraph, assets: NodeId, name: &str) -> Option<NodeId> {
+        Self::find_asset(graph, assets, "material", name)
+    }
+
+    pub fn get_hfield(graph: &SceneGraph, assets: NodeId, name: &str) -> Option<NodeId> {
+        Self::find_asset(graph, assets, "hfield", name)
+    }
+
+    /* Links every material with the texture sharing its name */
+    pub fn process_materials(graph: &mut SceneGraph, assets: NodeId) {
+        let children = graph.children(assets).to_vec();
+        for child in children {
+            if graph.get(child).get_node_name() != "material" {
+                continue;
+            }
+            let name = graph.get(child).get_name();
+            if let Some(texture) = Self::find_asset(graph, assets, "texture", &name) {
+                match graph.get_mut(child) {
+                    Node::Material(m) => {
+                        m.add_texture(texture);
+                    },
+                    _ => ()
+                }
+            }
+        }
+    }
+
+    pub fn to_load_texture_files(graph: &SceneGraph, assets: NodeId) -> Vec<String> {
+        let mut files = Vec::new();
+        for child in graph.children(assets) {
+            match graph.get(*child) {
+                Node::Texture(t) => {
+                    if t.file != "" {
+                        files.push(t.file.clone());
+                    }
+                },
+                _ => ()
+            }
+        }
+        files
+    }
+
+    pub fn to_load_mesh_files(graph: &SceneGraph, assets: NodeId) -> Vec<String> {
+        let mut files = Vec::new();
+        for child in graph.children(assets) {
+            match graph.get(*child) {
                 Node::Mesh(m) => {
                     match &m.mesh_contents_type {
-                        MeshType::ObjFile => {
+                        MeshType::File => {
                             files.push(m.file.clone());
                         },
                         _ => ()
@@ -1368,18 +2021,21 @@ impl AssetsManager {
         files
     }
 
-    pub fn load_obj_meshes(&self,files: HashMap<String,String>) {
-        for child in &self.children {
-            match &mut *child.borrow_mut() {
+    /* Sets up file meshes (OBJ, STL or PLY) from their raw contents & those
+    of their material libraries, returning the file name & parse error for 
+    the first file that fails */
+    pub fn load_mesh_files(graph: &mut SceneGraph, assets: NodeId, 
+            files: HashMap<String,(Vec<u8>,HashMap<String,Vec<u8>>)>) -> Result<(),(String,String)> {
+        let children = graph.children(assets).to_vec();
+        for child in children {
+            match graph.get_mut(child) {
                 Node::Mesh(m) => {
                     match m.mesh_contents_type {
-                        MeshType::ObjFile => {
-                            if let Some(file_raw) = files.get(&m.get_file()) {
-                                println!("Loading obj file in asset manager to mesh {:?} {:?}",
-                                    m.file,m.file);
-                                //WARNING: Performance hit, this might be an expensive clone:
-                                m.setup_with_obj_stream(file_raw.clone());
-
+                        MeshType::File => {
+                            if let Some((file_raw, materials)) = files.get(&m.get_file()) {
+                                m.setup_with_file_data(file_raw, materials).map_err(|error| {
+                                    (m.get_file(), error)
+                                })?;
                             }
                         },
                         _ => ()
@@ -1388,11 +2044,13 @@ impl AssetsManager {
                 _ => ()
             }
         }
+        Ok(())
     }
 
-    pub fn load_dimensions(&mut self, files: HashMap<String,(u32,u32)>) {
-        for child in &self.children {
-            match &mut *child.borrow_mut() {
+    pub fn load_dimensions(graph: &mut SceneGraph, assets: NodeId, files: HashMap<String,(u32,u32)>) {
+        let children = graph.children(assets).to_vec();
+        for child in children {
+            match graph.get_mut(child) {
                 Node::Texture(t) => {
                     let dimensions = files.get(&t.file);
                     println!("Loading file in asset manager {:?} {:?}",t.file,files.contains_key(&t.file));
@@ -1405,10 +2063,93 @@ impl AssetsManager {
         }
     }
 
-    pub fn get_textures_data(&self) -> Vec<Texture> {
+    pub fn to_load_hfield_files(graph: &SceneGraph, assets: NodeId) -> Vec<String> {
+        graph.children(assets).iter().filter_map(|child| match graph.get(*child) {
+            Node::Hfield(h) if h.get_file() != "" => Some(h.get_file()),
+            _ => None
+        }).collect()
+    }
+
+    pub fn to_load_mesh_texture_files(graph: &SceneGraph, assets: NodeId) -> Vec<String> {
+        let mut files: Vec<String> = graph.children(assets).iter().flat_map(|child| match graph.get(*child) {
+            Node::Mesh(m) => m.get_diffuse_texture_files(),
+            _ => Vec::new()
+        }).collect();
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    pub fn load_mesh_textures(graph: &mut SceneGraph, assets: NodeId, files: HashMap<String,(u32,u32,Vec<u8>)>) {
+        let children = graph.children(assets).to_vec();
+        for child in children {
+            if let Node::Mesh(m) = graph.get_mut(child) {
+                m.load_textures(&files);
+            }
+        }
+    }
+
+    /* Diffuse maps of all meshes, once each even when meshes share them */
+    pub fn get_mesh_textures(graph: &SceneGraph, assets: NodeId) -> Vec<Texture> {
+        let mut textures: HashMap<String,Texture> = HashMap::new();
+        for child in graph.children(assets) {
+            if let Node::Mesh(m) = graph.get(*child) {
+                for texture in m.get_diffuse_textures() {
+                    textures.insert(texture.get_name(), texture);
+                }
+            }
+        }
+        textures.into_values().collect()
+    }
+
+    /* Sets up the height fields from their decoded grayscale images, keyed by 
+    file, & checks them. Returns the name & problem for the first that's invalid */
+    pub fn load_hfields(graph: &mut SceneGraph, assets: NodeId,
+            files: HashMap<String,(u32,u32,Vec<u8>)>) -> Result<(),(String,String)> {
+        let children = graph.children(assets).to_vec();
+        for child in children {
+            if let Node::Hfield(h) = graph.get_mut(child) {
+                if let Some((width, height, luma)) = files.get(&h.get_file()) {
+                    h.load_image(*width, *height, luma);
+                }
+                h.finish().map_err(|message| (h.get_name(), message))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn inline_meshes(graph: &SceneGraph, assets: NodeId) -> Vec<NodeId> {
+        let mut meshes = Vec::new();
+        for child in graph.children(assets) {
+            match graph.get(*child) {
+                Node::Mesh(m) => {
+                    match &m.mesh_contents_type {
+                        MeshType::Inline => {
+                            meshes.push(*child);
+                        },
+                        _ => ()
+                    }
+                },
+                _ => ()
+            }
+        }
+        meshes
+    }
+
+    /* Runs the load time processing over every mesh asset */
+    pub fn process_meshes(graph: &mut SceneGraph, assets: NodeId, processing: &mesh_ops::MeshProcessing) {
+        let children = graph.children(assets).to_vec();
+        for child in children {
+            if let Node::Mesh(m) = graph.get_mut(child) {
+                m.process(processing);
+            }
+        }
+    }
+
+    pub fn get_textures_data(graph: &SceneGraph, assets: NodeId) -> Vec<Texture> {
         let mut textures = Vec::new();
-        for child in &self.children {
-            match &*child.borrow() {
+        for child in graph.children(assets) {
+            match graph.get(*child) {
                 Node::Texture(m) => {
                     textures.push(m.clone());
                 },
@@ -1420,46 +2161,21 @@ impl AssetsManager {
 }
 
 impl NodeType for AssetsManager {
-    fn add_attr(&mut self, key: String, value: String) -> bool {
-        false
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError> {
+        Ok(false)
     }
 
-    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> () {
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
         for (key, value) in attrs {
-            self.add_attr(key, value);
+            self.add_attr(key, value)?;
         }
+        Ok(())
     }
     
-    fn add_child(&mut self, child: Node) -> Rc<RefCell<Node>> {
-        self.add_child_ref(Rc::new(RefCell::new(child)))
-    }
-
-    fn add_child_ref(&mut self, child: Rc<RefCell<Node>>) -> Rc<RefCell<Node>> {
-        self.children.insert(0,child.clone());
-        child
-    }
-
-    fn set_parent(&mut self, parent: Weak<RefCell<Node>>) -> () {
-        // self.parent = parent;
-    }
-    
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>> {
-        None
-        // self.parent.upgrade()
-    }
-
     fn get_node_name(&self) -> String {
         String::from_str("asset").expect("Error deriving string")
     }
 
-    fn get_children(&self) -> Vec<Rc<RefCell<Node>>> {
-        let mut children_c = Vec::new();
-        for child in &self.children {
-            children_c.push(child.clone());
-        }
-        children_c
-    }
-    
     fn get_name(&self) -> String {
         "".to_string()
     }
@@ -1469,10 +2185,8 @@ impl NodeType for AssetsManager {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct WorldBody {
-    children: Vec<Rc<RefCell<Node>>>,
-    parent: Weak<RefCell<Node>>,
     name: String,
     childclass: String,
     class: String,
@@ -1482,26 +2196,25 @@ pub struct WorldBody {
 impl WorldBody {
     pub fn default() -> Self {
         WorldBody {
-            children: Vec::new(),
             name: String::new(),
             childclass: String::new(),
             class: String::new(),
-            parent: Weak::new(),
             attrs_map: HashMap::new()
         }
     }
 
     /* Returns a dump of vertex & indices arrays holding the 
-        all the geometries in the base frame of reference */ 
-    pub fn getAllGeometries(&self) -> MeshCollection {
+        all the geometries in the base frame of reference. 
+        id is this worldbody's id in graph */ 
+    pub fn getAllGeometries(&self, graph: &SceneGraph, id: NodeId) -> MeshCollection {
         let mut meshes = MeshCollection::new();
-        for child in &self.children {
-            match *child.borrow_mut() {
-                Node::Body(ref bodyn) => {
-                    meshes = meshes.merge(bodyn.getAllGeometries());
+        for child in graph.children(id) {
+            match graph.get(*child) {
+                Node::Body(bodyn) => {
+                    meshes = meshes.merge(bodyn.getAllGeometries(graph, *child));
                 },
-                Node::Geom(ref geomn) => {
-                    meshes = meshes.merge(geomn.getAllGeometries());
+                Node::Geom(geomn) => {
+                    meshes = meshes.merge(geomn.getAllGeometries(graph));
                 },
                 _ => ()
             }
@@ -1511,70 +2224,41 @@ impl WorldBody {
 }
 
 impl NodeType for WorldBody {
-    fn add_attr(&mut self, key: String, value: String) -> bool {
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError> {
         if self.attrs_map.contains_key(&key) {
-            false
+            Ok(false)
         } else {
             match key.as_str() {
                 "class" => {
                     self.class = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "childclass" => {
                     self.childclass = value.clone();
-                    for child in &self.children {
-                        child.borrow_mut().add_attr(
-                            key.clone(), 
-                            value.clone());
-                    }
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "name" => {
                     self.name = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
-                _ => false
+                _ => {
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                }
             }
         }  
     }
 
-    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> () {
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
         for (key, value) in attrs {
-            self.add_attr(key, value);
+            self.add_attr(key, value)?;
         }
+        Ok(())
     }
     
-    fn add_child(&mut self, mut child: Node) -> Rc<RefCell<Node>> {
-        //set childclass attribute of children here 
-        if self.attrs_map.contains_key("childclass") {
-            child.add_attr("class".to_string(),self.childclass.clone());
-            child.add_attr("childclass".to_string(),self.childclass.clone());
-        }
-        self.children.insert(0,Rc::new(RefCell::new(child)));
-        self.children.get(0).expect("Issue returning child").clone()
-    }
-
-    fn add_child_ref(&mut self, child: Rc<RefCell<Node>>) -> Rc<RefCell<Node>> {
-        //set childclass attribute of children here 
-        if self.attrs_map.contains_key("childclass") {
-            child.borrow_mut().add_attr("class".to_string(),self.childclass.clone());
-            child.borrow_mut().add_attr("childclass".to_string(),self.childclass.clone());
-        }
-        self.children.insert(0,child.clone());
-        child
-    }
-
-    fn set_parent(&mut self, parent: Weak<RefCell<Node>>) -> () {
-        self.parent = parent;
-    }
-    
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>> {
-        self.parent.upgrade()
-    }
-
     fn get_node_name(&self) -> String {
         String::from_str("worldbody").expect("Error deriving string")
     }
@@ -1587,21 +2271,6 @@ impl NodeType for WorldBody {
         }
     }
 
-    fn get_children(&self) -> Vec<Rc<RefCell<Node>>> {
-        let mut children_c = Vec::new();
-        for child in &self.children {
-            children_c.push(child.clone());
-        }
-        children_c
-    }
-
-    fn apply_assets(&mut self,asset_manager: Rc<RefCell<Node>>) {
-        //just apply to children
-        for child in &self.children {
-            child.borrow_mut().apply_assets(asset_manager.clone());
-        }
-    }
-
     fn get_name(&self) -> String {
         self.name.clone()
     }
@@ -1611,257 +2280,167 @@ impl NodeType for WorldBody {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Body { 
     name: String,
-    children: Vec<Rc<RefCell<Node>>>,
-    parent: Weak<RefCell<Node>>,
     class: String,
     childclass: String,
-    pos: (f32,f32,f32),
     orientation: Orientation,
+    //Local pose from pos, scale & the resolved orientation
+    pose: Transform,
     attrs_map: HashMap<String,String>,
-    scale: (f32,f32,f32),
-    added_rotations: Vec<Orientation>
+    //Rotations applied at runtime (e.g. by plugins) on top of the MJCF orientation
+    added_rotation: UnitQuaternion<f32>,
+    //Combined displacement of the body's joints at their current positions
+    joint_motion: Isometry3<f32>
 }
 
 impl Body { 
     pub fn default() -> Self {
         Body {
             name: String::new(),
-            children: Vec::new(),
             class: String::new(),
             childclass: String::new(),
-            pos: (0.0,0.0,0.0),
-            scale: (1.0,1.0,1.0),
-            parent: Weak::new(),
-            orientation: Orientation::Euler(Euler{x:0.0,y:0.0,z:0.0}),
+            orientation: Orientation::identity(),
+            pose: Transform::identity(),
             attrs_map: HashMap::new(),
-            added_rotations: Vec::new()
+            added_rotation: UnitQuaternion::identity(),
+            joint_motion: Isometry3::identity()
         }
     }
 
-    fn get_rotation_params(orientation: &Orientation) -> (f32,f32,f32) {
-        if let Orientation::Euler(e) = &orientation {
-            (e.x * (std::f64::consts::PI / 180.0) as f32,
-            e.y * (std::f64::consts::PI / 180.0) as f32,
-            e.z * (std::f64::consts::PI / 180.0) as f32)
-        } else {
-            //TODO: Handle for other forms of rotation
-            (0.0,0.0,0.0)
-        }
+    /* Converts the parsed orientation into the pose's rotation. 
+    Called once defaults & compiler settings are known */
+    pub fn resolve_orientation(&mut self, compiler: &Compiler) {
+        self.pose.isometry.rotation = self.orientation.to_quaternion(compiler);
     }
 
+    /* Turns the body about its own origin by rotation_params degrees, 
+    clockwise about the parent's x, y & z axes. Successive calls accumulate */
     pub fn apply_added_rotations(&mut self,rotation_params: (f32,f32,f32)) {
-        self.added_rotations.push(Orientation::Euler(
-            Euler{
-                x: rotation_params.0,
-                y: rotation_params.1,
-                z: rotation_params.2
-            }
-        ));
+        let applied = transform::euler_rotation(
+            rotation_params.0.to_radians(),
+            rotation_params.1.to_radians(),
+            rotation_params.2.to_radians()).inverse();
+        self.added_rotation = applied * self.added_rotation;
+        //Keep the accumulated rotation from drifting off unit length over many small steps
+        self.added_rotation.renormalize_fast();
     }
 
-    fn apply_transforms(&self,vertices: Vec<f32>) -> Vec<f32> {
-        let num_vertices = vertices.len() / 3;
-        let mut added_rotation_m = Matrix3::new(
-                    1.0,0.0,0.0,
-                    0.0, 1.0, 0.0,
-                    0.0, 0.0, 1.0
-            );
-        for added_r in &self.added_rotations {
-            let rotation_params = Self::get_rotation_params(added_r);
-            let applied_m = Matrix3::new(
-                    rotation_params.1.cos() * rotation_params.2.cos(), 
-                    rotation_params.1.cos() * rotation_params.2.sin(), 
-                    -rotation_params.1.sin(), 
-
-                    rotation_params.0.sin() * rotation_params.1.sin() * rotation_params.2.cos() - 
-                    rotation_params.0.cos() * rotation_params.2.sin(), 
-                    rotation_params.0.sin() * rotation_params.1.sin() * rotation_params.2.sin() + 
-                    rotation_params.0.cos() * rotation_params.2.cos(), 
-                    rotation_params.0.sin() * rotation_params.1.cos(), 
-
-                    rotation_params.0.cos() * rotation_params.1.sin() * rotation_params.2.cos() + 
-                    rotation_params.0.sin() * rotation_params.2.sin(),
-                    rotation_params.0.cos() * rotation_params.1.sin() * rotation_params.2.sin() - 
-                    rotation_params.0.sin() * rotation_params.2.cos(),
-                    rotation_params.0.cos() * rotation_params.1.cos()
-            );
-            added_rotation_m = applied_m * added_rotation_m;
-        }
-        let rotation_params = Self::get_rotation_params(&self.orientation);
-        let rotation_m = Matrix3::new(
-                rotation_params.1.cos() * rotation_params.2.cos(), 
-                rotation_params.1.cos() * rotation_params.2.sin(), 
-                -rotation_params.1.sin(), 
-
-                rotation_params.0.sin() * rotation_params.1.sin() * rotation_params.2.cos() - 
-                rotation_params.0.cos() * rotation_params.2.sin(), 
-                rotation_params.0.sin() * rotation_params.1.sin() * rotation_params.2.sin() + 
-                rotation_params.0.cos() * rotation_params.2.cos(), 
-                rotation_params.0.sin() * rotation_params.1.cos(), 
-
-                rotation_params.0.cos() * rotation_params.1.sin() * rotation_params.2.cos() + 
-                rotation_params.0.sin() * rotation_params.2.sin(),
-                rotation_params.0.cos() * rotation_params.1.sin() * rotation_params.2.sin() - 
-                rotation_params.0.sin() * rotation_params.2.cos(),
-                rotation_params.0.cos() * rotation_params.1.cos()
-        );
-        let scale_m = Matrix3::new(
-                self.scale.0, 0.0, 0.0,
-                0.0, self.scale.1, 0.0,
-                0.0, 0.0, self.scale.2,
-        );
-        let vertices_m = DMatrix::from_vec(3,num_vertices,vertices);
-        let mut vertices_mr = added_rotation_m * rotation_m * scale_m * vertices_m;
-        let mut translation = Vec::new();
-        for _i in 0..num_vertices {
-            translation.push(self.pos.0);
-            translation.push(self.pos.1);
-            translation.push(self.pos.2);
-        }
-        let translation_m = 
-                DMatrix::from_vec(3,num_vertices,translation);
-        vertices_mr += translation_m;
-        let vertices_r : Vec<f32> = Vec::from(vertices_mr.as_slice());
-        vertices_r
+    pub fn get_added_rotation(&self) -> UnitQuaternion<f32> {
+        self.added_rotation
+    }
+
+    /* Pose of the body in its parent's frame as given in the MJCF, 
+    without joint motion or added rotations */
+    pub fn get_model_pose(&self) -> &Transform {
+        &self.pose
+    }
+
+    pub fn set_joint_motion(&mut self, motion: Isometry3<f32>) {
+        self.joint_motion = motion;
+    }
+
+    /* Pose of the body in its parent's frame, including joint motion & added rotations */
+    pub fn get_local_pose(&self) -> Transform {
+        self.local_pose_with(&self.joint_motion)
     }
 
-    pub fn getAllGeometries(&self) -> MeshCollection {
+    /* Pose of the body in its parent's frame with its joints displacing it by 
+    joint_motion, including added rotations */
+    pub fn local_pose_with(&self, joint_motion: &Isometry3<f32>) -> Transform {
+        let mut pose = self.pose.clone();
+        pose.isometry = pose.isometry * joint_motion;
+        pose.isometry.rotation = self.added_rotation * pose.isometry.rotation;
+        pose
+    }
+
+    /* Returns the geometries of this body & its descendants, placed in 
+    the frame given by parent_pose. id is this body's id in graph */
+    pub fn get_geometries_in(&self, graph: &SceneGraph, id: NodeId, parent_pose: &Transform) -> MeshCollection {
         let mut meshes = MeshCollection::new();
-        for child in &self.children {
-            match *child.borrow_mut() {
-                Node::Body(ref bodyn) => {
-                    let child_meshes = bodyn.getAllGeometries();
-                    for mesh in child_meshes.drawable_meshes.iter() {
-                        meshes.add_drawable_mesh(
-                            self.apply_transforms(mesh.vertices.clone()), 
-                            mesh.indices.clone(), 
-                            mesh.texture_data.clone(),
-                        mesh.vertices_filling.clone());
-                    }
+        let pose = parent_pose.compose(&self.get_local_pose());
+        for child in graph.children(id) {
+            match graph.get(*child) {
+                Node::Body(bodyn) => {
+                    meshes = meshes.merge(bodyn.get_geometries_in(graph, *child, &pose));
                 },
-                Node::Geom(ref geomn) => {
-                    let child_meshes = geomn.getAllGeometries();
-                    for mesh in child_meshes.drawable_meshes.iter() {
-                        meshes.add_drawable_mesh(
-                            self.apply_transforms(mesh.vertices.clone()), 
-                            mesh.indices.clone(), 
-                            mesh.texture_data.clone(),
-                        mesh.vertices_filling.clone());
-                    }
+                Node::Geom(geomn) => {
+                    meshes = meshes.merge(geomn.get_geometries_in(graph, &pose));
                 },
                 _ => ()
             }
         }
         meshes
     }
+
+    pub fn getAllGeometries(&self, graph: &SceneGraph, id: NodeId) -> MeshCollection {
+        self.get_geometries_in(graph, id, &Transform::identity())
+    }
 }
 
 impl NodeType for Body {
-    fn add_attr(&mut self, key: String, value: String) -> bool { 
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError> {
         if self.attrs_map.contains_key(&key) {
-            false
+            Ok(false)
         } else {
             match key.as_str() {
                 "class" => {
                     self.class = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "childclass" => {
                     self.childclass = value.clone();
-                    for child in &self.children {
-                        child.borrow_mut().add_attr(
-                            key.clone(), 
-                            value.clone());
-                    }
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "pos" => {
-                    let values = value.split(" ");
-                    let values: Vec<&str> = values.collect();
-                    assert!(values.len() == 3,"Expected 3 position values only");
-                    self.pos.0 = values.get(0).expect("Expected position 0").parse::<f32>().unwrap();
-                    self.pos.1 = values.get(1).expect("Expected position 1").parse::<f32>().unwrap();
-                    self.pos.2 = values.get(2).expect("Expected position 2").parse::<f32>().unwrap();
+                    let values = parse_attr_values::<f32>(&key, &value, 3..=3)?;
+                    self.pose.isometry.translation = Translation3::new(values[0], values[1], values[2]);
                     self.attrs_map.insert(key.clone(), value.clone());
-                    self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "name" => {
                     self.name = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
-                },
-                "euler" => {
-                    let values = value.split(" ");
-                    let values: Vec<&str> = values.collect();
-                    assert!(values.len() == 3,"Expected 3 position values only");
-                    let mut orientation_ = Euler {x: 0.0, y: 0.0, z:0.0};
-                    orientation_.x = values.get(0).expect("Expected position 0").parse::<f32>().unwrap();
-                    orientation_.y = values.get(1).expect("Expected position 1").parse::<f32>().unwrap();
-                    orientation_.z = values.get(2).expect("Expected position 2").parse::<f32>().unwrap();
-                    self.orientation = Orientation::Euler(orientation_);
+                    Ok(true)
+                },
+                k if ORIENTATION_ATTRS.contains(&k) => {
+                    //Only one orientation form applies, the element's own one wins over defaults
+                    if ORIENTATION_ATTRS.iter().any(|k| self.attrs_map.contains_key(*k)) {
+                        return Ok(false);
+                    }
+                    self.orientation = Orientation::from_attr(&key, &value)?;
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "scale" => {
-                    let values = value.split_whitespace();
-                    let values: Vec<&str> = values.collect();
-                    self.scale.0 = values.get(0).expect("Expected position 0 for scale").parse::<f32>().unwrap();
-                    self.scale.1 = values.get(1).expect("Expected position 1 for scale").parse::<f32>().unwrap();
-                    self.scale.2 = values.get(2).expect("Expected position 2 for scale").parse::<f32>().unwrap();
+                    let values = parse_attr_values::<f32>(&key, &value, 3..=3)?;
+                    self.pose.scale = Vector3::new(values[0], values[1], values[2]);
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
-                _ => false
+                _ => {
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                }
             }
         }         
     }
 
-    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> () {
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
+        Orientation::check_single(&attrs)?;
         for (key, value) in attrs {
-            self.add_attr(key, value);
+            self.add_attr(key, value)?;
         }
+        Ok(())
     }
     
-    fn add_child(&mut self, mut child: Node) -> Rc<RefCell<Node>> {
-        //set childclass attribute of children here 
-        println!("Adding child node to body: {}",child.get_node_name());
-        if self.attrs_map.contains_key("childclass") {
-            child.add_attr("class".to_string(),self.childclass.clone());
-            child.add_attr("childclass".to_string(),self.childclass.clone());
-        }
-        self.children.insert(0,Rc::new(RefCell::new(child)));
-        self.children.get(0).expect("Issue returning child").clone()
-    }
-
-    fn add_child_ref(&mut self, child: Rc<RefCell<Node>>) -> Rc<RefCell<Node>> {
-        //set childclass attribute of children here 
-        println!("Adding child node tp body: {}",child.borrow_mut().get_node_name());
-        if self.attrs_map.contains_key("childclass") {
-            child.borrow_mut().add_attr("class".to_string(),self.childclass.clone());
-            child.borrow_mut().add_attr("childclass".to_string(),self.childclass.clone());
-        }
-        self.children.insert(0,child.clone());
-        child
-    }
-
     fn get_node_name(&self) -> String {
         String::from_str("body").expect("Error deriving string")
     }
 
-    fn set_parent(&mut self, parent: Weak<RefCell<Node>>) -> () {
-        self.parent = parent;
-    }
-    
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>> {
-        None
-    }
-
     fn get_class(&self) -> String {
         if self.class == "" {
             "main".to_string()
@@ -1870,21 +2449,6 @@ impl NodeType for Body {
         }
     }
 
-    fn get_children(&self) -> Vec<Rc<RefCell<Node>>> {
-        let mut children_c = Vec::new();
-        for child in &self.children {
-            children_c.push(child.clone());
-        }
-        children_c
-    }
-
-    fn apply_assets(&mut self,asset_manager: Rc<RefCell<Node>>) {
-        //just apply to children
-        for child in &self.children {
-            child.borrow_mut().apply_assets(asset_manager.clone());
-        }
-    }
-
     fn get_name(&self) -> String {
         self.name.clone()
     }
@@ -1894,77 +2458,285 @@ impl NodeType for Body {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Joint { 
     joint_type: JointType,
     name: String,
     class: String,
-    parent: Weak<RefCell<Node>>,
+    //Anchor & axis in the owning body's frame
+    pos: Vector3<f32>,
+    axis: Unit<Vector3<f32>>,
+    //Joint position, nq() values laid out as in MuJoCo's qpos
+    qpos: Vec<f32>,
+    //range, ref & springref as written; angles are in the compiler's unit until scaled by angle_scale
+    range: Option<(f32,f32)>,
+    //None is "auto": limited when a range is set
+    limited: Option<bool>,
+    reference: f32,
+    springref: f32,
+    angle_scale: f32,
+    stiffness: f32,
+    damping: f32,
+    armature: f32,
+    frictionloss: f32,
     attrs_map: HashMap<String,String>
 }
 
 impl Joint { 
     pub fn default() -> Self {
         Joint {
-            joint_type: JointType::Free,
+            joint_type: JointType::Hinge,
             name: String::new(),
             class: String::new(),
-            parent: Weak::new(),
+            pos: Vector3::zeros(),
+            axis: Vector3::z_axis(),
+            qpos: vec![0.0],
+            range: None,
+            limited: None,
+            reference: 0.0,
+            springref: 0.0,
+            angle_scale: 1.0,
+            stiffness: 0.0,
+            damping: 0.0,
+            armature: 0.0,
+            frictionloss: 0.0,
             attrs_map: HashMap::new()
         }
     }
+
+    /* Hinge & ball angles are written in the compiler's angle unit, 
+    positions are in radians. Called once compiler settings are known */
+    pub fn resolve_angles(&mut self, compiler: &Compiler) {
+        self.angle_scale = match self.joint_type {
+            JointType::Hinge | JointType::Ball => compiler.angle_to_radians(1.0),
+            JointType::Slide | JointType::Free => 1.0
+        };
+    }
+
+    /* Joint limits in qpos units. For ball joints only the upper value is 
+    used, as the largest rotation angle */
+    pub fn get_range(&self) -> Option<(f32,f32)> {
+        self.range.map(|(low, high)| (low * self.angle_scale, high * self.angle_scale))
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.range.is_some() && self.limited != Some(false)
+    }
+
+    /* Position of a hinge or slide at which the body is where the MJCF places it */
+    pub fn get_ref(&self) -> f32 {
+        self.reference * self.angle_scale
+    }
+
+    /* Position at which the joint's spring is at rest */
+    pub fn get_springref(&self) -> f32 {
+        self.springref * self.angle_scale
+    }
+
+    pub fn get_stiffness(&self) -> f32 {
+        self.stiffness
+    }
+
+    pub fn get_damping(&self) -> f32 {
+        self.damping
+    }
+
+    pub fn get_armature(&self) -> f32 {
+        self.armature
+    }
+
+    pub fn get_frictionloss(&self) -> f32 {
+        self.frictionloss
+    }
+
+    /* Keeps a hinge or slide position within the joint's range when limited */
+    pub fn clamp_to_range(&self, qpos: f32) -> f32 {
+        match self.get_range() {
+            Some((low, high)) if self.is_limited() && 
+                (self.joint_type == JointType::Hinge || self.joint_type == JointType::Slide) => qpos.clamp(low, high),
+            _ => qpos
+        }
+    }
+
+    pub fn get_joint_type(&self) -> JointType {
+        self.joint_type
+    }
+
+    pub fn get_pos(&self) -> Vector3<f32> {
+        self.pos
+    }
+
+    pub fn get_axis(&self) -> Unit<Vector3<f32>> {
+        self.axis
+    }
+
+    /* Number of qpos values: position & quaternion for free joints, a 
+    quaternion for ball joints & a single value for hinges & slides */
+    pub fn nq(&self) -> usize {
+        match self.joint_type {
+            JointType::Free => 7,
+            JointType::Ball => 4,
+            JointType::Slide | JointType::Hinge => 1
+        }
+    }
+
+    /* The joint position the model starts in. Free joints start where their 
+    body was placed, body_pose being the body's pose in its parent's frame */
+    pub fn qpos0(&self, body_pose: &Isometry3<f32>) -> Vec<f32> {
+        match self.joint_type {
+            JointType::Free => {
+                let t = body_pose.translation.vector;
+                let q = body_pose.rotation;
+                vec![t.x, t.y, t.z, q.w, q.i, q.j, q.k]
+            },
+            JointType::Ball => vec![1.0, 0.0, 0.0, 0.0],
+            JointType::Slide | JointType::Hinge => vec![self.get_ref()]
+        }
+    }
+
+    /* Number of degrees of freedom: rotation & translation for free joints, 
+    rotation about the body's x, y & z for ball joints & one for the rest */
+    pub fn nv(&self) -> usize {
+        match self.joint_type {
+            JointType::Free => 6,
+            JointType::Ball => 3,
+            JointType::Slide | JointType::Hinge => 1
+        }
+    }
+
+    pub fn get_qpos(&self) -> &[f32] {
+        &self.qpos
+    }
+
+    /* Takes the first nq() values of qpos */
+    pub fn set_qpos(&mut self, qpos: &[f32]) {
+        self.qpos = qpos[..self.nq()].to_vec();
+    }
+
+    /* Displacement of the owning body in its own frame caused by the joint at its 
+    current position. Free joints place the body directly, so the displacement is 
+    relative to body_pose, the body's pose in its parent's frame as modelled */
+    pub fn motion(&self, body_pose: &Isometry3<f32>) -> Isometry3<f32> {
+        self.motion_at(&self.qpos, body_pose)
+    }
+
+    /* Same as motion, with the joint at qpos rather than at its current position */
+    pub fn motion_at(&self, qpos: &[f32], body_pose: &Isometry3<f32>) -> Isometry3<f32> {
+        match self.joint_type {
+            JointType::Hinge => {
+                let rotation = UnitQuaternion::from_axis_angle(&self.axis, qpos[0] - self.get_ref());
+                //Rotation about the axis through pos rather than through the body's origin
+                Isometry3::from_parts(Translation3::from(self.pos - rotation * self.pos), rotation)
+            },
+            JointType::Slide => {
+                Isometry3::from_parts(Translation3::from(self.axis.into_inner() * (qpos[0] - self.get_ref())), 
+                    UnitQuaternion::identity())
+            },
+            JointType::Ball => {
+                let rotation = UnitQuaternion::from_quaternion(
+                    Quaternion::new(qpos[0], qpos[1], qpos[2], qpos[3]));
+                Isometry3::from_parts(Translation3::from(self.pos - rotation * self.pos), rotation)
+            },
+            JointType::Free => {
+                let placed = Isometry3::from_parts(
+                    Translation3::new(qpos[0], qpos[1], qpos[2]),
+                    UnitQuaternion::from_quaternion(
+                        Quaternion::new(qpos[3], qpos[4], qpos[5], qpos[6])));
+                body_pose.inverse() * placed
+            }
+        }
+    }
 }
 
 impl NodeType for Joint {
-    fn add_attr(&mut self, key: String, value: String) -> bool { 
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError> {
         if self.attrs_map.contains_key(&key) {
-            false
+            Ok(false)
         } else {
             match key.as_str() {
                 "class" => {
                     self.class = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "name" => {
                     self.name = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
+                },
+                "type" => {
+                    self.joint_type = match value.as_str() {
+                        "free" => JointType::Free,
+                        "ball" => JointType::Ball,
+                        "slide" => JointType::Slide,
+                        "hinge" => JointType::Hinge,
+                        _ => return Err(AttributeError::new(&key, &value, 
+                            "expected free, ball, slide or hinge".to_string()))
+                    };
+                    self.qpos = vec![0.0; self.nq()];
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                },
+                "pos" => {
+                    let values = parse_attr_values::<f32>(&key, &value, 3..=3)?;
+                    self.pos = Vector3::new(values[0], values[1], values[2]);
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                },
+                "axis" => {
+                    let values = parse_attr_values::<f32>(&key, &value, 3..=3)?;
+                    self.axis = Unit::try_new(Vector3::new(values[0], values[1], values[2]), f32::EPSILON).
+                        ok_or(AttributeError::new(&key, &value, "axis has zero length".to_string()))?;
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                },
+                "range" => {
+                    let values = parse_attr_values::<f32>(&key, &value, 2..=2)?;
+                    if values[0] > values[1] {
+                        return Err(AttributeError::new(&key, &value, "lower bound exceeds upper bound".to_string()));
+                    }
+                    self.range = Some((values[0], values[1]));
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                },
+                "limited" => {
+                    self.limited = parse_limited(&key, &value)?;
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                },
+                "ref" | "springref" | "stiffness" | "damping" | "armature" | "frictionloss" => {
+                    let v = parse_attr_values::<f32>(&key, &value, 1..=1)?[0];
+                    match key.as_str() {
+                        "ref" => self.reference = v,
+                        "springref" => self.springref = v,
+                        "stiffness" => self.stiffness = v,
+                        "damping" => self.damping = v,
+                        "armature" => self.armature = v,
+                        _ => self.frictionloss = v
+                    }
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
                 },
-                _ => false
+                _ => {
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                }
             }
         }  
         
     }
 
-    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> () {
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
         for (key, value) in attrs {
-            self.add_attr(key, value);
+            self.add_attr(key, value)?;
         }
+        Ok(())
     }
     
-    fn add_child(&mut self, child: Node) -> Rc<RefCell<Node>> {
-        println!("Warning: Joint can't have children. Skipping add_child.");
-        Rc::new(RefCell::new(child))
-    }
-
-    fn add_child_ref(&mut self, child: Rc<RefCell<Node>>) -> Rc<RefCell<Node>> {
-        println!("Warning: Joint can't have children. Skipping add_child_ref.");
-        child
-    }
-
     fn get_node_name(&self) -> String {
         String::from_str("joint").expect("Error deriving string")
     }
 
-    fn set_parent(&mut self, parent: Weak<RefCell<Node>>) -> () {
-        self.parent = parent;
-    }
-    
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>> {
-        None
-    }
-
     fn get_class(&self) -> String {
         if self.class == "" {
             "main".to_string()
@@ -1973,13 +2745,6 @@ impl NodeType for Joint {
         }
     }
 
-    fn get_children(&self) -> Vec<Rc<RefCell<Node>>> {
-        Vec::new()
-    }
-
-    fn apply_assets(&mut self,asset_manager: Rc<RefCell<Node>>) {
-    }
-
     fn get_name(&self) -> String {
         self.name.clone()
     }
@@ -1989,26 +2754,32 @@ impl NodeType for Joint {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Geom {
     geom_t: GeomType,
     name: String,
     class: String,
     childclass: String,
-    // children: Vec<Rc<RefCell<Node>>>,
-    parent: Option<Weak<RefCell<Node>>>,
     orientation: Orientation,
-    mesh: Option<Rc<RefCell<Node>>>,
-    material: Option<Rc<RefCell<Node>>>,
+    //Local pose from pos & the resolved orientation
+    pose: Transform,
+    mesh: Option<NodeId>,
+    material: Option<NodeId>,
+    hfield: Option<NodeId>,
     mesh_name: String,
     material_name: String,
+    hfield_name: String,
     mass: f32,
     density: f32,
     size: [f32;3],
-    pos: (f32,f32,f32),
+    //Segment the geom spans, sets its pose & length instead of pos & the orientation
+    fromto: Option<(Vector3<f32>,Vector3<f32>)>,
     attrs_map: HashMap<String,String>
 }
 
+//Half size drawn for planes of infinite extent (a zero size)
+const INFINITE_PLANE_HALF_SIZE: f32 = 1.0;
+
 impl Geom { 
     pub fn default() -> Self {
         Geom {
@@ -2016,88 +2787,137 @@ impl Geom {
             name: String::new(),
             class: String::new(),
             childclass: String::new(),
-            parent: None,
-            // children: Vec::new(),
             size: [0.0,0.0,0.0],
-            pos: (0.0,0.0,0.0),
             mesh: None,
             material: None,
+            hfield: None,
             mesh_name: "".to_string(),
             material_name: "".to_string(),
+            hfield_name: "".to_string(),
             mass: 0.0,
-            density: 0.0,
-            orientation: Orientation::Euler(Euler{x:0.0,y:0.0,z:0.0}),
+            density: 1000.0,
+            fromto: None,
+            orientation: Orientation::identity(),
+            pose: Transform::identity(),
             attrs_map: HashMap::new()
         }
     }
 
-    fn get_rotation_params(&self) -> (f32,f32,f32) {
-        if let Orientation::Euler(e) = &self.orientation {
-            (e.x * (std::f64::consts::PI / 180.0) as f32,
-            e.y * (std::f64::consts::PI / 180.0) as f32,
-            e.z * (std::f64::consts::PI / 180.0) as f32)
+    pub fn resolve_orientation(&mut self, compiler: &Compiler) {
+        self.pose.isometry.rotation = self.orientation.to_quaternion(compiler);
+        self.resolve_fromto();
+    }
+
+    /* As in MuJoCo, fromto places capsules, cylinders, boxes & ellipsoids midway 
+    along the segment with their z axis along it & half its length as the size 
+    along z. Other geom types ignore it */
+    fn resolve_fromto(&mut self) {
+        let Some((from, to)) = self.fromto else {
+            return;
+        };
+        let length_index = match self.geom_t {
+            GeomType::Capsule | GeomType::Cylinder => 1,
+            GeomType::Box | GeomType::Ellipsoid => 2,
+            _ => return
+        };
+        let direction = to - from;
+        self.pose.isometry.translation = Translation3::from((from + to) / 2.0);
+        self.pose.isometry.rotation = UnitQuaternion::rotation_between(&Vector3::z(), &direction).
+            unwrap_or(UnitQuaternion::from_axis_angle(&Vector3::x_axis(), std::f32::consts::PI));
+        self.size[length_index] = direction.norm() / 2.0;
+    }
+
+    /* Pose of the geom in its parent body's frame */
+    pub fn get_local_pose(&self) -> Transform {
+        self.pose.clone()
+    }
+
+    pub fn get_geom_type(&self) -> GeomType {
+        self.geom_t
+    }
+
+    pub fn get_size(&self) -> [f32;3] {
+        self.size
+    }
+
+    pub fn get_mesh(&self) -> Option<NodeId> {
+        self.mesh
+    }
+
+    pub fn get_hfield(&self) -> Option<NodeId> {
+        self.hfield
+    }
+
+    /* The first asset the geom refers to, e.g. to report it when there's no asset section to link it against */
+    pub fn get_asset_ref(&self) -> Option<MissingAssetRef> {
+        let asset = match self.geom_t {
+            GeomType::Mesh => Some(("mesh", &self.mesh_name)),
+            GeomType::Hfield => Some(("hfield", &self.hfield_name)),
+            _ => None
+        };
+        asset.or(self.attrs_map.contains_key("material").then_some(("material", &self.material_name))).
+            map(|(kind, name)| MissingAssetRef {
+                kind: kind.to_string(),
+                name: name.clone()
+            })
+    }
+
+    /* The mass when set on the geom, it then takes precedence over the density */
+    pub fn get_mass(&self) -> Option<f32> {
+        if self.attrs_map.contains_key("mass") {
+            Some(self.mass)
         } else {
-            //TODO: Handle for other forms of rotation
-            (0.0,0.0,0.0)
+            None
         }
     }
 
-    fn apply_transforms(&self,vertices: Vec<f32>) -> Vec<f32> {
-        let num_vertices = vertices.len() / 3;
-        let rotation_params = self.get_rotation_params();
-        let rotation_m = Matrix3::new(
-                rotation_params.1.cos() * rotation_params.2.cos(), 
-                rotation_params.1.cos() * rotation_params.2.sin(), 
-                -rotation_params.1.sin(), 
-
-                rotation_params.0.sin() * rotation_params.1.sin() * rotation_params.2.cos() - 
-                rotation_params.0.cos() * rotation_params.2.sin(), 
-                rotation_params.0.sin() * rotation_params.1.sin() * rotation_params.2.sin() + 
-                rotation_params.0.cos() * rotation_params.2.cos(), 
-                rotation_params.0.sin() * rotation_params.1.cos(), 
-
-                rotation_params.0.cos() * rotation_params.1.sin() * rotation_params.2.cos() + 
-                rotation_params.0.sin() * rotation_params.2.sin(),
-                rotation_params.0.cos() * rotation_params.1.sin() * rotation_params.2.sin() - 
-                rotation_params.0.sin() * rotation_params.2.cos(),
-                rotation_params.0.cos() * rotation_params.1.cos()
-        );
-        let vertices_m = DMatrix::from_vec(3,num_vertices,vertices);
-        let mut vertices_mr = rotation_m * vertices_m;
-        let mut translation = Vec::new();
-        for _i in 0..num_vertices {
-            translation.push(self.pos.0);
-            translation.push(self.pos.1);
-            translation.push(self.pos.2);
-        }
-        // println!("Translation matrix: {:?}; rotation matrix: {:?}",(self.pos.0,self.pos.1,self.pos.2),rotation_m);
-        let translation_m = 
-                DMatrix::from_vec(3,num_vertices,translation);
-        vertices_mr += translation_m;
-        let vertices_r : Vec<f32> = Vec::from(vertices_mr.as_slice());
-        vertices_r
+    pub fn get_density(&self) -> f32 {
+        self.density
     }
 
-    pub fn getAllGeometries(&self) -> MeshCollection {
+    /* Returns the geom's geometry placed in the frame given by parent_pose */
+    pub fn get_geometries_in(&self, graph: &SceneGraph, parent_pose: &Transform) -> MeshCollection {
+        let pose = parent_pose.compose(&self.pose);
         let mut meshes = MeshCollection::new();
         match &self.geom_t {
             GeomType::Mesh => {
-                match &*(self.mesh.as_ref().expect("Was expecting mesh to be available").borrow()) {
-                    Node::Mesh(y) => {
-                        let mesh_geoms = y.getAllGeometries();
-                        let mesh_texture_data = self.get_textures_data();
-                        let mesh_vertices_filling = 
-                            self.get_vertices_filling(&mesh_geoms.0);
-                        // println!("Returning geom geometries: {} {} {:?} {}",
-                        //     mesh_geoms.0.len(),
-                        //     mesh_geoms.1.len(),
-                        // mesh_texture_data.as_ref().expect("Expected texture data"),
-                        // mesh_vertices_filling.as_ref().expect("Expected vertices filling").len());
-                        meshes.add_drawable_mesh(
-                            self.apply_transforms(mesh_geoms.0),
-                            mesh_geoms.1,
-                            mesh_texture_data,
-                            mesh_vertices_filling);
+                //Unlinked meshes are reported when loading, there's nothing to draw for them
+                match self.mesh.map(|mesh| graph.get(mesh)) {
+                    Some(Node::Mesh(y)) => {
+                        //Each part of the mesh is drawn on its own, so it can keep its file's material
+                        for part in y.get_parts() {
+                            let mut mesh_texture_data = self.get_textures_data(graph);
+                            let mut mesh_vertices_filling = self.get_vertices_filling(graph, &part.vertices);
+                            //Without a filling from the geom's own material, the file's material is used
+                            if let (None, Some(material)) = (&mesh_vertices_filling, &part.material) {
+                                match (y.get_diffuse_texture(material), &part.texcoords) {
+                                    (Some(texture), Some(texcoords)) => {
+                                        //OBJ texture coordinates start at the image's bottom row
+                                        mesh_vertices_filling = Some(texcoords.chunks(2).map(|uv| {
+                                            VertexFilling::TwoDTextureCoordinates(uv[0], 1.0 - uv[1])
+                                        }).collect());
+                                        mesh_texture_data = Some(texture);
+                                    },
+                                    _ => {
+                                        mesh_vertices_filling = Some(vec![VertexFilling::Color(material.diffuse[0], 
+                                            material.diffuse[1], material.diffuse[2]); part.vertices.len() / 3]);
+                                    }
+                                }
+                            }
+                            match part.normals {
+                                Some(normals) => meshes.add_drawable_mesh_with_normals(
+                                    pose.transform_vertices(part.vertices),
+                                    part.indices,
+                                    pose.transform_normals(normals),
+                                    mesh_texture_data,
+                                    mesh_vertices_filling),
+                                None => meshes.add_drawable_mesh(
+                                    pose.transform_vertices(part.vertices),
+                                    part.indices,
+                                    mesh_texture_data,
+                                    mesh_vertices_filling)
+                            }
+                        }
                     },
                     _ => {
                         println!("Expected mesh node to be available");
@@ -2106,45 +2926,91 @@ impl Geom {
             },
             GeomType::Sphere => {
                 let vertices = self.getSphereVertices();
-                let mesh_texture_data = self.get_textures_data();
+                let mesh_texture_data = self.get_textures_data(graph);
                 //TO FIX: Get filling before applying transforms!!
                 let mesh_vertices_filling = 
-                    self.get_vertices_filling(&vertices);
+                    self.get_vertices_filling(graph, &vertices);
                 meshes.add_drawable_mesh(
-                            self.apply_transforms(vertices),
+                            pose.transform_vertices(vertices),
                             Self::getSphereIndices(),
                             mesh_texture_data,
                         mesh_vertices_filling);
             },
             GeomType::Box => {
                 let vertices = self.getBoxVertices();
-                let mesh_texture_data = self.get_textures_data();
+                let mesh_texture_data = self.get_textures_data(graph);
                 //TO FIX: Get filling before applying transforms!!
                 let mesh_vertices_filling = 
-                    self.get_vertices_filling(&vertices);
+                    self.get_vertices_filling(graph, &vertices);
                 meshes.add_drawable_mesh(
-                            self.apply_transforms(vertices),
+                            pose.transform_vertices(vertices),
                             Self::getBoxIndices(),
                             mesh_texture_data,
                         mesh_vertices_filling);
             },
-            _ => {
-                println!("Unimplemented geometries for node");
+            GeomType::Hfield => {
+                //Unlinked height fields are reported when loading, there's nothing to draw for them
+                if let Some(Node::Hfield(h)) = self.hfield.map(|hfield| graph.get(hfield)) {
+                    let shape = h.triangulate();
+                    let mesh_texture_data = self.get_textures_data(graph);
+                    let mesh_vertices_filling = 
+                        self.get_vertices_filling(graph, &shape.vertices);
+                    meshes.add_drawable_mesh_with_normals(
+                                pose.transform_vertices(shape.vertices),
+                                shape.indices,
+                                pose.transform_normals(shape.normals),
+                                mesh_texture_data,
+                            mesh_vertices_filling);
+                }
+            },
+            GeomType::Capsule | GeomType::Cylinder | GeomType::Ellipsoid | GeomType::Plane => {
+                let shape = self.get_primitive_shape();
+                let mesh_texture_data = self.get_textures_data(graph);
+                let mesh_vertices_filling = 
+                    self.get_vertices_filling(graph, &shape.vertices);
+                meshes.add_drawable_mesh_with_normals(
+                            pose.transform_vertices(shape.vertices),
+                            shape.indices,
+                            pose.transform_normals(shape.normals),
+                            mesh_texture_data,
+                        mesh_vertices_filling);
+            },
+            GeomType::Sdf => {
+                //Signed distance fields come from plugins, which aren't supported, so nothing is drawn
             }
         }
         meshes
-}
+    }
+
+    pub fn getAllGeometries(&self, graph: &SceneGraph) -> MeshCollection {
+        self.get_geometries_in(graph, &Transform::identity())
+    }
+
+    /* Tessellates the geom types given by size alone, in the geom's frame. 
+    Capsules & cylinders take a radius & half length along z, ellipsoids 
+    their three radii & planes their half sizes along x & y */
+    fn get_primitive_shape(&self) -> primitives::TriangleMesh {
+        match self.geom_t {
+            GeomType::Capsule => primitives::capsule(self.size[0], self.size[1]),
+            GeomType::Cylinder => primitives::cylinder(self.size[0], self.size[1]),
+            GeomType::Ellipsoid => primitives::ellipsoid(self.size),
+            _ => {
+                let half_size = |s: f32| if s > 0.0 { s } else { INFINITE_PLANE_HALF_SIZE };
+                primitives::plane(half_size(self.size[0]), half_size(self.size[1]))
+            }
+        }
+    }
 
     fn getBoxVertices(&self) -> Vec<f32> {
         let mut box_vertices_r = Vec::new();
-        box_vertices_r.push(self.pos.0 - self.size[0]); box_vertices_r.push(self.pos.1 - self.size[1]); box_vertices_r.push(self.pos.2 - self.size[2]); //0
-        box_vertices_r.push(self.pos.0 - self.size[0]); box_vertices_r.push(self.pos.1 - self.size[1]); box_vertices_r.push(self.pos.2 + self.size[2]); //1
-        box_vertices_r.push(self.pos.0 - self.size[0]); box_vertices_r.push(self.pos.1 + self.size[1]); box_vertices_r.push(self.pos.2 - self.size[2]); //2
-        box_vertices_r.push(self.pos.0 - self.size[0]); box_vertices_r.push(self.pos.1 + self.size[1]); box_vertices_r.push(self.pos.2 + self.size[2]); //3
-        box_vertices_r.push(self.pos.0 + self.size[0]); box_vertices_r.push(self.pos.1 - self.size[1]); box_vertices_r.push(self.pos.2 - self.size[2]); //4
-        box_vertices_r.push(self.pos.0 + self.size[0]); box_vertices_r.push(self.pos.1 - self.size[1]); box_vertices_r.push(self.pos.2 + self.size[2]); //5
-        box_vertices_r.push(self.pos.0 + self.size[0]); box_vertices_r.push(self.pos.1 + self.size[1]); box_vertices_r.push(self.pos.2 - self.size[2]); //6
-        box_vertices_r.push(self.pos.0 + self.size[0]); box_vertices_r.push(self.pos.1 + self.size[1]); box_vertices_r.push(self.pos.2 + self.size[2]); //7
+        box_vertices_r.push(-self.size[0]); box_vertices_r.push(-self.size[1]); box_vertices_r.push(-self.size[2]); //0
+        box_vertices_r.push(-self.size[0]); box_vertices_r.push(-self.size[1]); box_vertices_r.push(self.size[2]); //1
+        box_vertices_r.push(-self.size[0]); box_vertices_r.push(self.size[1]); box_vertices_r.push(-self.size[2]); //2
+        box_vertices_r.push(-self.size[0]); box_vertices_r.push(self.size[1]); box_vertices_r.push(self.size[2]); //3
+        box_vertices_r.push(self.size[0]); box_vertices_r.push(-self.size[1]); box_vertices_r.push(-self.size[2]); //4
+        box_vertices_r.push(self.size[0]); box_vertices_r.push(-self.size[1]); box_vertices_r.push(self.size[2]); //5
+        box_vertices_r.push(self.size[0]); box_vertices_r.push(self.size[1]); box_vertices_r.push(-self.size[2]); //6
+        box_vertices_r.push(self.size[0]); box_vertices_r.push(self.size[1]); box_vertices_r.push(self.size[2]); //7
         box_vertices_r
     }
 
@@ -2297,33 +3163,33 @@ impl Geom {
     }
 
     fn getSphereVertices(&self) -> Vec<f32> {
-        Self::generateSphereMesh(32,[self.pos.0,self.pos.1,self.pos.2],self.size[0])
+        Self::generateSphereMesh(32,[0.0,0.0,0.0],self.size[0])
     }
     
     fn getSphereIndices() -> Vec<u32> {
         Self::generateSphereIndices(32)
     }
 
-    fn get_textures_data(&self) -> Option<Texture> {
+    fn get_textures_data(&self, graph: &SceneGraph) -> Option<Texture> {
         // println!("Getting textures data for geom class: {}",self.class);
         self.material.as_ref().map_or(None,|e| {
             // println!("Material available for geom class: {}",self.class);
-            match &*e.borrow() {
+            match graph.get(*e) {
                 Node::Material(m) => {
-                    m.get_textures_data()
+                    m.get_textures_data(graph)
                 },
                 _ => None
             }
         })
     }
 
-    fn get_vertices_filling(&self,vertices: &Vec<f32>) -> Option<Vec<VertexFilling>> {
+    fn get_vertices_filling(&self, graph: &SceneGraph, vertices: &Vec<f32>) -> Option<Vec<VertexFilling>> {
         // println!("Getting vertices filling for geom class: {} {:?}",self.class,vertices);
         self.material.as_ref().map_or(None,|e| {
             // println!("Material available for geom class: {}",self.class);
-            match &*e.borrow() {
+            match graph.get(*e) {
                 Node::Material(m) => {
-                    m.get_vertices_filling(vertices)
+                    m.get_vertices_filling(graph, vertices)
                 },
                 _ => None
             }
@@ -2332,49 +3198,41 @@ impl Geom {
 }
 
 impl NodeType for Geom {
-    fn add_attr(&mut self, key: String, value: String) -> bool { 
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError> {
         if self.attrs_map.contains_key(&key) {
-            false
+            Ok(false)
         } else {
             match key.as_str() {
                 "class" => {
                     println!("Setting class for geom node {}",value.clone());
                     self.class = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "pos" => {
-                    let values = value.split(" ");
-                    let values: Vec<&str> = values.collect();
-                    assert!(values.len() == 3,"Expected 3 position values only");
-                    self.pos.0 = values.get(0).expect("Expected position 0").parse::<f32>().unwrap();
-                    self.pos.1 = values.get(1).expect("Expected position 1").parse::<f32>().unwrap();
-                    self.pos.2 = values.get(2).expect("Expected position 2").parse::<f32>().unwrap();
+                    let values = parse_attr_values::<f32>(&key, &value, 3..=3)?;
+                    self.pose.isometry.translation = Translation3::new(values[0], values[1], values[2]);
                     self.attrs_map.insert(key.clone(), value.clone());
-                    self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "name" => {
                     self.name = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "material" => {
                     self.material_name = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
-                },
-                "euler" => {
-                    let values = value.split(" ");
-                    let values: Vec<&str> = values.collect();
-                    assert!(values.len() == 3,"Expected 3 position values only");
-                    let mut orientation_ = Euler {x: 0.0, y: 0.0, z:0.0};
-                    orientation_.x = values.get(0).expect("Expected position 0").parse::<f32>().unwrap();
-                    orientation_.y = values.get(1).expect("Expected position 1").parse::<f32>().unwrap();
-                    orientation_.z = values.get(2).expect("Expected position 2").parse::<f32>().unwrap();
-                    self.orientation = Orientation::Euler(orientation_);
+                    Ok(true)
+                },
+                k if ORIENTATION_ATTRS.contains(&k) => {
+                    //Only one orientation form applies, the element's own one wins over defaults
+                    if ORIENTATION_ATTRS.iter().any(|k| self.attrs_map.contains_key(*k)) {
+                        return Ok(false);
+                    }
+                    self.orientation = Orientation::from_attr(&key, &value)?;
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "type" => {
                     self.geom_t = match value.as_str() {
@@ -2383,106 +3241,691 @@ impl NodeType for Geom {
                         "sphere" => GeomType::Sphere,
                         "capsule" => GeomType::Capsule,
                         "ellipsoid" => GeomType::Ellipsoid,
+                        "cylinder" => GeomType::Cylinder,
                         "box" => GeomType::Box,
                         "mesh" => GeomType::Mesh,
                         "sdf" => GeomType::Sdf,
-                        _ => GeomType::Box,
+                        _ => return Err(AttributeError::new(&key, &value, 
+                            "expected plane, hfield, sphere, capsule, ellipsoid, cylinder, box, mesh or sdf".to_string()))
                     };
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
                 "mesh" => {
                     //check if the mesh asset is available, if yes, attach it
                     //otherwise skip & just attach the name 
                     self.mesh_name = value.clone();
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
+                },
+                "hfield" => {
+                    self.hfield_name = value.clone();
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
                 },
                 "size" => {
-                    let values = value.split(" ");
-                    let values: Vec<&str> = values.collect();
+                    let values = parse_attr_values::<f32>(&key, &value, 1..=3)?;
                     for i in 0..values.len() {
-                        self.size[i] = values.get(i).expect("Expected this position in size").parse::<f32>().unwrap();
+                        self.size[i] = values[i];
+                    }
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                },
+                "fromto" => {
+                    let values = parse_attr_values::<f32>(&key, &value, 6..=6)?;
+                    let from = Vector3::new(values[0], values[1], values[2]);
+                    let to = Vector3::new(values[3], values[4], values[5]);
+                    if from == to {
+                        return Err(AttributeError::new(&key, &value, "the two end points coincide".to_string()));
                     }
+                    self.fromto = Some((from, to));
                     self.attrs_map.insert(key.clone(), value.clone());
-                    true
+                    Ok(true)
                 },
-                _ => false
+                "mass" | "density" => {
+                    let v = parse_attr_values::<f32>(&key, &value, 1..=1)?[0];
+                    if v < 0.0 {
+                        return Err(AttributeError::new(&key, &value, format!("{} can't be negative", key)));
+                    }
+                    if key == "mass" {
+                        self.mass = v;
+                    } else {
+                        self.density = v;
+                    }
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                },
+                _ => {
+                    self.attrs_map.insert(key.clone(), value.clone());
+                    Ok(true)
+                }
             }
         } 
     }
 
-    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> () {
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
+        Orientation::check_single(&attrs)?;
         for (key, value) in attrs {
-            self.add_attr(key, value);
+            self.add_attr(key, value)?;
         }
+        Ok(())
     }
     
-    fn add_child(&mut self, child: Node) -> Rc<RefCell<Node>> {
-        println!("Warning: Geoms can't have children. Skipping add_child.");
-        Rc::new(RefCell::new(child))
+    fn get_node_name(&self) -> String {
+        String::from_str("geom").expect("Error deriving string")
     }
 
-    fn add_child_ref(&mut self, child: Rc<RefCell<Node>>) -> Rc<RefCell<Node>> {
-        println!("Warning: Geoms can't have children. Skipping add_child_ref.");
-        child
+    fn get_class(&self) -> String {
+        if self.class == "" {
+            "main".to_string()
+        } else {
+            String::from_str(&self.class).expect("Failed to create string")
+        }
     }
 
-    fn get_node_name(&self) -> String {
-        String::from_str("geom").expect("Error deriving string")
+    fn apply_assets(&mut self, graph: &SceneGraph, asset_manager: NodeId) -> Result<(),MissingAssetRef> {
+        match self.geom_t {
+            GeomType::Mesh => {
+                    println!("Applying mesh asset to geom node.");
+                    let mesh = AssetsManager::get_mesh(graph, asset_manager, &self.mesh_name).
+                        ok_or(MissingAssetRef {
+                            kind: "mesh".to_string(),
+                            name: self.mesh_name.clone()
+                        })?;
+                    self.mesh = Some(mesh);
+            },
+            GeomType::Hfield => {
+                    let hfield = AssetsManager::get_hfield(graph, asset_manager, &self.hfield_name).
+                        ok_or(MissingAssetRef {
+                            kind: "hfield".to_string(),
+                            name: self.hfield_name.clone()
+                        })?;
+                    self.hfield = Some(hfield);
+            },
+            _ => {}
+        }
+        if self.attrs_map.contains_key("material") {
+            let material = AssetsManager::get_material(graph, asset_manager, &self.material_name).
+                ok_or(MissingAssetRef {
+                    kind: "material".to_string(),
+                    name: self.material_name.clone()
+                })?;
+            self.material = Some(material);
+        }
+        Ok(())
     }
 
-    fn set_parent(&mut self, parent: Weak<RefCell<Node>>) -> () {
-        self.parent = Some(parent);
+    fn get_set_attributes_list(&self) -> HashMap<String,String> {
+        self.attrs_map.clone()
     }
-    
-    fn get_parent(&self) -> Option<Rc<RefCell<Node>>> {
-        None
+
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/* An MJCF element this crate doesn't interpret, e.g. <option>, <light> or 
+<actuator>. It's kept with its attributes & children so it still shows up in 
+the hierarchy & is written back out as it was read */
+#[derive(Debug, Clone)]
+pub struct RawElement {
+    tag: String,
+    name: String,
+    class: String,
+    attrs_map: HashMap<String,String>,
+    location: Option<SourceLocation>
+}
+
+impl RawElement {
+    pub fn new(tag: String, location: Option<SourceLocation>) -> Self {
+        RawElement {
+            tag: tag,
+            name: String::new(),
+            class: String::new(),
+            attrs_map: HashMap::new(),
+            location: location
+        }
+    }
+
+    /* Where the element was read from, None for elements created in code */
+    pub fn get_location(&self) -> Option<&SourceLocation> {
+        self.location.as_ref()
+    }
+
+    /* Takes on the childclass of an enclosing body for default lookups. Unlike 
+    a class attribute this isn't written out, as not every element accepts one */
+    pub fn inherit_class(&mut self, class: String) {
+        if self.class == "" {
+            self.class = class;
+        }
+    }
+}
+
+impl NodeType for RawElement {
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError> {
+        if self.attrs_map.contains_key(&key) {
+            Ok(false)
+        } else {
+            match key.as_str() {
+                "name" => {
+                    self.name = value.clone();
+                },
+                "class" => {
+                    self.class = value.clone();
+                },
+                _ => ()
+            }
+            self.attrs_map.insert(key, value);
+            Ok(true)
+        }
+    }
+
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
+        for (key, value) in attrs {
+            self.add_attr(key, value)?;
+        }
+        Ok(())
+    }
+
+    fn get_node_name(&self) -> String {
+        self.tag.clone()
     }
 
     fn get_class(&self) -> String {
         if self.class == "" {
             "main".to_string()
         } else {
-            String::from_str(&self.class).expect("Failed to create string")
+            self.class.clone()
         }
     }
 
-    fn get_children(&self) -> Vec<Rc<RefCell<Node>>> {
-        let children_c = Vec::new();
-        children_c
+    fn get_name(&self) -> String {
+        self.name.clone()
     }
 
-    fn apply_assets(&mut self,asset_manager: Rc<RefCell<Node>>) {
-        match &*asset_manager.borrow() {
-            Node::Assets(e) => {
-                match self.geom_t {
-                    GeomType::Mesh => {
-                            println!("Applying mesh asset to geom node.");
-                            let mesh = e.get_mesh(self.mesh_name.clone());
-                            self.mesh = Some(mesh.
-                                expect("Was expecting a mesh in assets when processing geoms.").
-                                clone());
-                    },
-                    _ => {}
+    fn get_set_attributes_list(&self) -> HashMap<String,String> {
+        self.attrs_map.clone()
+    }
+}
+
+/* An actuator from the <actuator> section. motor, position & velocity are 
+shortcuts that MuJoCo expands into general gain/bias parameters, which is also 
+what's done here. Only joint transmissions are linked, other targets (tendon, 
+site, ..) are kept as plain attributes */
+/* Elements that are read as actuators, within <actuator> & defaults */
+pub const ACTUATOR_TAGS: [&str; 4] = ["motor", "general", "position", "velocity"];
+
+#[derive(Debug, Clone)]
+pub struct Actuator {
+    actuator_type: ActuatorType,
+    name: String,
+    class: String,
+    joint: String,
+    //Joint named by the joint attribute, linked once the model is loaded
+    target: Option<NodeId>,
+    gaintype: GainType,
+    biastype: BiasType,
+    //First three gain & bias parameters, the rest only matter for muscles & user types
+    gainprm: [f32; 3],
+    biasprm: [f32; 3],
+    gear: f32,
+    ctrlrange: Option<(f32,f32)>,
+    forcerange: Option<(f32,f32)>,
+    //None is "auto": limited when the matching range is set
+    ctrllimited: Option<bool>,
+    forcelimited: Option<bool>,
+    //Current control input
+    ctrl: f32,
+    attrs_map: HashMap<String,String>
+}
+
+impl Actuator {
+    pub fn new(actuator_type: ActuatorType) -> Self {
+        let (gainprm, biastype, biasprm) = match actuator_type {
+            ActuatorType::Motor | ActuatorType::General => ([1.0, 0.0, 0.0], BiasType::None, [0.0, 0.0, 0.0]),
+            //kp defaults to 1 & kv to 0
+            ActuatorType::Position => ([1.0, 0.0, 0.0], BiasType::Affine, [0.0, -1.0, 0.0]),
+            //kv defaults to 1
+            ActuatorType::Velocity => ([1.0, 0.0, 0.0], BiasType::Affine, [0.0, 0.0, -1.0])
+        };
+        Actuator {
+            actuator_type: actuator_type,
+            name: String::new(),
+            class: String::new(),
+            joint: String::new(),
+            target: None,
+            gaintype: GainType::Fixed,
+            biastype: biastype,
+            gainprm: gainprm,
+            biasprm: biasprm,
+            gear: 1.0,
+            ctrlrange: None,
+            forcerange: None,
+            ctrllimited: None,
+            forcelimited: None,
+            ctrl: 0.0,
+            attrs_map: HashMap::new()
+        }
+    }
+
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "motor" => Some(Self::new(ActuatorType::Motor)),
+            "general" => Some(Self::new(ActuatorType::General)),
+            "position" => Some(Self::new(ActuatorType::Position)),
+            "velocity" => Some(Self::new(ActuatorType::Velocity)),
+            _ => None
+        }
+    }
+
+    pub fn get_actuator_type(&self) -> ActuatorType {
+        self.actuator_type
+    }
+
+    /* Name of the joint this actuator drives, empty for other transmissions */
+    pub fn get_joint_name(&self) -> &str {
+        &self.joint
+    }
+
+    pub fn get_joint(&self) -> Option<NodeId> {
+        self.target
+    }
+
+    pub fn set_joint(&mut self, joint: NodeId) {
+        self.target = Some(joint);
+    }
+
+    pub fn get_gear(&self) -> f32 {
+        self.gear
+    }
+
+    pub fn get_ctrl_range(&self) -> Option<(f32,f32)> {
+        self.ctrlrange
+    }
+
+    pub fn get_force_range(&self) -> Option<(f32,f32)> {
+        self.forcerange
+    }
+
+    pub fn is_ctrl_limited(&self) -> bool {
+        self.ctrlrange.is_some() && self.ctrllimited != Some(false)
+    }
+
+    pub fn is_force_limited(&self) -> bool {
+        self.forcerange.is_some() && self.forcelimited != Some(false)
+    }
+
+    pub fn get_ctrl(&self) -> f32 {
+        self.ctrl
+    }
+
+    /* Sets the control input, clamped to ctrlrange when the control is limited */
+    pub fn set_ctrl(&mut self, ctrl: f32) {
+        self.ctrl = match self.ctrlrange {
+            Some((low, high)) if self.is_ctrl_limited() => ctrl.clamp(low, high),
+            _ => ctrl
+        };
+    }
+
+    /* Generalized force on the target joint for the current control, given the 
+    joint's position & velocity. As in MuJoCo the actuator works on the joint 
+    scaled by gear, force = gain * ctrl + bias clamped to forcerange */
+    pub fn joint_force(&self, qpos: f32, qvel: f32) -> f32 {
+        let length = self.gear * qpos;
+        let velocity = self.gear * qvel;
+        let gain = match self.gaintype {
+            GainType::Fixed => self.gainprm[0],
+            GainType::Affine => self.gainprm[0] + self.gainprm[1] * length + self.gainprm[2] * velocity
+        };
+        let bias = match self.biastype {
+            BiasType::None => 0.0,
+            BiasType::Affine => self.biasprm[0] + self.biasprm[1] * length + self.biasprm[2] * velocity
+        };
+        let mut force = gain * self.ctrl + bias;
+        if let Some((low, high)) = self.forcerange {
+            if self.is_force_limited() {
+                force = force.clamp(low, high);
+            }
+        }
+        self.gear * force
+    }
+}
+
+fn parse_limited(key: &str, value: &str) -> Result<Option<bool>,AttributeError> {
+    match value {
+        "true" => Ok(Some(true)),
+        "false" => Ok(Some(false)),
+        "auto" => Ok(None),
+        _ => Err(AttributeError::new(key, value, "expected true, false or auto".to_string()))
+    }
+}
+
+/* Up to 10 parameters are allowed, only the first three are used */
+fn parse_prm(key: &str, value: &str) -> Result<[f32; 3],AttributeError> {
+    let values = parse_attr_values::<f32>(key, value, 1..=10)?;
+    let mut prm = [0.0; 3];
+    for (i, v) in values.iter().take(3).enumerate() {
+        prm[i] = *v;
+    }
+    Ok(prm)
+}
+
+impl NodeType for Actuator {
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError> {
+        if self.attrs_map.contains_key(&key) {
+            return Ok(false);
+        }
+        match key.as_str() {
+            "class" => {
+                self.class = value.clone();
+            },
+            "name" => {
+                self.name = value.clone();
+            },
+            "joint" => {
+                self.joint = value.clone();
+            },
+            "gear" => {
+                self.gear = parse_attr_values::<f32>(&key, &value, 1..=6)?[0];
+            },
+            "ctrlrange" | "forcerange" => {
+                let values = parse_attr_values::<f32>(&key, &value, 2..=2)?;
+                if values[0] > values[1] {
+                    return Err(AttributeError::new(&key, &value, "lower bound exceeds upper bound".to_string()));
                 }
-                if self.attrs_map.contains_key("material") {
-                    let material = 
-                        e.get_material(self.material_name.clone()).
-                        expect("Was expecting the material in assets when processing geoms").
-                        clone();
-                    self.material = Some(material);
+                if key == "ctrlrange" {
+                    self.ctrlrange = Some((values[0], values[1]));
+                } else {
+                    self.forcerange = Some((values[0], values[1]));
                 }
             },
-            _ => {}
+            "ctrllimited" => {
+                self.ctrllimited = parse_limited(&key, &value)?;
+            },
+            "forcelimited" => {
+                self.forcelimited = parse_limited(&key, &value)?;
+            },
+            "gaintype" => {
+                self.gaintype = match value.as_str() {
+                    "fixed" => GainType::Fixed,
+                    "affine" => GainType::Affine,
+                    _ => return Err(AttributeError::new(&key, &value, "supported types are fixed & affine".to_string()))
+                };
+            },
+            "biastype" => {
+                self.biastype = match value.as_str() {
+                    "none" => BiasType::None,
+                    "affine" => BiasType::Affine,
+                    _ => return Err(AttributeError::new(&key, &value, "supported types are none & affine".to_string()))
+                };
+            },
+            "gainprm" => {
+                self.gainprm = parse_prm(&key, &value)?;
+            },
+            "biasprm" => {
+                self.biasprm = parse_prm(&key, &value)?;
+            },
+            "kp" if self.actuator_type == ActuatorType::Position => {
+                let kp = parse_attr_values::<f32>(&key, &value, 1..=1)?[0];
+                self.gainprm[0] = kp;
+                self.biasprm[1] = -kp;
+            },
+            "kv" if self.actuator_type == ActuatorType::Position => {
+                self.biasprm[2] = -parse_attr_values::<f32>(&key, &value, 1..=1)?[0];
+            },
+            "kv" if self.actuator_type == ActuatorType::Velocity => {
+                let kv = parse_attr_values::<f32>(&key, &value, 1..=1)?[0];
+                self.gainprm[0] = kv;
+                self.biasprm[2] = -kv;
+            },
+            //Gains of the other shortcuts, e.g. from a default shared with a position actuator
+            "kp" | "kv" => return Ok(false),
+            _ => ()
+        }
+        self.attrs_map.insert(key, value);
+        Ok(true)
+    }
+
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
+        for (key, value) in attrs {
+            self.add_attr(key, value)?;
+        }
+        Ok(())
+    }
+
+    fn get_node_name(&self) -> String {
+        match self.actuator_type {
+            ActuatorType::Motor => "motor",
+            ActuatorType::General => "general",
+            ActuatorType::Position => "position",
+            ActuatorType::Velocity => "velocity"
+        }.to_string()
+    }
+
+    fn get_class(&self) -> String {
+        if self.class == "" {
+            "main".to_string()
+        } else {
+            self.class.clone()
         }
     }
 
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
     fn get_set_attributes_list(&self) -> HashMap<String,String> {
         self.attrs_map.clone()
     }
+}
+
+/* A body's explicit <inertial>: mass, center of mass at pos & principal inertia 
+about it, either diagonal in the inertial frame or as a full tensor */
+#[derive(Debug, Clone)]
+pub struct Inertial {
+    mass: f32,
+    pos: Vector3<f32>,
+    orientation: Orientation,
+    rotation: UnitQuaternion<f32>,
+    diaginertia: Option<Vector3<f32>>,
+    //ixx iyy izz ixy ixz iyz
+    fullinertia: Option<[f32;6]>,
+    attrs_map: HashMap<String,String>
+}
+
+impl Inertial {
+    pub fn new() -> Self {
+        Inertial {
+            mass: 0.0,
+            pos: Vector3::zeros(),
+            orientation: Orientation::identity(),
+            rotation: UnitQuaternion::identity(),
+            diaginertia: None,
+            fullinertia: None,
+            attrs_map: HashMap::new()
+        }
+    }
+
+    pub fn resolve_orientation(&mut self, compiler: &Compiler) {
+        self.rotation = self.orientation.to_quaternion(compiler);
+    }
+
+    pub fn get_mass(&self) -> f32 {
+        self.mass
+    }
+
+    /* Center of mass in the body's frame */
+    pub fn get_pos(&self) -> Vector3<f32> {
+        self.pos
+    }
+
+    /* Inertia tensor about the center of mass, in the body's frame */
+    pub fn get_inertia(&self) -> Matrix3<f32> {
+        let local = match (self.fullinertia, self.diaginertia) {
+            (Some(f), _) => Matrix3::new(
+                f[0], f[3], f[4],
+                f[3], f[1], f[5],
+                f[4], f[5], f[2]),
+            (None, Some(d)) => Matrix3::from_diagonal(&d),
+            (None, None) => Matrix3::zeros()
+        };
+        let r = self.rotation.to_rotation_matrix();
+        r.matrix() * local * r.matrix().transpose()
+    }
+}
+
+impl NodeType for Inertial {
+    fn add_attr(&mut self, key: String, value: String) -> Result<bool,AttributeError> {
+        if self.attrs_map.contains_key(&key) {
+            return Ok(false);
+        }
+        match key.as_str() {
+            "mass" => {
+                self.mass = parse_attr_values::<f32>(&key, &value, 1..=1)?[0];
+                if self.mass < 0.0 {
+                    return Err(AttributeError::new(&key, &value, "mass can't be negative".to_string()));
+                }
+            },
+            "pos" => {
+                let values = parse_attr_values::<f32>(&key, &value, 3..=3)?;
+                self.pos = Vector3::new(values[0], values[1], values[2]);
+            },
+            k if ORIENTATION_ATTRS.contains(&k) => {
+                if ORIENTATION_ATTRS.iter().any(|k| self.attrs_map.contains_key(*k)) {
+                    return Ok(false);
+                }
+                self.orientation = Orientation::from_attr(&key, &value)?;
+            },
+            "diaginertia" => {
+                let values = parse_attr_values::<f32>(&key, &value, 3..=3)?;
+                self.diaginertia = Some(Vector3::new(values[0], values[1], values[2]));
+            },
+            "fullinertia" => {
+                let values = parse_attr_values::<f32>(&key, &value, 6..=6)?;
+                self.fullinertia = Some([values[0], values[1], values[2], values[3], values[4], values[5]]);
+            },
+            _ => ()
+        }
+        self.attrs_map.insert(key, value);
+        Ok(true)
+    }
+
+    /* Inertials are set up from their own attributes only, so once they're all 
+    added the element as a whole is checked as MuJoCo does */
+    fn add_attrs(&mut self, attrs: Vec<(String,String)>) -> Result<(),AttributeError> {
+        for (key, value) in attrs {
+            self.add_attr(key, value)?;
+        }
+        if let Some(value) = self.attrs_map.get("fullinertia") {
+            if let Some(key) = ORIENTATION_ATTRS.iter().find(|k| self.attrs_map.contains_key(**k)) {
+                return Err(AttributeError::new("fullinertia", value, 
+                    format!("can't be combined with an orientation ({})", key)));
+            }
+        }
+        if !self.attrs_map.contains_key("mass") {
+            return Err(AttributeError::new("mass", "", "inertials need a mass".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_node_name(&self) -> String {
+        "inertial".to_string()
+    }
 
     fn get_name(&self) -> String {
-        self.name.clone()
+        "".to_string()
+    }
+
+    fn get_set_attributes_list(&self) -> HashMap<String,String> {
+        self.attrs_map.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+    use crate::model::tests::{load_str, load_str_with_options};
+
+    fn geom(model: &Model, name: &str) -> Geom {
+        match model.graph.get(model.find_by_name("geom", name).expect("Expected the geom")) {
+            Node::Geom(g) => g.clone(),
+            _ => panic!("Expected {} to be a geom", name)
+        }
+    }
+
+    #[test]
+    fn fromto_places_geoms_along_the_segment() {
+        let model = load_str("fromto", r#"<mujoco>
+            <worldbody>
+                <geom name="capsule" type="capsule" fromto="0 0 0 0 0 2" size="0.1"/>
+                <geom name="cylinder" type="cylinder" fromto="1 1 0 2 1 0" size="0.2"/>
+                <geom name="box" type="box" fromto="0 0 1 0 0 -1" size="0.1 0.3"/>
+                <geom name="ellipsoid" type="ellipsoid" fromto="0 0 0 0 3 4" size="0.1 0.2"/>
+                <geom name="sphere" type="sphere" fromto="0 0 0 0 0 2" size="0.5" pos="1 2 3"/>
+            </worldbody>
+        </mujoco>"#).expect("Expected the model to load");
+        let check = |name: &str, center: [f32; 3], axis: [f32; 3], size: [f32; 3]| {
+            let g = geom(&model, name);
+            let pose = g.get_local_pose().isometry;
+            assert!((pose.translation.vector - Vector3::from(center)).norm() < 1e-5, "{} at {}", name, pose.translation.vector);
+            assert!((pose.rotation * Vector3::z() - Vector3::from(axis)).norm() < 1e-5, "{} along {}", name, pose.rotation * Vector3::z());
+            assert!((Vector3::from(g.get_size()) - Vector3::from(size)).norm() < 1e-5, "{} sized {:?}", name, g.get_size());
+        };
+        check("capsule", [0.0, 0.0, 1.0], [0.0, 0.0, 1.0], [0.1, 1.0, 0.0]);
+        check("cylinder", [1.5, 1.0, 0.0], [1.0, 0.0, 0.0], [0.2, 0.5, 0.0]);
+        //Pointing straight down, opposite to the geom's own z axis
+        check("box", [0.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.1, 0.3, 1.0]);
+        check("ellipsoid", [0.0, 1.5, 2.0], [0.0, 0.6, 0.8], [0.1, 0.2, 2.5]);
+        //Spheres have no axis, fromto is ignored
+        check("sphere", [1.0, 2.0, 3.0], [0.0, 0.0, 1.0], [0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn unknown_geom_types_are_rejected() {
+        let model = load_str("geom-type", "<mujoco><worldbody>\n  <geom name=\"g\" type=\"cylnder\" size=\"0.1 0.2\"/>\n</worldbody></mujoco>");
+        match model {
+            Err(crate::model::ModelLoadError::BadAttribute { location, element, error }) => {
+                assert_eq!((element.as_str(), error.key.as_str(), error.value.as_str()), ("geom", "type", "cylnder"));
+                assert_eq!((location.line, location.column), (2, 3));
+            },
+            other => panic!("Expected a bad type attribute, got {:?}", other.err())
+        }
+    }
+
+    #[test]
+    fn a_second_orientation_is_rejected() {
+        for (element, xml) in [
+            ("body", r#"<body name="b" quat="1 0 0 0" euler="0 0 90"/>"#),
+            ("geom", r#"<geom name="g" size="0.1" zaxis="0 0 1" axisangle="0 0 1 90"/>"#)
+        ] {
+            let model = load_str("double-orientation", &format!("<mujoco><worldbody>{}</worldbody></mujoco>", xml));
+            assert!(matches!(model, Err(crate::model::ModelLoadError::BadAttribute { element: ref e, ref error, .. })
+                if e == element && (error.key == "euler" || error.key == "axisangle")), "{:?}", model.err());
+        }
+        //An orientation from the class default doesn't count against the element's own one
+        let model = load_str("default-orientation", r#"<mujoco>
+            <default><geom euler="0 0 90"/></default>
+            <worldbody><geom name="g" size="0.1" quat="1 0 0 0"/></worldbody>
+        </mujoco>"#).expect("Expected the model to load");
+        assert!(geom(&model, "g").get_set_attributes_list().get("euler").is_none());
+    }
+
+    #[test]
+    fn inline_meshes_left_without_faces_keep_them() {
+        let processing = mesh_ops::MeshProcessing { remove_degenerate: true, ..Default::default() };
+        let model = load_str_with_options("degenerate", r#"<mujoco>
+            <asset><mesh name="flat" vertex="0 0 0  1 0 0  2 0 0  0 1 1" face="0 1 2"/></asset>
+            <worldbody><geom type="mesh" mesh="flat"/></worldbody>
+        </mujoco>"#, &processing).expect("Expected the model to load");
+        let assets = model.asset_manager.expect("Expected the assets");
+        let mesh = AssetsManager::get_mesh(&model.graph, assets, "flat").expect("Expected the mesh");
+        match model.graph.get(mesh) {
+            Node::Mesh(m) => {
+                assert!(!m.is_convex_hull());
+                assert_eq!(m.indices, vec![0, 1, 2]);
+            },
+            _ => panic!("Expected flat to be a mesh")
+        }
     }
-}
\ No newline at end of file
+}
@@ -7,6 +7,7 @@ use crate::ViewProp;
 use crate::node::{NodeType, TextureType};
 use crate::node::Texture;
 use crate::drawable::{MeshCollection};
+use crate::transform;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
@@ -15,7 +16,8 @@ pub struct PaintsMan {
     vertex_array: Option<glow::VertexArray>,
     viewport_transform_matrix: Matrix3<f32>,
     viewport_translation_matrix: Matrix4x1<f32>,
-    texture_ids_map: Option<HashMap<String,glow::NativeTexture>>,
+    //Each texture with the target it's bound to, cube map or 2D
+    texture_ids_map: Option<HashMap<String,(glow::NativeTexture,u32)>>,
     draw_map: Option<Vec<(u32,Option<String>)>>
 }
 
@@ -45,23 +47,8 @@ impl PaintsMan {
                 0.0, zoom_scale, 0.0,
                 0.0, 0.0, zoom_scale,
         );
-        let rotation_m = Matrix3::new(
-                rotation_params.1.cos() * rotation_params.2.cos(), 
-                rotation_params.1.cos() * rotation_params.2.sin(), 
-                -rotation_params.1.sin(), 
-
-                rotation_params.0.sin() * rotation_params.1.sin() * rotation_params.2.cos() - 
-                rotation_params.0.cos() * rotation_params.2.sin(), 
-                rotation_params.0.sin() * rotation_params.1.sin() * rotation_params.2.sin() + 
-                rotation_params.0.cos() * rotation_params.2.cos(), 
-                rotation_params.0.sin() * rotation_params.1.cos(), 
-
-                rotation_params.0.cos() * rotation_params.1.sin() * rotation_params.2.cos() + 
-                rotation_params.0.sin() * rotation_params.2.sin(),
-                rotation_params.0.cos() * rotation_params.1.sin() * rotation_params.2.sin() - 
-                rotation_params.0.sin() * rotation_params.2.cos(),
-                rotation_params.0.cos() * rotation_params.1.cos()
-        );
+        let rotation_m = transform::euler_rotation(rotation_params.0, rotation_params.1, rotation_params.2).
+            inverse().to_rotation_matrix().into_inner();
         // println!("Applying the viewport rotation {} to {}",rotation_m,self.viewport_transform_matrix);
         self.viewport_transform_matrix = rotation_m * rotation_s * self.viewport_transform_matrix;
         // println!("To obtain {}",self.viewport_transform_matrix);
@@ -108,13 +95,17 @@ impl PaintsMan {
                     layout(location = 0) in vec3 verts;
                     layout(location = 1) in vec3 color;
                     layout(location = 2) in vec3 tex_coord_r;
+                    layout(location = 3) in vec3 normal;
                     uniform mat4 projection;
                     out vec4 v_color;
                     out vec3 tex_coord;
                     uniform mat3 viewport_transform;
                     uniform vec4 viewport_translate;
                     void main() {
-                        v_color = vec4(color,0.75);
+                        //Headlight: surfaces facing the viewer are brightest, either side lit
+                        vec3 view_normal = normalize(viewport_transform * normal);
+                        float light = 0.35 + 0.65 * abs(view_normal.z);
+                        v_color = vec4(color * light,0.75);
                         tex_coord = tex_coord_r;
                         gl_Position = viewport_translate + 
                             (projection * vec4(viewport_transform * verts, 1.0));
@@ -125,8 +116,9 @@ impl PaintsMan {
                     in vec4 v_color;
                     in vec3 tex_coord;
                     out vec4 out_color;
-                    // uniform sampler2D o_tex;
                     uniform samplerCube o_tex;
+                    uniform sampler2D o_tex_2d;
+                    //0 for none, 1 for a cube map, 2 for a 2D texture
                     uniform uint use_texture;
                     // uniform uint is_cube_tex;
 
@@ -143,6 +135,9 @@ impl PaintsMan {
                             //     out_color = vec4(0.5,0.5,0.5,0.5);
                             // }   
                         } 
+                        else if(use_texture == uint(2)) {
+                            out_color = texture(o_tex_2d,tex_coord.xy) * v_color;
+                        }
                         else {
                             out_color =  v_color;
                         }
@@ -214,14 +209,17 @@ impl PaintsMan {
             gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER,Some(elements_buffer));
             gl.buffer_data_u8_slice(glow::ELEMENT_ARRAY_BUFFER, elements.as_slice(), glow::STATIC_DRAW);
             gl.vertex_attrib_pointer_f32(0, 3, 
-                        glow::FLOAT, false, 9*f32_size, 0);
+                        glow::FLOAT, false, 12*f32_size, 0);
             gl.enable_vertex_attrib_array(0);
             gl.vertex_attrib_pointer_f32(1, 3, 
-                        glow::FLOAT, false, 9*f32_size, 3*f32_size);
+                        glow::FLOAT, false, 12*f32_size, 3*f32_size);
             gl.enable_vertex_attrib_array(1); 
             gl.vertex_attrib_pointer_f32(2, 3, 
-                        glow::FLOAT, false, 9*f32_size, 6*f32_size);
+                        glow::FLOAT, false, 12*f32_size, 6*f32_size);
             gl.enable_vertex_attrib_array(2);
+            gl.vertex_attrib_pointer_f32(3, 3, 
+                        glow::FLOAT, false, 12*f32_size, 9*f32_size);
+            gl.enable_vertex_attrib_array(3);
             gl.bind_vertex_array(None);
 
             // println!("Got this textures map {:?}",textures_ids_map);
@@ -237,17 +235,19 @@ impl PaintsMan {
 
     fn load_textures(
             gl: &glow::Context, 
-            textures_data: Vec<Texture>) -> HashMap<String,glow::NativeTexture> {
+            textures_data: Vec<Texture>) -> HashMap<String,(glow::NativeTexture,u32)> {
         use glow::HasContext as _;
         unsafe {
             //Set texture data
-            let mut textures_ids_map: HashMap<String,glow::NativeTexture> = HashMap::new();
+            let mut textures_ids_map: HashMap<String,(glow::NativeTexture,u32)> = HashMap::new();
+            //Rows of RGB images aren't padded to 4 bytes
+            gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 1);
             for texture in textures_data {
                 // println!("Hash of texture file {} is {:?}",texture.get_name(),Self::calculate_hash(&texture.file_data));
                 match texture.t_type {
                     TextureType::Cube => {
                         let tex: glow::NativeTexture = gl.create_texture().unwrap();
-                        textures_ids_map.insert(texture.get_name(),tex);
+                        textures_ids_map.insert(texture.get_name(),(tex,glow::TEXTURE_CUBE_MAP));
                         gl.bind_texture(glow::TEXTURE_CUBE_MAP, Some(tex));
 
                         //For some reason the latter works rather than the former: 
@@ -285,6 +285,26 @@ impl PaintsMan {
                         //     texture.texture_dimensions.1);
                         // gl.generate_mipmap(glow::TEXTURE_2D);
                     }, 
+                    TextureType::TwoD => {
+                        let tex: glow::NativeTexture = gl.create_texture().unwrap();
+                        textures_ids_map.insert(texture.get_name(),(tex,glow::TEXTURE_2D));
+                        gl.bind_texture(glow::TEXTURE_2D, Some(tex));
+                        gl.tex_image_2d(glow::TEXTURE_2D, 
+                            0, 
+                            glow::RGB as i32, 
+                            texture.texture_image_dimensions.0 as i32, 
+                            texture.texture_image_dimensions.1 as i32, 
+                            0, 
+                            glow::RGB, 
+                            glow::UNSIGNED_BYTE, 
+                            glow::PixelUnpackData::Slice(Some(texture.file_data.as_slice())));
+                        gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_WRAP_S, glow::REPEAT as i32);
+                        gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_WRAP_T, glow::REPEAT as i32);
+                        gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::LINEAR_MIPMAP_LINEAR as i32);
+                        gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::LINEAR as i32);
+                        gl.generate_mipmap(glow::TEXTURE_2D);
+                        gl.bind_texture(glow::TEXTURE_2D, None);
+                    },
                     _ => ()
                 }
                 gl.bind_texture(glow::TEXTURE_CUBE_MAP, None);
@@ -338,20 +358,29 @@ impl PaintsMan {
             let texture_map = 
                 self.texture_ids_map.as_ref().expect("Expected texture ids map to be available.");
 
+            //Cube maps are sampled from unit 0, 2D textures from unit 1
+            gl.uniform_1_i32(
+                gl.get_uniform_location(self.program.expect("No program available in PaintsMan instance"), "o_tex").as_ref(), 0);
+            gl.uniform_1_i32(
+                gl.get_uniform_location(self.program.expect("No program available in PaintsMan instance"), "o_tex_2d").as_ref(), 1);
             let mut index_offset = 0;
             for draw_comm in draw_map {
+                let binding = draw_comm.1.as_ref().map(|e| {
+                    // println!("Binding texture {} {:?}",e,texture_map.get(e).expect("Expected texture binding to be available.").clone());
+                    texture_map.get(e).expect("Expected texture binding to be available.").clone()
+                });
                 gl.uniform_1_u32(
                     gl.get_uniform_location(self.program.
                         expect("No program available in PaintsMan instance"), 
                         "use_texture").as_ref(),
-                        draw_comm.1.as_ref().map_or(0, |e| {
-                            1
+                        binding.map_or(0, |(_, target)| {
+                            if target == glow::TEXTURE_2D { 2 } else { 1 }
                         }));
-                gl.bind_texture(glow::TEXTURE_CUBE_MAP, 
-                    draw_comm.1.as_ref().map_or(None, |e| {
-                    // println!("Binding texture {} {:?}",e,texture_map.get(e).expect("Expected texture binding to be available.").clone());
-                    Some(texture_map.get(e).expect("Expected texture binding to be available.").clone())
-                }));
+                if let Some((tex, target)) = binding {
+                    gl.active_texture(if target == glow::TEXTURE_2D { glow::TEXTURE1 } else { glow::TEXTURE0 });
+                    gl.bind_texture(target, Some(tex));
+                    gl.active_texture(glow::TEXTURE0);
+                }
                 // println!("Drawing {} indices at offset: {}",draw_comm.0.clone(),index_offset);
                 gl.draw_elements(glow::TRIANGLES, draw_comm.0.clone() as i32, 
                     glow::UNSIGNED_INT, index_offset * u32_size);
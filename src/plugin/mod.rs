@@ -1,4 +1,4 @@
-use crate::node::Node;
+use crate::model::Model;
 use std::rc::{Rc};
 use std::cell::RefCell;
 use std::time::{Duration};
@@ -24,9 +24,12 @@ impl PluginCapabilities {
     }
 }
 
+/* Plugins get the whole model, so they can use its scene graph as well as 
+model level APIs such as joint positions & inverse kinematics. Node ids they 
+keep hold of stay valid for as long as the model is loaded */
 pub trait Plugin {
-    fn process_sim_loop(&mut self, t: Duration, worldbody: Rc<RefCell<Node>>);
-    fn process_model_load(&mut self,worldbody: Rc<RefCell<Node>>);
+    fn process_sim_loop(&mut self, t: Duration, model: &mut Model);
+    fn process_model_load(&mut self, model: &mut Model);
 }
 
 pub struct PluginManager {
@@ -44,22 +47,30 @@ impl PluginManager {
         self.registered_plugins.push((cap,plugin));
     }
 
-    pub fn process_sim_loop(&self, view_prop: Arc<Mutex<ViewProp>>, t: Duration, worldbody: Rc<RefCell<Node>>) {
+    /* Returns whether any plugin was given the model to update */
+    pub fn process_sim_loop(&self, view_prop: Arc<Mutex<ViewProp>>, t: Duration, model: &mut Model) -> bool {
+        let mut processed = false;
         for plugin in &self.registered_plugins {
             if plugin.0.process_sim_loop {
-                plugin.1.borrow_mut().process_sim_loop(t.clone(), worldbody.clone());
+                processed = true;
+                plugin.1.borrow_mut().process_sim_loop(t.clone(), model);
                 //TODO: This should be further passed down to the plugin to 
                 //exactly determine whether any update has been made
                 view_prop.lock().unwrap().model_updated = true;
             }
         }
+        processed
     }
 
-    pub fn process_model_load(&self,worldbody: Rc<RefCell<Node>>) {
+    /* Returns whether any plugin was given the model to process */
+    pub fn process_model_load(&self, model: &mut Model) -> bool {
+        let mut processed = false;
         for plugin in &self.registered_plugins {
             if plugin.0.process_model_load {
-                plugin.1.borrow_mut().process_model_load(worldbody.clone());
+                processed = true;
+                plugin.1.borrow_mut().process_model_load(model);
             }
         }
+        processed
     }
 } 
\ No newline at end of file
@@ -1,9 +1,11 @@
-use std::rc::Rc;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use crate::plugin::Plugin;
+use crate::model::Model;
 use std::time::{Duration};
-use crate::node::{Node, NodeType};
+use crate::node::Node;
+use crate::query;
+use crate::scene::{NodeId, SceneGraph};
 use crate::plugin::rubiks_solver::RubiksSolver;
 
 #[derive(Clone,Debug)]
@@ -289,11 +291,11 @@ impl CornerSlot {
 }
 
 pub struct RubiksCubeModelInterface {
-    body: Option<Rc<RefCell<Node>>>, //Geometric representation,
+    body: Option<NodeId>, //Geometric representation,
     anim_counter: Option<(f32,f32)>,
     anim_time: f32,
     move_queue: Vec<(CubeMove,bool)>,
-    anim_active: Option<(Rc<RefCell<Node>>,Vec<u32>,(f32,f32,f32),CubeMove)>,
+    anim_active: Option<(NodeId,Vec<u32>,(f32,f32,f32),CubeMove)>,
     cube: RubiksCube,
     solver: RubiksSolver
 }
@@ -322,7 +324,7 @@ impl RubiksCubeModelInterface {
     //Possible moves: F+, F-, B+, B-, L+, L-, R+, R-, U+, U-, D+, D-
     //Select the cubelets index & select 9 for each move 
     // Apply transforms to the body as well as apply the internal state updates
-    pub fn apply_move(&mut self, mv: CubeMove, animate: bool) {
+    pub fn apply_move(&mut self, graph: &mut SceneGraph, mv: CubeMove, animate: bool) {
         if self.anim_active.is_none() {
             if match &mv {
                 CubeMove::NoOp => true,
@@ -331,15 +333,9 @@ impl RubiksCubeModelInterface {
                 return;
             }
             //Work on the body which has the list of all cubelets
-            if let Some(b) = &self.body {
+            if let Some(b) = self.body {
                 {
-                    let children = b.borrow().get_children();
-                    let mut target_body = None;
-                    for child in children {
-                        if child.borrow().get_name() == "core" {
-                            target_body = Some(child.clone());
-                        }
-                    }
+                    let target_body = query::find_child_by_name(graph, b, "core");
                     if let Some(tb) = target_body {
                         let a_indices: Vec<u32> = self.cube.get_indices_for_move(mv.clone());
                         let rotation_params = RubiksCube::get_rotation_params(mv.clone());
@@ -348,6 +344,7 @@ impl RubiksCubeModelInterface {
                             //Keep applying the transforms for when advance_animation is called
                         } else {
                             Self::apply_geometry_transforms(
+                                graph,
                                 tb,
                                 a_indices,
                                 rotation_params);
@@ -363,7 +360,7 @@ impl RubiksCubeModelInterface {
     }
 
     //t1, t2, t3, (t4 / 10)
-    fn advance_animation(&mut self, t: f32) {
+    fn advance_animation(&mut self, graph: &mut SceneGraph, t: f32) {
         if let Some((tb,a,b,mv)) = 
             &self.anim_active {
             if let Some(counter) = &mut self.anim_counter {
@@ -377,7 +374,8 @@ impl RubiksCubeModelInterface {
                 println!("Advancing animation by: {:?} {:?} {} {} {} {}",b,u_rotation_params,chunk,inc,t,counter.1);
                 //Advance animation transforms: 
                 Self::apply_geometry_transforms(
-                    tb.clone(),
+                    graph,
+                    *tb,
                     a.clone(),
                     u_rotation_params);
                 counter.1 = t;
@@ -389,7 +387,7 @@ impl RubiksCubeModelInterface {
                     //Setup the queue move now
                     if !self.move_queue.is_empty() {
                         let (mv,anim) = self.move_queue.remove(0);
-                        self.apply_move(mv.clone(), anim.clone());
+                        self.apply_move(graph, mv.clone(), anim.clone());
                     }
                 }
             } else {
@@ -399,16 +397,17 @@ impl RubiksCubeModelInterface {
     }
 
     pub fn apply_geometry_transforms(
-        target_body: Rc<RefCell<Node>>,
+        graph: &mut SceneGraph,
+        target_body: NodeId,
         indices: Vec<u32>, 
         rotation_params: (f32,f32,f32)) {
-        match *target_body.borrow() {
-            Node::Body(ref t) => {
-                let children = t.get_children();
+        match graph.get(target_body) {
+            Node::Body(_) => {
+                let children = graph.children(target_body).to_vec();
                 let mut index = 0;
-                for child in children.iter().rev() {
-                    match *child.borrow_mut() {
-                        Node::Body(ref mut b) => {    
+                for child in children {
+                    match graph.get_mut(child) {
+                        Node::Body(b) => {    
                             // println!("Applying move to body node: {}",b.get_name());
                             if indices.contains(&(index as u32)) {
                                 b.apply_added_rotations(rotation_params);
@@ -425,16 +424,20 @@ impl RubiksCubeModelInterface {
 }
 
 impl Plugin for RubiksCubeModelInterface {
-    fn process_sim_loop(&mut self, t: Duration, worldbody: Rc<RefCell<Node>>) {
+    fn process_sim_loop(&mut self, t: Duration, model: &mut Model) {
         println!("Duration passed: {:?}", t);
-        self.advance_animation(t.as_secs_f32());
+        self.advance_animation(&mut model.graph, t.as_secs_f32());
     }
 
-    fn process_model_load(&mut self,worldbody: Rc<RefCell<Node>>) {
+    fn process_model_load(&mut self, model: &mut Model) {
         println!("Processing model load in Rubik's plugin");
+        let Some(worldbody) = model.world_body else {
+            return;
+        };
         self.body = Some(worldbody);
-        self.apply_move(CubeMove::FPlus,false);
-        self.apply_move(CubeMove::UPlus,false);
+        let graph = &mut model.graph;
+        self.apply_move(graph, CubeMove::FPlus,false);
+        self.apply_move(graph, CubeMove::UPlus,false);
         for i in 0..self.solver.num_epochs {
             println!("Training {} epoch",i);
             let trajs = 
@@ -447,8 +450,8 @@ impl Plugin for RubiksCubeModelInterface {
         let rcb2 = self.cube.apply_move(mv.clone());
         let mv2 = self.solver.generate_move(&rcb2);
         // println!("Got the cubemove from policy: {:?}",mv);
-        self.apply_move(mv,true);
-        self.apply_move(mv2,true);
+        self.apply_move(graph, mv,true);
+        self.apply_move(graph, mv2,true);
         // self.apply_move(CubeMove::UPlus,true);
         // self.apply_move(CubeMove::RPlus,true);
         // self.apply_move(CubeMove::DPlus,true);
@@ -0,0 +1,202 @@
+use std::f32::consts::PI;
+use nalgebra::Vector3;
+
+//Resolution of the generated shapes, as used for spheres
+const SEGMENTS: usize = 32;
+const RINGS: usize = 16;
+
+/* Triangulated shape with a normal per vertex, coordinates are flattened x,y,z */
+#[derive(Debug, Clone)]
+pub struct TriangleMesh {
+    pub vertices: Vec<f32>,
+    pub indices: Vec<u32>,
+    pub normals: Vec<f32>
+}
+
+impl TriangleMesh {
+    fn new() -> Self {
+        TriangleMesh {
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            normals: Vec::new()
+        }
+    }
+
+    /* Revolves a profile, given top to bottom as (distance from the z axis, z,
+    normal within the profile's plane), around the z axis. Triangles are wound
+    counter clockwise seen from outside */
+    fn add_revolved(&mut self, profile: &[(f32,f32,(f32,f32))]) {
+        let first = (self.vertices.len() / 3) as u32;
+        for (rho, z, (n_rho, n_z)) in profile {
+            for j in 0..SEGMENTS {
+                let phi = 2.0 * PI * j as f32 / SEGMENTS as f32;
+                self.vertices.extend_from_slice(&[rho * phi.cos(), rho * phi.sin(), *z]);
+                self.normals.extend_from_slice(&[n_rho * phi.cos(), n_rho * phi.sin(), *n_z]);
+            }
+        }
+        let at = |ring: usize, j: usize| first + (ring * SEGMENTS + j % SEGMENTS) as u32;
+        for ring in 0..profile.len().saturating_sub(1) {
+            for j in 0..SEGMENTS {
+                let (a, b, c, d) = (at(ring, j), at(ring + 1, j), at(ring, j + 1), at(ring + 1, j + 1));
+                self.indices.extend_from_slice(&[a, b, c, c, b, d]);
+            }
+        }
+    }
+
+    /* Adds a flat strip given as pairs of points, (a0, b0), (a1, b1) ..., 
+    each triangle wound to face along normal */
+    fn add_flat(&mut self, strip: &[Vector3<f32>], normal: &Vector3<f32>) {
+        let first = (self.vertices.len() / 3) as u32;
+        for p in strip {
+            self.vertices.extend_from_slice(&[p.x, p.y, p.z]);
+            self.normals.extend_from_slice(&[normal.x, normal.y, normal.z]);
+        }
+        for i in (0..strip.len().saturating_sub(3)).step_by(2) {
+            for [a, b, c] in [[i, i + 1, i + 2], [i + 2, i + 1, i + 3]] {
+                let facing = (strip[b] - strip[a]).cross(&(strip[c] - strip[a])).dot(normal);
+                let triangle = if facing < 0.0 { [a, c, b] } else { [a, b, c] };
+                self.indices.extend(triangle.iter().map(|k| first + *k as u32));
+            }
+        }
+    }
+}
+
+/* Profile of a sphere of the given radius, its upper half raised by offset &
+its lower half lowered by it. With an offset the equator is repeated, so the 
+two halves are joined by a cylinder */
+fn sphere_profile(radius: f32, offset: f32) -> Vec<(f32,f32,(f32,f32))> {
+    let mut profile = Vec::new();
+    for i in 0..=RINGS {
+        let theta = PI * i as f32 / RINGS as f32;
+        let (sin, cos) = theta.sin_cos();
+        //sin(PI) isn't quite 0, keeps the bottom pole on the axis
+        let sin = sin.max(0.0);
+        if 2 * i <= RINGS {
+            profile.push((radius * sin, radius * cos + offset, (sin, cos)));
+        }
+        if 2 * i > RINGS || (2 * i == RINGS && offset != 0.0) {
+            profile.push((radius * sin, radius * cos - offset, (sin, cos)));
+        }
+    }
+    profile
+}
+
+/* Capsule along z: a cylinder of half length half_length capped by hemispheres */
+pub fn capsule(radius: f32, half_length: f32) -> TriangleMesh {
+    let mut mesh = TriangleMesh::new();
+    mesh.add_revolved(&sphere_profile(radius, half_length));
+    mesh
+}
+
+/* Closed cylinder along z, the caps are flat shaded */
+pub fn cylinder(radius: f32, half_length: f32) -> TriangleMesh {
+    let mut mesh = TriangleMesh::new();
+    mesh.add_revolved(&[(0.0, half_length, (0.0, 1.0)), (radius, half_length, (0.0, 1.0))]);
+    mesh.add_revolved(&[(radius, half_length, (1.0, 0.0)), (radius, -half_length, (1.0, 0.0))]);
+    mesh.add_revolved(&[(radius, -half_length, (0.0, -1.0)), (0.0, -half_length, (0.0, -1.0))]);
+    mesh
+}
+
+/* Ellipsoid with the given radii along x, y & z */
+pub fn ellipsoid(radii: [f32;3]) -> TriangleMesh {
+    let mut mesh = TriangleMesh::new();
+    mesh.add_revolved(&sphere_profile(1.0, 0.0));
+    for (i, value) in mesh.vertices.iter_mut().enumerate() {
+        *value *= radii[i % 3];
+    }
+    //Normals of a scaled surface scale by the inverse
+    for normal in mesh.normals.chunks_exact_mut(3) {
+        let n = [normal[0] / radii[0], normal[1] / radii[1], normal[2] / radii[2]];
+        let length = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+        if length > 0.0 {
+            normal.copy_from_slice(&[n[0] / length, n[1] / length, n[2] / length]);
+        }
+    }
+    mesh
+}
+
+/* Rectangle in the xy plane facing +z, of half sizes half_x & half_y */
+pub fn plane(half_x: f32, half_y: f32) -> TriangleMesh {
+    TriangleMesh {
+        vertices: vec![
+            -half_x, -half_y, 0.0,
+            half_x, -half_y, 0.0,
+            half_x, half_y, 0.0,
+            -half_x, half_y, 0.0],
+        indices: vec![0, 1, 2, 0, 2, 3],
+        normals: [0.0, 0.0, 1.0].repeat(4)
+    }
+}
+
+/* Height field surface over a row major nrow x ncol grid of points, each 
+cell split along its (row, col) to (row + 1, col + 1) diagonal. Walls drop 
+from the border to the base, base below z = 0, which closes it from below */
+pub fn height_field(nrow: usize, ncol: usize, points: &[Vector3<f32>], base: f32) -> TriangleMesh {
+    let mut mesh = TriangleMesh::new();
+    if nrow < 2 || ncol < 2 || points.len() != nrow * ncol {
+        return mesh;
+    }
+    mesh.vertices = points.iter().flat_map(|p| [p.x, p.y, p.z]).collect();
+    let at = |row: usize, col: usize| (row * ncol + col) as u32;
+    for row in 0..nrow - 1 {
+        for col in 0..ncol - 1 {
+            mesh.indices.extend_from_slice(&[
+                at(row, col), at(row, col + 1), at(row + 1, col + 1),
+                at(row, col), at(row + 1, col + 1), at(row + 1, col)]);
+        }
+    }
+    mesh.normals = vertex_normals(&mesh.vertices, &mesh.indices);
+    let border = |edge: Vec<(usize,usize)>| -> Vec<Vector3<f32>> {
+        edge.into_iter().map(|(row, col)| points[row * ncol + col]).collect()
+    };
+    let walls = [
+        (border((0..ncol).map(|col| (0, col)).collect()), Vector3::new(0.0, -1.0, 0.0)),
+        (border((0..ncol).map(|col| (nrow - 1, col)).collect()), Vector3::new(0.0, 1.0, 0.0)),
+        (border((0..nrow).map(|row| (row, 0)).collect()), Vector3::new(-1.0, 0.0, 0.0)),
+        (border((0..nrow).map(|row| (row, ncol - 1)).collect()), Vector3::new(1.0, 0.0, 0.0))];
+    for (edge, outward) in walls {
+        let mut wall = Vec::new();
+        for top in edge {
+            wall.push(top);
+            wall.push(Vector3::new(top.x, top.y, -base));
+        }
+        mesh.add_flat(&wall, &outward);
+    }
+    let (x, y) = (points[nrow * ncol - 1].x, points[nrow * ncol - 1].y);
+    let bottom = [
+        Vector3::new(-x, -y, -base), Vector3::new(-x, y, -base),
+        Vector3::new(x, -y, -base), Vector3::new(x, y, -base)];
+    mesh.add_flat(&bottom, &Vector3::new(0.0, 0.0, -1.0));
+    mesh
+}
+
+/* Area weighted vertex normals, for meshes that don't come with any */
+pub fn vertex_normals(vertices: &[f32], indices: &[u32]) -> Vec<f32> {
+    let mut normals = vec![0.0; vertices.len()];
+    let vertex = |i: usize| [vertices[3 * i], vertices[3 * i + 1], vertices[3 * i + 2]];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0] as usize, triangle[1] as usize, triangle[2] as usize];
+        if [a, b, c].iter().any(|i| 3 * i + 2 >= vertices.len()) {
+            continue;
+        }
+        let (p, q, r) = (vertex(a), vertex(b), vertex(c));
+        let u = [q[0] - p[0], q[1] - p[1], q[2] - p[2]];
+        let v = [r[0] - p[0], r[1] - p[1], r[2] - p[2]];
+        let n = [u[1] * v[2] - u[2] * v[1], u[2] * v[0] - u[0] * v[2], u[0] * v[1] - u[1] * v[0]];
+        for i in [a, b, c] {
+            for k in 0..3 {
+                normals[3 * i + k] += n[k];
+            }
+        }
+    }
+    for normal in normals.chunks_exact_mut(3) {
+        let length = (normal[0] * normal[0] + normal[1] * normal[1] + normal[2] * normal[2]).sqrt();
+        if length > 0.0 {
+            normal.iter_mut().for_each(|value| *value /= length);
+        } else {
+            //Unused or degenerate vertices, any direction will do
+            normal.copy_from_slice(&[0.0, 0.0, 1.0]);
+        }
+    }
+    normals
+}
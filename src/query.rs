@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use crate::node::NodeType;
+use crate::scene::{NodeId, SceneGraph};
+use crate::visitor::depth_first;
+
+/* Lookups over the node tree. Names follow MuJoCo's rules: they only have to
+be unique among elements of the same type, so a body & a geom may share one */
+
+pub fn find_child_by_name(graph: &SceneGraph, node: NodeId, name: &str) -> Option<NodeId> {
+    graph.children(node).iter().copied().find(|child| {
+        graph.get(*child).get_name() == name
+    })
+}
+
+/* Calls f on node & all of its descendants, parents before children */
+pub fn for_each_node<F: FnMut(NodeId)>(graph: &SceneGraph, node: NodeId, f: &mut F) {
+    for n in depth_first(graph, node) {
+        f(n);
+    }
+}
+
+/* All nodes of the given element type (e.g. "geom", "joint", "body") below & including root */
+pub fn find_all_of_type(graph: &SceneGraph, root: NodeId, node_type: &str) -> Vec<NodeId> {
+    let mut nodes = Vec::new();
+    for_each_node(graph, root, &mut |node| {
+        if graph.get(node).get_node_name() == node_type {
+            nodes.push(node);
+        }
+    });
+    nodes
+}
+
+pub fn find_by_class(graph: &SceneGraph, root: NodeId, class: &str) -> Vec<NodeId> {
+    let mut nodes = Vec::new();
+    for_each_node(graph, root, &mut |node| {
+        if graph.get(node).get_class() == class {
+            nodes.push(node);
+        }
+    });
+    nodes
+}
+
+/* Resolves a slash separated path such as "worldbody/core/cubelet_12". The first
+segment names root itself (by name, or by element type when root is unnamed), the
+rest are names of successive children */
+pub fn find_by_path(graph: &SceneGraph, root: NodeId, path: &str) -> Option<NodeId> {
+    let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+    let first = segments.next()?;
+    let root_n = graph.get(root);
+    if root_n.get_name() != first && root_n.get_node_name() != first {
+        return None;
+    }
+    let mut current = root;
+    for segment in segments {
+        current = find_child_by_name(graph, current, segment)?;
+    }
+    Some(current)
+}
+
+/* Path of a node from the top of its tree, the inverse of find_by_path */
+pub fn path_of(graph: &SceneGraph, node: NodeId) -> String {
+    let mut segments = Vec::new();
+    let mut current = Some(node);
+    while let Some(n) = current {
+        let name = graph.get(n).get_name();
+        segments.push(if name == "" { graph.get(n).get_node_name() } else { name });
+        current = graph.parent(n);
+    }
+    segments.reverse();
+    segments.join("/")
+}
+
+/* Index of the named nodes below & including root, keyed by (element type, name).
+Names are checked for uniqueness while parsing, so each key maps to a single node */
+pub fn build_name_index(graph: &SceneGraph, root: NodeId) -> HashMap<(String,String),NodeId> {
+    let mut index = HashMap::new();
+    for_each_node(graph, root, &mut |node| {
+        let key = (graph.get(node).get_node_name(), graph.get(node).get_name());
+        if key.1 != "" && !index.contains_key(&key) {
+            index.insert(key, node);
+        }
+    });
+    index
+}
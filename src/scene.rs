@@ -0,0 +1,87 @@
+use crate::node::Node;
+
+/* Handle to a node in a SceneGraph. Ids stay valid for the lifetime of the
+graph (nodes are never removed) & are only meaningful for the graph, or
+clones of the graph, that handed them out */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Slot {
+    node: Node,
+    parent: Option<NodeId>,
+    //In document order
+    children: Vec<NodeId>
+}
+
+/* Arena holding every node of a model along with the parent/child links
+between them. The graph owns plain data only, so it can be cloned for
+snapshots & sent across threads */
+#[derive(Debug, Clone, Default)]
+pub struct SceneGraph {
+    slots: Vec<Slot>
+}
+
+impl SceneGraph {
+    pub fn new() -> Self {
+        SceneGraph {
+            slots: Vec::new()
+        }
+    }
+
+    /* Adds a node without a parent, e.g. the worldbody or the asset section */
+    pub fn add_root(&mut self, node: Node) -> NodeId {
+        self.push(node, None)
+    }
+
+    /* Adds node as the last child of parent */
+    pub fn add_child(&mut self, parent: NodeId, node: Node) -> NodeId {
+        let id = self.push(node, Some(parent));
+        self.slots[parent.0].children.push(id);
+        id
+    }
+
+    fn push(&mut self, node: Node, parent: Option<NodeId>) -> NodeId {
+        self.slots.push(Slot {
+            node: node,
+            parent: parent,
+            children: Vec::new()
+        });
+        NodeId(self.slots.len() - 1)
+    }
+
+    pub fn get(&self, id: NodeId) -> &Node {
+        &self.slots[id.0].node
+    }
+
+    pub fn get_mut(&mut self, id: NodeId) -> &mut Node {
+        &mut self.slots[id.0].node
+    }
+
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.slots[id.0].parent
+    }
+
+    pub fn children(&self, id: NodeId) -> &[NodeId] {
+        &self.slots[id.0].children
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /* All ids in the graph, in the order the nodes were added */
+    pub fn ids(&self) -> impl Iterator<Item = NodeId> {
+        (0..self.slots.len()).map(NodeId)
+    }
+}
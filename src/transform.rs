@@ -0,0 +1,147 @@
+use nalgebra::{Isometry3, UnitQuaternion, Vector3, Translation3, Point3, Matrix3, DMatrix};
+use crate::node::Node;
+use crate::scene::{NodeId, SceneGraph};
+
+/* Placement of a node relative to its parent: a rigid transform (rotation +
+translation) with a per axis scale. Points are scaled first, then rotated &
+finally translated */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transform {
+    pub isometry: Isometry3<f32>,
+    pub scale: Vector3<f32>
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Transform {
+            isometry: Isometry3::identity(),
+            scale: Vector3::new(1.0, 1.0, 1.0)
+        }
+    }
+
+    pub fn new(translation: Vector3<f32>, rotation: UnitQuaternion<f32>, scale: Vector3<f32>) -> Self {
+        Transform {
+            isometry: Isometry3::from_parts(Translation3::from(translation), rotation),
+            scale: scale
+        }
+    }
+
+    pub fn from_isometry(isometry: Isometry3<f32>) -> Self {
+        Transform {
+            isometry: isometry,
+            scale: Vector3::new(1.0, 1.0, 1.0)
+        }
+    }
+
+    pub fn translation(&self) -> Vector3<f32> {
+        self.isometry.translation.vector
+    }
+
+    pub fn rotation(&self) -> UnitQuaternion<f32> {
+        self.isometry.rotation
+    }
+
+    pub fn inverse(&self) -> Self {
+        let inv_scale = self.scale.map(|s| if s != 0.0 { 1.0 / s } else { 0.0 });
+        let inv_isometry = self.isometry.inverse();
+        //Undoing the scale after the rotation is only exact for uniform scales
+        Transform {
+            isometry: Isometry3::from_parts(
+                Translation3::from(inv_isometry.translation.vector.component_mul(&inv_scale)),
+                inv_isometry.rotation),
+            scale: inv_scale
+        }
+    }
+
+    /* Returns the transform applying `child` first & then `self`, i.e. the
+    pose of a child frame given in this frame. The parent's scale stretches
+    the child's offset; combining rotations with non-uniform parent scales
+    isn't a similarity, so only the scale factors get multiplied there */
+    pub fn compose(&self, child: &Transform) -> Self {
+        let child_translation = child.translation().component_mul(&self.scale);
+        Transform {
+            isometry: Isometry3::from_parts(
+                Translation3::from(self.isometry.transform_vector(&child_translation) + self.translation()),
+                self.rotation() * child.rotation()),
+            scale: self.scale.component_mul(&child.scale)
+        }
+    }
+
+    pub fn transform_point(&self, point: &Point3<f32>) -> Point3<f32> {
+        self.isometry.transform_point(&Point3::from(point.coords.component_mul(&self.scale)))
+    }
+
+    pub fn transform_vector(&self, vector: &Vector3<f32>) -> Vector3<f32> {
+        self.isometry.transform_vector(&vector.component_mul(&self.scale))
+    }
+
+    /* Applies the transform to a flat x, y, z vertex array */
+    pub fn transform_vertices(&self, vertices: Vec<f32>) -> Vec<f32> {
+        let num_vertices = vertices.len() / 3;
+        let linear_m = self.rotation().to_rotation_matrix().into_inner() *
+            Matrix3::from_diagonal(&self.scale);
+        let vertices_m = DMatrix::from_vec(3, num_vertices, vertices);
+        let mut vertices_mr = linear_m * vertices_m;
+        let translation = self.translation();
+        for mut column in vertices_mr.column_iter_mut() {
+            column += translation;
+        }
+        Vec::from(vertices_mr.as_slice())
+    }
+
+    /* Applies the transform to a flat x, y, z array of unit normals. Normals 
+    take the inverse scale & no translation, & are normalised again */
+    pub fn transform_normals(&self, normals: Vec<f32>) -> Vec<f32> {
+        let inv_scale = self.scale.map(|s| if s != 0.0 { 1.0 / s } else { 0.0 });
+        let linear_m = self.rotation().to_rotation_matrix().into_inner() *
+            Matrix3::from_diagonal(&inv_scale);
+        let num_normals = normals.len() / 3;
+        let mut normals_m = linear_m * DMatrix::from_vec(3, num_normals, normals);
+        for mut column in normals_m.column_iter_mut() {
+            let norm = column.norm();
+            if norm > 0.0 {
+                column /= norm;
+            }
+        }
+        Vec::from(normals_m.as_slice())
+    }
+}
+
+/* Rotation by x, y & z radians about the fixed x, y & z axes, in that order */
+pub fn euler_rotation(x: f32, y: f32, z: f32) -> UnitQuaternion<f32> {
+    UnitQuaternion::from_euler_angles(x, y, z)
+}
+
+/* Transform of a node in its parent's frame. Nodes that don't 
+place themselves (worldbody, joints, assets...) sit at their parent's origin */
+pub fn local_transform(graph: &SceneGraph, node: NodeId) -> Transform {
+    match graph.get(node) {
+        Node::Body(b) => b.get_local_pose(),
+        Node::Geom(g) => g.get_local_pose(),
+        _ => Transform::identity()
+    }
+}
+
+/* Transform of a node in the world frame, chaining the local transforms 
+of its ancestors */
+pub fn world_transform(graph: &SceneGraph, node: NodeId) -> Transform {
+    let local = local_transform(graph, node);
+    match graph.parent(node) {
+        Some(p) => world_transform(graph, p).compose(&local),
+        None => local
+    }
+}
+
+/* Pose queries; these leave out the node's own scale */
+pub fn local_pose(graph: &SceneGraph, node: NodeId) -> Isometry3<f32> {
+    local_transform(graph, node).isometry
+}
+
+pub fn world_pose(graph: &SceneGraph, node: NodeId) -> Isometry3<f32> {
+    world_transform(graph, node).isometry
+}
+
+/* Pose of `b` expressed in the frame of `a` */
+pub fn relative_pose(graph: &SceneGraph, a: NodeId, b: NodeId) -> Isometry3<f32> {
+    world_pose(graph, a).inverse() * world_pose(graph, b)
+}
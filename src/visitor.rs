@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+use crate::scene::{NodeId, SceneGraph};
+use crate::node::{Node, Geom, WorldBody, Body, Joint, Defaults, AssetsManager,
+    Mesh, Texture, Material, Compiler, RawElement, Actuator, Inertial, Hfield};
+
+/* Returned by the pre-order hooks to steer the walk */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VisitControl {
+    Continue,
+    //Don't descend into this node's children; its post-order hook still runs
+    SkipChildren,
+    Stop
+}
+
+/* Read only walk over a node tree. Override the hooks for the node kinds of
+interest: visit_* runs before a node's children, leave_* after them */
+pub trait NodeVisitor {
+    fn visit_geom(&mut self, _geom: &Geom) -> VisitControl { VisitControl::Continue }
+    fn visit_worldbody(&mut self, _worldbody: &WorldBody) -> VisitControl { VisitControl::Continue }
+    fn visit_body(&mut self, _body: &Body) -> VisitControl { VisitControl::Continue }
+    fn visit_joint(&mut self, _joint: &Joint) -> VisitControl { VisitControl::Continue }
+    fn visit_defaults(&mut self, _defaults: &Defaults) -> VisitControl { VisitControl::Continue }
+    fn visit_assets(&mut self, _assets: &AssetsManager) -> VisitControl { VisitControl::Continue }
+    fn visit_mesh(&mut self, _mesh: &Mesh) -> VisitControl { VisitControl::Continue }
+    fn visit_texture(&mut self, _texture: &Texture) -> VisitControl { VisitControl::Continue }
+    fn visit_material(&mut self, _material: &Material) -> VisitControl { VisitControl::Continue }
+    fn visit_compiler(&mut self, _compiler: &Compiler) -> VisitControl { VisitControl::Continue }
+    fn visit_raw(&mut self, _raw: &RawElement) -> VisitControl { VisitControl::Continue }
+    fn visit_actuator(&mut self, _actuator: &Actuator) -> VisitControl { VisitControl::Continue }
+    fn visit_inertial(&mut self, _inertial: &Inertial) -> VisitControl { VisitControl::Continue }
+    fn visit_hfield(&mut self, _hfield: &Hfield) -> VisitControl { VisitControl::Continue }
+
+    fn leave_geom(&mut self, _geom: &Geom) {}
+    fn leave_worldbody(&mut self, _worldbody: &WorldBody) {}
+    fn leave_body(&mut self, _body: &Body) {}
+    fn leave_joint(&mut self, _joint: &Joint) {}
+    fn leave_defaults(&mut self, _defaults: &Defaults) {}
+    fn leave_assets(&mut self, _assets: &AssetsManager) {}
+    fn leave_mesh(&mut self, _mesh: &Mesh) {}
+    fn leave_texture(&mut self, _texture: &Texture) {}
+    fn leave_material(&mut self, _material: &Material) {}
+    fn leave_compiler(&mut self, _compiler: &Compiler) {}
+    fn leave_raw(&mut self, _raw: &RawElement) {}
+    fn leave_actuator(&mut self, _actuator: &Actuator) {}
+    fn leave_inertial(&mut self, _inertial: &Inertial) {}
+    fn leave_hfield(&mut self, _hfield: &Hfield) {}
+
+    /* Entry points for a node of any kind, dispatching to the hooks above */
+    fn visit(&mut self, node: &Node) -> VisitControl {
+        match node {
+            Node::Geom(e) => self.visit_geom(e),
+            Node::WorldBody(e) => self.visit_worldbody(e),
+            Node::Body(e) => self.visit_body(e),
+            Node::Joint(e) => self.visit_joint(e),
+            Node::Defaults(e) => self.visit_defaults(e),
+            Node::Assets(e) => self.visit_assets(e),
+            Node::Mesh(e) => self.visit_mesh(e),
+            Node::Texture(e) => self.visit_texture(e),
+            Node::Material(e) => self.visit_material(e),
+            Node::Compiler(e) => self.visit_compiler(e),
+            Node::Raw(e) => self.visit_raw(e),
+            Node::Actuator(e) => self.visit_actuator(e),
+            Node::Inertial(e) => self.visit_inertial(e),
+            Node::Hfield(e) => self.visit_hfield(e)
+        }
+    }
+
+    fn leave(&mut self, node: &Node) {
+        match node {
+            Node::Geom(e) => self.leave_geom(e),
+            Node::WorldBody(e) => self.leave_worldbody(e),
+            Node::Body(e) => self.leave_body(e),
+            Node::Joint(e) => self.leave_joint(e),
+            Node::Defaults(e) => self.leave_defaults(e),
+            Node::Assets(e) => self.leave_assets(e),
+            Node::Mesh(e) => self.leave_mesh(e),
+            Node::Texture(e) => self.leave_texture(e),
+            Node::Material(e) => self.leave_material(e),
+            Node::Compiler(e) => self.leave_compiler(e),
+            Node::Raw(e) => self.leave_raw(e),
+            Node::Actuator(e) => self.leave_actuator(e),
+            Node::Inertial(e) => self.leave_inertial(e),
+            Node::Hfield(e) => self.leave_hfield(e)
+        }
+    }
+}
+
+/* Same as NodeVisitor, with mutable access to the nodes */
+pub trait NodeVisitorMut {
+    fn visit_geom_mut(&mut self, _geom: &mut Geom) -> VisitControl { VisitControl::Continue }
+    fn visit_worldbody_mut(&mut self, _worldbody: &mut WorldBody) -> VisitControl { VisitControl::Continue }
+    fn visit_body_mut(&mut self, _body: &mut Body) -> VisitControl { VisitControl::Continue }
+    fn visit_joint_mut(&mut self, _joint: &mut Joint) -> VisitControl { VisitControl::Continue }
+    fn visit_defaults_mut(&mut self, _defaults: &mut Defaults) -> VisitControl { VisitControl::Continue }
+    fn visit_assets_mut(&mut self, _assets: &mut AssetsManager) -> VisitControl { VisitControl::Continue }
+    fn visit_mesh_mut(&mut self, _mesh: &mut Mesh) -> VisitControl { VisitControl::Continue }
+    fn visit_texture_mut(&mut self, _texture: &mut Texture) -> VisitControl { VisitControl::Continue }
+    fn visit_material_mut(&mut self, _material: &mut Material) -> VisitControl { VisitControl::Continue }
+    fn visit_compiler_mut(&mut self, _compiler: &mut Compiler) -> VisitControl { VisitControl::Continue }
+    fn visit_raw_mut(&mut self, _raw: &mut RawElement) -> VisitControl { VisitControl::Continue }
+    fn visit_actuator_mut(&mut self, _actuator: &mut Actuator) -> VisitControl { VisitControl::Continue }
+    fn visit_inertial_mut(&mut self, _inertial: &mut Inertial) -> VisitControl { VisitControl::Continue }
+    fn visit_hfield_mut(&mut self, _hfield: &mut Hfield) -> VisitControl { VisitControl::Continue }
+
+    fn leave_geom_mut(&mut self, _geom: &mut Geom) {}
+    fn leave_worldbody_mut(&mut self, _worldbody: &mut WorldBody) {}
+    fn leave_body_mut(&mut self, _body: &mut Body) {}
+    fn leave_joint_mut(&mut self, _joint: &mut Joint) {}
+    fn leave_defaults_mut(&mut self, _defaults: &mut Defaults) {}
+    fn leave_assets_mut(&mut self, _assets: &mut AssetsManager) {}
+    fn leave_mesh_mut(&mut self, _mesh: &mut Mesh) {}
+    fn leave_texture_mut(&mut self, _texture: &mut Texture) {}
+    fn leave_material_mut(&mut self, _material: &mut Material) {}
+    fn leave_compiler_mut(&mut self, _compiler: &mut Compiler) {}
+    fn leave_raw_mut(&mut self, _raw: &mut RawElement) {}
+    fn leave_actuator_mut(&mut self, _actuator: &mut Actuator) {}
+    fn leave_inertial_mut(&mut self, _inertial: &mut Inertial) {}
+    fn leave_hfield_mut(&mut self, _hfield: &mut Hfield) {}
+
+    fn visit_mut(&mut self, node: &mut Node) -> VisitControl {
+        match node {
+            Node::Geom(e) => self.visit_geom_mut(e),
+            Node::WorldBody(e) => self.visit_worldbody_mut(e),
+            Node::Body(e) => self.visit_body_mut(e),
+            Node::Joint(e) => self.visit_joint_mut(e),
+            Node::Defaults(e) => self.visit_defaults_mut(e),
+            Node::Assets(e) => self.visit_assets_mut(e),
+            Node::Mesh(e) => self.visit_mesh_mut(e),
+            Node::Texture(e) => self.visit_texture_mut(e),
+            Node::Material(e) => self.visit_material_mut(e),
+            Node::Compiler(e) => self.visit_compiler_mut(e),
+            Node::Raw(e) => self.visit_raw_mut(e),
+            Node::Actuator(e) => self.visit_actuator_mut(e),
+            Node::Inertial(e) => self.visit_inertial_mut(e),
+            Node::Hfield(e) => self.visit_hfield_mut(e)
+        }
+    }
+
+    fn leave_mut(&mut self, node: &mut Node) {
+        match node {
+            Node::Geom(e) => self.leave_geom_mut(e),
+            Node::WorldBody(e) => self.leave_worldbody_mut(e),
+            Node::Body(e) => self.leave_body_mut(e),
+            Node::Joint(e) => self.leave_joint_mut(e),
+            Node::Defaults(e) => self.leave_defaults_mut(e),
+            Node::Assets(e) => self.leave_assets_mut(e),
+            Node::Mesh(e) => self.leave_mesh_mut(e),
+            Node::Texture(e) => self.leave_texture_mut(e),
+            Node::Material(e) => self.leave_material_mut(e),
+            Node::Compiler(e) => self.leave_compiler_mut(e),
+            Node::Raw(e) => self.leave_raw_mut(e),
+            Node::Actuator(e) => self.leave_actuator_mut(e),
+            Node::Inertial(e) => self.leave_inertial_mut(e),
+            Node::Hfield(e) => self.leave_hfield_mut(e)
+        }
+    }
+}
+
+/* Depth first walk of the tree below & including node. Returns false when
+the visitor stopped the walk */
+pub fn walk<V: NodeVisitor>(graph: &SceneGraph, node: NodeId, visitor: &mut V) -> bool {
+    let control = visitor.visit(graph.get(node));
+    if control == VisitControl::Stop {
+        return false;
+    }
+    if control == VisitControl::Continue {
+        for child in graph.children(node) {
+            if !walk(graph, *child, visitor) {
+                return false;
+            }
+        }
+    }
+    visitor.leave(graph.get(node));
+    true
+}
+
+pub fn walk_mut<V: NodeVisitorMut>(graph: &mut SceneGraph, node: NodeId, visitor: &mut V) -> bool {
+    let control = visitor.visit_mut(graph.get_mut(node));
+    if control == VisitControl::Stop {
+        return false;
+    }
+    if control == VisitControl::Continue {
+        let children = graph.children(node).to_vec();
+        for child in children {
+            if !walk_mut(graph, child, visitor) {
+                return false;
+            }
+        }
+    }
+    visitor.leave_mut(graph.get_mut(node));
+    true
+}
+
+/* Pre-order depth first iterator over a node & its descendants */
+pub struct DepthFirstIter<'a> {
+    graph: &'a SceneGraph,
+    stack: Vec<NodeId>
+}
+
+impl<'a> Iterator for DepthFirstIter<'a> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        //Pushed in reverse so that children come out in their stored order
+        self.stack.extend(self.graph.children(node).iter().rev());
+        Some(node)
+    }
+}
+
+/* Level by level iterator over a node & its descendants */
+pub struct BreadthFirstIter<'a> {
+    graph: &'a SceneGraph,
+    queue: VecDeque<NodeId>
+}
+
+impl<'a> Iterator for BreadthFirstIter<'a> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.queue.pop_front()?;
+        self.queue.extend(self.graph.children(node));
+        Some(node)
+    }
+}
+
+pub fn depth_first(graph: &SceneGraph, root: NodeId) -> DepthFirstIter<'_> {
+    DepthFirstIter { graph: graph, stack: vec![root] }
+}
+
+pub fn breadth_first(graph: &SceneGraph, root: NodeId) -> BreadthFirstIter<'_> {
+    BreadthFirstIter { graph: graph, queue: VecDeque::from(vec![root]) }
+}
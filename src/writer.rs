@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use quick_xml::events::{Event, BytesStart, BytesEnd};
+use quick_xml::writer::Writer;
+use nalgebra::UnitQuaternion;
+use crate::model::Model;
+use crate::node::{Node, NodeType, ORIENTATION_ATTRS};
+use crate::scene::{NodeId, SceneGraph};
+
+/* Serializes a model back to MJCF. Elements are written from the attributes
+set on the nodes (get_set_attributes_list), so attributes this crate doesn't
+interpret are written back unchanged. Top level sections keep the order they 
+were read in, sections that were split up (e.g. across included files) are 
+written merged where they first appeared. Asset paths are kept as they were 
+in the source file, i.e. relative to the original model's directory */
+pub fn write_mjcf<W: Write>(model: &Model, out: W) -> io::Result<()> {
+    let mut writer = Writer::new_with_indent(out, b' ', 2);
+    start(&mut writer, "mujoco", &model.attributes)?;
+    for section in &model.sections {
+        match model.graph.get(*section) {
+            Node::Compiler(compiler) => {
+                let attrs = compiler.get_set_attributes_list();
+                if !attrs.is_empty() {
+                    write_element(&mut writer, "compiler", &attrs, false)?;
+                }
+            },
+            Node::Defaults(_) => write_defaults(&mut writer, &model.graph, *section, &HashMap::new())?,
+            Node::Assets(_) => {
+                let children = model.graph.children(*section);
+                if !children.is_empty() {
+                    writer.write_event(Event::Start(BytesStart::new("asset")))?;
+                    for child in children {
+                        write_node(&mut writer, &model.graph, *child)?;
+                    }
+                    writer.write_event(Event::End(BytesEnd::new("asset")))?;
+                }
+            },
+            _ => write_node(&mut writer, &model.graph, *section)?
+        }
+    }
+    writer.write_event(Event::End(BytesEnd::new("mujoco")))?;
+    Ok(())
+}
+
+/* Writes a default class. Nested classes already hold the attributes they inherit,
+only the ones that differ from the enclosing class are written */
+fn write_defaults<W: Write>(writer: &mut Writer<W>, graph: &SceneGraph, id: NodeId,
+    parent_element_attrs: &HashMap<String,HashMap<String,String>>) -> io::Result<()> {
+    let Node::Defaults(defaults) = graph.get(id) else {
+        return Ok(());
+    };
+    let element_attrs = defaults.get_element_attrs();
+    start(writer, "default", &defaults.get_set_attributes_list())?;
+    let mut elements: Vec<&String> = element_attrs.keys().collect();
+    elements.sort();
+    for element in elements {
+        let inherited = parent_element_attrs.get(element);
+        let attrs: HashMap<String,String> = element_attrs[element].iter()
+            .filter(|(key, value)| inherited.and_then(|i| i.get(*key)) != Some(*value))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        if !attrs.is_empty() {
+            write_element(writer, element, &attrs, false)?;
+        }
+    }
+    for child in graph.children(id) {
+        write_defaults(writer, graph, *child, element_attrs)?;
+    }
+    writer.write_event(Event::End(BytesEnd::new("default")))?;
+    Ok(())
+}
+
+fn write_node<W: Write>(writer: &mut Writer<W>, graph: &SceneGraph, id: NodeId) -> io::Result<()> {
+    let node = graph.get(id);
+    let name = node.get_node_name();
+    let mut attrs = node.get_set_attributes_list();
+    if let Node::Body(body) = node {
+        //Rotations added at runtime are saved by replacing the orientation with the current one
+        if body.get_added_rotation() != UnitQuaternion::identity() {
+            attrs.retain(|key, _| !ORIENTATION_ATTRS.contains(&key.as_str()));
+            let q = body.get_added_rotation() * body.get_model_pose().rotation();
+            attrs.insert("quat".to_string(), format!("{} {} {} {}", q.w, q.i, q.j, q.k));
+        }
+    }
+    let children = graph.children(id);
+    if children.is_empty() {
+        write_element(writer, &name, &attrs, false)
+    } else {
+        start(writer, &name, &attrs)?;
+        for child in children {
+            write_node(writer, graph, *child)?;
+        }
+        writer.write_event(Event::End(BytesEnd::new(name.as_str())))?;
+        Ok(())
+    }
+}
+
+fn start<W: Write>(writer: &mut Writer<W>, name: &str, attrs: &HashMap<String,String>) -> io::Result<()> {
+    write_element(writer, name, attrs, true)
+}
+
+fn write_element<W: Write>(writer: &mut Writer<W>, name: &str, attrs: &HashMap<String,String>,
+    has_children: bool) -> io::Result<()> {
+    let mut element = BytesStart::new(name);
+    for (key, value) in sorted_attrs(attrs) {
+        element.push_attribute((key.as_str(), value.as_str()));
+    }
+    if has_children {
+        writer.write_event(Event::Start(element))
+    } else {
+        writer.write_event(Event::Empty(element))
+    }
+}
+
+/* Identifying attributes first, the rest alphabetically so the output is stable */
+fn sorted_attrs(attrs: &HashMap<String,String>) -> Vec<(&String,&String)> {
+    const LEADING: [&str; 4] = ["name", "class", "childclass", "type"];
+    let mut sorted: Vec<(&String,&String)> = attrs.iter().collect();
+    sorted.sort_by_key(|(key, _)| {
+        let rank = LEADING.iter().position(|l| l == key).unwrap_or(LEADING.len());
+        (rank, key.as_str())
+    });
+    sorted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::model::Model;
+    use crate::node::{Node, NodeType};
+    use crate::visitor::depth_first;
+
+    /* Written next to the original, so its relative asset paths still resolve */
+    fn reload(original: &PathBuf, mjcf: &str) -> Model {
+        let path = original.with_extension("roundtrip-test.xml");
+        std::fs::write(&path, mjcf).expect("Expected the written model to be saved");
+        let loaded = Model::load_model(path.clone());
+        std::fs::remove_file(&path).expect("Expected the written model to be removed");
+        loaded.expect("Expected the written model to load")
+    }
+
+    fn summary(model: &Model) -> Vec<(String,Vec<(String,String)>)> {
+        let mut nodes = Vec::new();
+        let roots = [model.compiler, model.defaults, model.asset_manager, model.world_body].into_iter().flatten();
+        for root in roots.chain(model.raw_sections.iter().copied()) {
+            for id in depth_first(&model.graph, root) {
+                let mut attrs: Vec<(String,String)> = model.graph.get(id).get_set_attributes_list().into_iter().collect();
+                attrs.sort();
+                nodes.push((model.graph.get(id).get_node_name(), attrs));
+            }
+        }
+        nodes
+    }
+
+    #[test]
+    fn written_models_reload_unchanged() {
+        let assets = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
+        for path in [assets.join("model-1.xml"), assets.join("model-2.xml"), crate::model::tests::ur5e_path()] {
+            let name = path.display();
+            let model = Model::load_model(path.clone()).expect("Expected the asset model to load");
+            let mjcf = model.to_mjcf();
+            let reloaded = reload(&path, &mjcf);
+            assert_eq!(summary(&reloaded), summary(&model), "{} changed on reload", name);
+            assert_eq!(reloaded.get_qpos(), model.get_qpos(), "{} changed qpos on reload", name);
+            assert_eq!(reloaded.attributes, model.attributes);
+            let sections = |m: &Model| m.sections.iter().map(|id| m.graph.get(*id).get_node_name()).collect::<Vec<String>>();
+            assert_eq!(sections(&reloaded), sections(&model));
+            assert_eq!(reloaded.raw_sections.len(), model.raw_sections.len());
+            let meshes = |m: &Model| m.graph.ids().filter(|id| matches!(m.graph.get(*id), Node::Mesh(_))).count();
+            assert_eq!(meshes(&reloaded), meshes(&model));
+            assert_eq!(reloaded.to_mjcf(), mjcf, "{} isn't written the same way twice", name);
+        }
+    }
+}